@@ -7,6 +7,10 @@ struct BootloaderProtocol {
     kernel_file_data: &'static [u8],
     #[allow(dead_code)]
     memory_image_start: *mut u8,
+    #[allow(dead_code)]
+    ram_disk_image_data: Option<&'static [u8]>,
+    #[allow(dead_code)]
+    root_key_file_data: Option<&'static [u8]>,
 }
 
 unsafe impl Send for BootloaderProtocol {}
@@ -15,6 +15,28 @@ const PAGE_SIZE: usize = 4096;
 
 include!("common.rs");
 
+fn load_esp_file(image_handle: uefi::Handle, boot_services: &uefi::table::boot::BootServices, path: &uefi::CStr16) -> Option<&'static [u8]> {
+    use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+    let mut file_system = boot_services.get_image_file_system(image_handle).ok()?;
+    let mut root_dir = file_system.open_volume().ok()?;
+    let mut file = root_dir.open(path, FileMode::Read, FileAttribute::empty()).ok()?.into_regular_file()?;
+    file.set_position(RegularFile::END_OF_FILE).ok()?;
+    let size = file.get_position().ok()? as usize;
+    file.set_position(0).ok()?;
+    let mut data = alloc::alloc::Global
+        .allocate_zeroed(core::alloc::Layout::from_size_align(size.max(1), PAGE_SIZE).unwrap())
+        .unwrap();
+    let data: &'static mut [u8] = &mut unsafe { data.as_mut() }[..size];
+    let mut offset = 0;
+    while offset < size {
+        match file.read(&mut data[offset..]) {
+            Ok(0) | Err(_) => return None,
+            Ok(len) => offset += len,
+        }
+    }
+    Some(data)
+}
+
 #[uefi::entry]
 fn main(image_handle: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi::table::Boot>) -> uefi::Status {
     uefi_services::init(&mut system_table).unwrap();
@@ -57,6 +79,14 @@ fn main(image_handle: uefi::Handle, mut system_table: uefi::table::SystemTable<u
             relocation_type => panic!("unknown relocation type: {}", relocation_type),
         }
     }
+    let ram_disk_image_data = load_esp_file(image_handle, system_table.boot_services(), uefi::cstr16!("\\efi\\boot\\root.img"));
+    if let Some(ram_disk_image_data) = ram_disk_image_data {
+        uefi_services::println!("RAM disk image size: {}", ram_disk_image_data.len());
+    }
+    let root_key_file_data = load_esp_file(image_handle, system_table.boot_services(), uefi::cstr16!("\\efi\\boot\\root.key"));
+    if root_key_file_data.is_some() {
+        uefi_services::println!("Root key file found");
+    }
     unsafe {
         system_table
             .boot_services()
@@ -66,6 +96,8 @@ fn main(image_handle: uefi::Handle, mut system_table: uefi::table::SystemTable<u
                 Box::leak(Box::new(BootloaderProtocol {
                     kernel_file_data: &KERNEL_FILE_DATA,
                     memory_image_start: memory_image.as_mut_ptr(),
+                    ram_disk_image_data,
+                    root_key_file_data,
                 })) as *mut _ as *mut _,
             )
             .unwrap();
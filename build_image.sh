@@ -2,8 +2,11 @@
 
 fast=0
 export_root=0
+ram_root=0
+encrypt_root=0
+root_key=0
 
-while getopts "fr" opt; do
+while getopts "frmek" opt; do
   case "$opt" in
     f)
       fast=1
@@ -11,6 +14,15 @@ while getopts "fr" opt; do
     r)
       export_root=1
       ;;
+    m)
+      ram_root=1
+      ;;
+    e)
+      encrypt_root=1
+      ;;
+    k)
+      root_key=1
+      ;;
   esac
 done
 
@@ -27,13 +39,20 @@ loop_device=$(sudo losetup -f --show -P image.img)
 sudo mount ${loop_device}p1 mnt
 sudo mkdir -p mnt/efi/boot
 sudo cp target/bundle.efi mnt/efi/boot/bootx64.efi
+(($ram_root)) && sudo cp root.img mnt/efi/boot/root.img
+(($root_key)) && sudo cp root.key mnt/efi/boot/root.key
 sudo umount mnt
-((!$fast)) && sudo mkfs.ext2 -q ${loop_device}p2
-((!$fast)) && sudo mount ${loop_device}p2 mnt
+root_device=${loop_device}p2
+((!$fast && $encrypt_root)) && sudo cryptsetup luksFormat -q --type luks2 --key-file root.key ${loop_device}p2
+((!$fast && $encrypt_root)) && sudo cryptsetup luksOpen --key-file root.key ${loop_device}p2 kernel_root
+(($encrypt_root)) && root_device=/dev/mapper/kernel_root
+((!$fast)) && sudo mkfs.ext2 -q $root_device
+((!$fast)) && sudo mount $root_device mnt
 ((!$fast)) && sudo tee -a mnt/example <<< text > /dev/null
 ((!$fast)) && sudo mkdir -p mnt/a/b/c <<< text > /dev/null
 ((!$fast)) && sudo tee -a mnt/a/b/c/d <<< efgh > /dev/null
 ((!$fast)) && sudo umount mnt
+((!$fast && $encrypt_root)) && sudo cryptsetup luksClose kernel_root
 (($export_root)) && sudo dd if=${loop_device}p2 of=kernel_root.img bs=64K status=none
 sudo losetup -D $loop_device
 rmdir mnt
@@ -3,3 +3,4 @@
 (cd bootloader && cargo check --message-format=json)
 (cd kernel && cargo check --message-format=json)
 (cd embed && cargo check --message-format=json)
+(cd storage && cargo check --all-targets --features std --message-format=json)
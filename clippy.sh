@@ -3,3 +3,4 @@
 (cd bootloader && cargo clippy)
 (cd kernel && cargo clippy)
 (cd embed && cargo clippy)
+(cd storage && cargo clippy --all-targets --features std)
@@ -0,0 +1,321 @@
+use alloc::vec::Vec;
+use core::{
+    fmt::Debug,
+    sync::atomic::{fence, Ordering},
+};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+const SECTOR_SIZE: u64 = 512;
+
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_SATA: u8 = 0x06;
+const PCI_PROG_IF_AHCI: u8 = 0x01;
+const PCI_BAR_INDEX_ABAR: u8 = 5;
+
+const HBA_REGISTER_GHC: usize = 0x04;
+const HBA_REGISTER_PI: usize = 0x0C;
+const HBA_PORT_REGISTERS_OFFSET: usize = 0x100;
+const HBA_PORT_REGISTERS_SIZE: usize = 0x80;
+
+const GHC_BIT_AE: u32 = 1 << 31;
+
+const PORT_REGISTER_CLB: usize = 0x00;
+const PORT_REGISTER_CLBU: usize = 0x04;
+const PORT_REGISTER_FB: usize = 0x08;
+const PORT_REGISTER_FBU: usize = 0x0C;
+const PORT_REGISTER_IS: usize = 0x10;
+const PORT_REGISTER_IE: usize = 0x14;
+const PORT_REGISTER_CMD: usize = 0x18;
+const PORT_REGISTER_TFD: usize = 0x20;
+const PORT_REGISTER_SIG: usize = 0x24;
+const PORT_REGISTER_SSTS: usize = 0x28;
+const PORT_REGISTER_SERR: usize = 0x30;
+const PORT_REGISTER_CI: usize = 0x38;
+
+const CMD_BIT_ST: u32 = 1 << 0;
+const CMD_BIT_FRE: u32 = 1 << 4;
+const CMD_BIT_FR: u32 = 1 << 14;
+const CMD_BIT_CR: u32 = 1 << 15;
+
+const IS_BIT_TFES: u32 = 1 << 30;
+
+const TFD_BIT_ERR: u32 = 1 << 0;
+const TFD_BIT_DRQ: u32 = 1 << 3;
+const TFD_BIT_BSY: u32 = 1 << 7;
+
+const SSTS_DET_MASK: u32 = 0xF;
+const SSTS_DET_PRESENT: u32 = 0x3;
+
+const SIGNATURE_ATA: u32 = 0x00000101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_LEN: u32 = 5; // in dwords
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+const COMMAND_LIST_SIZE: usize = 32 * 32; // 32 command headers
+const RECEIVED_FIS_SIZE: usize = 256;
+const COMMAND_TABLE_PRDT_OFFSET: usize = 0x80;
+const COMMAND_TABLE_SIZE: usize = COMMAND_TABLE_PRDT_OFFSET + 16; // a single PRDT entry
+
+const MAX_SECTOR_COUNT_PER_COMMAND: u64 = 128;
+
+const POLL_LIMIT: u64 = 10_000_000;
+
+pub struct DiskSectorStorage {
+    device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
+    port_index: usize,
+    port_registers: *mut u32,
+    sector_count: u64,
+    command_list: super::dma::Buffer,
+    received_fis: super::dma::Buffer,
+    command_table: super::dma::Buffer,
+    data_buffer: super::dma::Buffer,
+}
+
+impl Debug for DiskSectorStorage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DiskSectorStorage")
+            .field("device_function", &self.device_function)
+            .field("port_index", &self.port_index)
+            .finish()
+    }
+}
+
+impl DiskSectorStorage {
+    pub fn new_all(
+        pci_root: &mut virtio_drivers::transport::pci::bus::PciRoot,
+        device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
+        device_function_info: &virtio_drivers::transport::pci::bus::DeviceFunctionInfo,
+    ) -> Vec<Self> {
+        if (device_function_info.class, device_function_info.subclass, device_function_info.prog_if)
+            != (PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_SATA, PCI_PROG_IF_AHCI)
+        {
+            return Vec::new();
+        }
+        pci_root.set_command(
+            device_function,
+            virtio_drivers::transport::pci::bus::Command::MEMORY_SPACE | virtio_drivers::transport::pci::bus::Command::BUS_MASTER,
+        );
+        let Ok(virtio_drivers::transport::pci::bus::BarInfo::Memory { address, .. }) = pci_root.bar_info(device_function, PCI_BAR_INDEX_ABAR) else {
+            return Vec::new();
+        };
+        let hba_registers = address as *mut u8;
+        let ports_implemented = unsafe {
+            let ghc = (hba_registers.add(HBA_REGISTER_GHC) as *mut u32).read_volatile();
+            (hba_registers.add(HBA_REGISTER_GHC) as *mut u32).write_volatile(ghc | GHC_BIT_AE);
+            (hba_registers.add(HBA_REGISTER_PI) as *mut u32).read_volatile()
+        };
+        (0..32)
+            .filter(|port_index| ports_implemented & (1 << port_index) != 0)
+            .filter_map(|port_index| {
+                log::debug!("AHCI port {}", port_index);
+                DiskSectorStorage::new(device_function, hba_registers, port_index)
+            })
+            .collect()
+    }
+
+    pub fn device_function(&self) -> virtio_drivers::transport::pci::bus::DeviceFunction {
+        self.device_function
+    }
+
+    pub fn port_index(&self) -> usize {
+        self.port_index
+    }
+
+    fn new(device_function: virtio_drivers::transport::pci::bus::DeviceFunction, hba_registers: *mut u8, port_index: usize) -> Option<Self> {
+        let port_registers = unsafe { hba_registers.add(HBA_PORT_REGISTERS_OFFSET + port_index * HBA_PORT_REGISTERS_SIZE) as *mut u32 };
+        unsafe {
+            if port_registers.add(PORT_REGISTER_SSTS / 4).read_volatile() & SSTS_DET_MASK != SSTS_DET_PRESENT {
+                return None; // no device attached
+            }
+            if port_registers.add(PORT_REGISTER_SIG / 4).read_volatile() != SIGNATURE_ATA {
+                return None; // not an ATA drive, left to the firmware
+            }
+        }
+        // from here on the port is ours, dropping the disk stops it before the buffers are freed
+        let mut disk_sector_storage = DiskSectorStorage {
+            device_function,
+            port_index,
+            port_registers,
+            sector_count: 0,
+            command_list: super::dma::Buffer::new(COMMAND_LIST_SIZE, 1024),
+            received_fis: super::dma::Buffer::new(RECEIVED_FIS_SIZE, 256),
+            command_table: super::dma::Buffer::new(COMMAND_TABLE_SIZE, 128),
+            data_buffer: super::dma::Buffer::new((MAX_SECTOR_COUNT_PER_COMMAND * SECTOR_SIZE) as usize, 4096),
+        };
+        unsafe {
+            disk_sector_storage.stop().ok()?;
+            disk_sector_storage.write_register(PORT_REGISTER_CLB, disk_sector_storage.command_list.physical_address() as u32);
+            disk_sector_storage.write_register(PORT_REGISTER_CLBU, (disk_sector_storage.command_list.physical_address() >> 32) as u32);
+            disk_sector_storage.write_register(PORT_REGISTER_FB, disk_sector_storage.received_fis.physical_address() as u32);
+            disk_sector_storage.write_register(PORT_REGISTER_FBU, (disk_sector_storage.received_fis.physical_address() >> 32) as u32);
+            disk_sector_storage.write_register(PORT_REGISTER_IE, 0); // do not send IRQs
+            disk_sector_storage.write_register(PORT_REGISTER_SERR, u32::MAX);
+            disk_sector_storage.write_register(PORT_REGISTER_IS, u32::MAX);
+            disk_sector_storage.start().ok()?;
+            disk_sector_storage.execute_command(COMMAND_IDENTIFY, 0, 0, SECTOR_SIZE as usize, false).ok()?;
+            let identify_data: &[u16] = bytemuck::cast_slice(core::slice::from_raw_parts(disk_sector_storage.data_buffer.as_ptr(), SECTOR_SIZE as usize));
+            if identify_data[83] & (1 << 10) == 0 {
+                return None; // LBA48 mode is not supported
+            }
+            disk_sector_storage.sector_count = u64::from_le_bytes(bytemuck::cast_slice(&identify_data[100..104]).try_into().unwrap());
+        }
+        Some(disk_sector_storage)
+    }
+
+    unsafe fn read_register(&self, register: usize) -> u32 {
+        self.port_registers.add(register / 4).read_volatile()
+    }
+
+    unsafe fn write_register(&self, register: usize, value: u32) {
+        self.port_registers.add(register / 4).write_volatile(value)
+    }
+
+    unsafe fn wait_for_register(&self, register: usize, mask: u32, value: u32) -> Result<(), StorageError> {
+        for _ in 0..POLL_LIMIT {
+            if self.read_register(register) & mask == value {
+                return Ok(());
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+
+    unsafe fn stop(&self) -> Result<(), StorageError> {
+        self.write_register(PORT_REGISTER_CMD, self.read_register(PORT_REGISTER_CMD) & !CMD_BIT_ST);
+        self.wait_for_register(PORT_REGISTER_CMD, CMD_BIT_CR, 0)?;
+        self.write_register(PORT_REGISTER_CMD, self.read_register(PORT_REGISTER_CMD) & !CMD_BIT_FRE);
+        self.wait_for_register(PORT_REGISTER_CMD, CMD_BIT_FR, 0)
+    }
+
+    unsafe fn start(&self) -> Result<(), StorageError> {
+        self.wait_for_register(PORT_REGISTER_CMD, CMD_BIT_CR, 0)?;
+        self.write_register(PORT_REGISTER_CMD, self.read_register(PORT_REGISTER_CMD) | CMD_BIT_FRE);
+        self.write_register(PORT_REGISTER_CMD, self.read_register(PORT_REGISTER_CMD) | CMD_BIT_ST);
+        Ok(())
+    }
+
+    unsafe fn execute_command(&self, command: u8, sector_index: u64, sector_count: u64, data_len: usize, write: bool) -> Result<(), StorageError> {
+        assert!(sector_count <= MAX_SECTOR_COUNT_PER_COMMAND && data_len <= self.data_buffer.size());
+        self.wait_for_register(PORT_REGISTER_TFD, TFD_BIT_BSY | TFD_BIT_DRQ, 0)?;
+        let prdt_len = if data_len > 0 { 1 } else { 0 };
+        let command_header = self.command_list.as_ptr() as *mut u32;
+        command_header.write_volatile(FIS_LEN | if write { 1 << 6 } else { 0 } | prdt_len << 16);
+        command_header.add(1).write_volatile(0); // PRDBC
+        command_header.add(2).write_volatile(self.command_table.physical_address() as u32);
+        command_header.add(3).write_volatile((self.command_table.physical_address() >> 32) as u32);
+        let command_table = core::slice::from_raw_parts_mut(self.command_table.as_ptr(), COMMAND_TABLE_SIZE);
+        command_table.fill(0);
+        let sector_index_bytes: [u8; 8] = sector_index.to_le_bytes();
+        let sector_count_bytes: [u8; 8] = sector_count.to_le_bytes();
+        command_table[0..16].copy_from_slice(&[
+            FIS_TYPE_REG_H2D,
+            0x80, // command, not control
+            command,
+            0, // features
+            sector_index_bytes[0],
+            sector_index_bytes[1],
+            sector_index_bytes[2],
+            0x40, // LBA mode
+            sector_index_bytes[3],
+            sector_index_bytes[4],
+            sector_index_bytes[5],
+            0, // features (high)
+            sector_count_bytes[0],
+            sector_count_bytes[1],
+            0, // isochronous command completion
+            0, // control
+        ]);
+        if data_len > 0 {
+            let prdt_entry = &mut command_table[COMMAND_TABLE_PRDT_OFFSET..COMMAND_TABLE_PRDT_OFFSET + 16];
+            prdt_entry[0..8].copy_from_slice(&self.data_buffer.physical_address().to_le_bytes());
+            prdt_entry[12..16].copy_from_slice(&(data_len as u32 - 1).to_le_bytes());
+        }
+        self.write_register(PORT_REGISTER_IS, u32::MAX);
+        fence(Ordering::SeqCst);
+        self.write_register(PORT_REGISTER_CI, 1);
+        for _ in 0..POLL_LIMIT {
+            if self.read_register(PORT_REGISTER_IS) & IS_BIT_TFES != 0 {
+                return Err(StorageError::DeviceError);
+            }
+            if self.read_register(PORT_REGISTER_CI) & 1 == 0 {
+                fence(Ordering::SeqCst);
+                if self.read_register(PORT_REGISTER_TFD) & TFD_BIT_ERR != 0 {
+                    return Err(StorageError::DeviceError);
+                }
+                return Ok(());
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+}
+
+impl Drop for DiskSectorStorage {
+    fn drop(&mut self) {
+        if unsafe { self.stop() }.is_err() {
+            log::error!("AHCI port {} did not stop", self.port_index);
+        }
+    }
+}
+
+impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut sectors_data = Vec::with_capacity((sector_count * SECTOR_SIZE) as usize);
+        for chunk_sector_index in (sector_index..sector_index + sector_count).step_by(MAX_SECTOR_COUNT_PER_COMMAND as usize) {
+            let chunk_sector_count = (sector_index + sector_count - chunk_sector_index).min(MAX_SECTOR_COUNT_PER_COMMAND);
+            let chunk_len = (chunk_sector_count * SECTOR_SIZE) as usize;
+            unsafe {
+                self.execute_command(COMMAND_READ_DMA_EXT, chunk_sector_index, chunk_sector_count, chunk_len, false)?;
+                sectors_data.extend_from_slice(core::slice::from_raw_parts(self.data_buffer.as_ptr(), chunk_len));
+            }
+        }
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % SECTOR_SIZE == 0);
+        if sector_index + sectors_data.len() as u64 / SECTOR_SIZE > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        for (chunk_index, chunk_data) in sectors_data.chunks((MAX_SECTOR_COUNT_PER_COMMAND * SECTOR_SIZE) as usize).enumerate() {
+            unsafe {
+                core::slice::from_raw_parts_mut(self.data_buffer.as_ptr(), chunk_data.len()).copy_from_slice(chunk_data);
+                self.execute_command(
+                    COMMAND_WRITE_DMA_EXT,
+                    sector_index + chunk_index as u64 * MAX_SECTOR_COUNT_PER_COMMAND,
+                    chunk_data.len() as u64 / SECTOR_SIZE,
+                    chunk_data.len(),
+                    true,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        unsafe { self.execute_command(COMMAND_FLUSH_CACHE_EXT, 0, 0, 0, false) }
+    }
+}
@@ -0,0 +1,177 @@
+use alloc::{string::String, vec::Vec};
+
+use super::{guid::Guid, partitions};
+
+#[derive(Debug)]
+pub enum RootDevice {
+    PartUuid(Guid),
+    PartLabel(String),
+    // diskN or diskNpM, with the disks numbered from 0 as discovered and the partitions from 1 in partition table order
+    Disk { disk_index: usize, partition_number: Option<usize> },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsType {
+    Ext2,
+    Iso9660,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Driver {
+    Gop,
+    Pata,
+    Ahci,
+    Nvme,
+    VirtioBlk,
+    VirtioGpu,
+    UefiBlockIo,
+}
+
+#[derive(Debug)]
+pub struct CommandLine {
+    pub root: Option<RootDevice>,
+    pub root_fs_type: Option<FsType>,
+    pub root_read_only: bool, // without it, writes go straight to the root device instead of an in-memory overlay
+    pub log_level: Option<log::LevelFilter>,
+    pub disabled_drivers: Vec<Driver>,
+    pub unknown_options: Vec<String>, // reported once the logger is up
+}
+
+// options are separated by whitespace, double quotes allow whitespace within an option
+fn split_options(command_line: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    let mut option = String::new();
+    let mut quoted = false;
+    for character in command_line.chars() {
+        match character {
+            '"' => quoted = !quoted,
+            character if character.is_whitespace() && !quoted => {
+                if !option.is_empty() {
+                    options.push(core::mem::take(&mut option));
+                }
+            }
+            character => option.push(character),
+        }
+    }
+    if !option.is_empty() {
+        options.push(option);
+    }
+    options
+}
+
+// either a GUID or the SSSSSSSS-NN form of MBR disk signature and partition number
+fn parse_part_uuid(value: &str) -> Option<Guid> {
+    if let Some(id) = Guid::parse(value) {
+        return Some(id);
+    }
+    let (disk_signature, partition_number) = value.split_once('-')?;
+    if disk_signature.len() != 8 || partition_number.len() != 2 {
+        return None;
+    }
+    Some(partitions::mbr_partition_id(
+        u32::from_str_radix(disk_signature, 16).ok()?,
+        u8::from_str_radix(partition_number, 16).ok()?,
+    ))
+}
+
+fn parse_root(value: &str) -> Option<RootDevice> {
+    if let Some(part_uuid) = value.strip_prefix("PARTUUID=") {
+        return parse_part_uuid(part_uuid).map(RootDevice::PartUuid);
+    }
+    if let Some(part_label) = value.strip_prefix("PARTLABEL=") {
+        return Some(RootDevice::PartLabel(String::from(part_label)));
+    }
+    let disk = value.strip_prefix("disk")?;
+    let (disk_index, partition_number) = match disk.split_once('p') {
+        Some((disk_index, partition_number)) => (disk_index, Some(partition_number.parse().ok()?)),
+        None => (disk, None),
+    };
+    Some(RootDevice::Disk {
+        disk_index: disk_index.parse().ok()?,
+        partition_number,
+    })
+}
+
+// a number follows the Linux console log levels, showing the messages of a lower level
+fn parse_log_level(value: &str) -> Option<log::LevelFilter> {
+    match value.parse::<u8>() {
+        Ok(0) => Some(log::LevelFilter::Off),
+        Ok(1..=4) => Some(log::LevelFilter::Error),
+        Ok(5) => Some(log::LevelFilter::Warn),
+        Ok(6..=7) => Some(log::LevelFilter::Info),
+        Ok(_) => Some(log::LevelFilter::Debug),
+        Err(_) => value.parse().ok(),
+    }
+}
+
+fn parse_fs_type(value: &str) -> Option<FsType> {
+    match value {
+        "ext2" => Some(FsType::Ext2),
+        "iso9660" => Some(FsType::Iso9660),
+        _ => None,
+    }
+}
+
+fn parse_driver(value: &str) -> Option<Driver> {
+    match value {
+        "gop" => Some(Driver::Gop),
+        "pata" => Some(Driver::Pata),
+        "ahci" => Some(Driver::Ahci),
+        "nvme" => Some(Driver::Nvme),
+        "virtio_blk" => Some(Driver::VirtioBlk),
+        "virtio_gpu" => Some(Driver::VirtioGpu),
+        "uefi_block_io" => Some(Driver::UefiBlockIo),
+        _ => None,
+    }
+}
+
+impl CommandLine {
+    pub fn parse(command_line: &str) -> Self {
+        let mut options = split_options(command_line);
+        // the UEFI shell passes the image path first
+        if options
+            .first()
+            .is_some_and(|option| !option.contains('=') && option.to_ascii_lowercase().ends_with(".efi"))
+        {
+            options.remove(0);
+        }
+        let mut parsed_command_line = CommandLine {
+            root: None,
+            root_fs_type: None,
+            root_read_only: true,
+            log_level: None,
+            disabled_drivers: Vec::new(),
+            unknown_options: Vec::new(),
+        };
+        for option in options {
+            let known = match option.split_once('=') {
+                Some(("root", value)) => parse_root(value).map(|root| parsed_command_line.root = Some(root)).is_some(),
+                Some(("rootfstype", value)) => parse_fs_type(value)
+                    .map(|root_fs_type| parsed_command_line.root_fs_type = Some(root_fs_type))
+                    .is_some(),
+                Some(("loglevel", value)) => parse_log_level(value)
+                    .map(|log_level| parsed_command_line.log_level = Some(log_level))
+                    .is_some(),
+                Some(("disable_drivers", value)) => value
+                    .split(',')
+                    .map(parse_driver)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|disabled_drivers| parsed_command_line.disabled_drivers.extend(disabled_drivers))
+                    .is_some(),
+                None if option == "ro" || option == "rw" => {
+                    parsed_command_line.root_read_only = option == "ro";
+                    true
+                }
+                _ => false,
+            };
+            if !known {
+                parsed_command_line.unknown_options.push(option);
+            }
+        }
+        parsed_command_line
+    }
+
+    pub fn driver_enabled(&self, driver: Driver) -> bool {
+        !self.disabled_drivers.contains(&driver)
+    }
+}
@@ -1,7 +1,11 @@
 use acpi::AcpiTable;
 use alloc::vec::Vec;
+use core::ops::Range;
 
-use super::sector_storage::SectorStorage;
+use super::{
+    command_line::Driver,
+    sector_storage::{SectorStorage, StorageError},
+};
 
 #[derive(Clone, Copy)]
 struct AcpiHandler;
@@ -70,29 +74,209 @@ impl super::display::Display for Display {
 pub enum DiskSectorStorage {
     Pata(super::pata::DiskSectorStorage),
     VirtioBlk(super::virtio_blk::DiskSectorStorage),
+    Ahci(super::ahci::DiskSectorStorage),
+    Nvme(super::nvme::DiskSectorStorage),
+    Atapi(super::pata::AtapiSectorStorage),
+    UefiBlockIo(super::uefi_block_io::DiskSectorStorage),
 }
 
 impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.sector_size(),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.sector_size(),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.sector_size(),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.sector_size(),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.sector_size(),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.sector_size(),
+        }
+    }
+
     fn sector_count(&self) -> u64 {
         match self {
             DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.sector_count(),
             DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.sector_count(),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.sector_count(),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.sector_count(),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.sector_count(),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.sector_count(),
         }
     }
 
-    fn read_sector(&self, sector_index: u64) -> [u8; super::sector_storage::SECTOR_SIZE as usize] {
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
         match self {
             DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
             DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.read_sector(sector_index),
         }
     }
 
-    fn write_sector(&self, sector_index: u64, sector_data: [u8; super::sector_storage::SECTOR_SIZE as usize]) {
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
         match self {
             DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
             DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.write_sector(sector_index, sector_data),
+        }
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.read_sectors(sector_index, sector_count),
+        }
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.write_sectors(sector_index, sectors_data),
+        }
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.flush(),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.flush(),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.flush(),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.flush(),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.flush(),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.flush(),
         }
     }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.write_sectors_fua(sector_index, sectors_data),
+        }
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.discard(sectors),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.discard(sectors),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.discard(sectors),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.discard(sectors),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.discard(sectors),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.discard(sectors),
+        }
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.write_zeroes(sectors),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => disk_sector_storage.read_only(),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => disk_sector_storage.read_only(),
+            DiskSectorStorage::Ahci(disk_sector_storage) => disk_sector_storage.read_only(),
+            DiskSectorStorage::Nvme(disk_sector_storage) => disk_sector_storage.read_only(),
+            DiskSectorStorage::Atapi(disk_sector_storage) => disk_sector_storage.read_only(),
+            DiskSectorStorage::UefiBlockIo(disk_sector_storage) => disk_sector_storage.read_only(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum DiskLocation {
+    Pata(super::pata::Device),
+    Pci(virtio_drivers::transport::pci::bus::DeviceFunction, Option<u32>), // port or namespace
+}
+
+impl DiskSectorStorage {
+    fn location(&self) -> Option<DiskLocation> {
+        match self {
+            DiskSectorStorage::Pata(disk_sector_storage) => Some(DiskLocation::Pata(disk_sector_storage.device())),
+            DiskSectorStorage::VirtioBlk(disk_sector_storage) => Some(DiskLocation::Pci(disk_sector_storage.device_function(), None)),
+            DiskSectorStorage::Ahci(disk_sector_storage) => Some(DiskLocation::Pci(
+                disk_sector_storage.device_function(),
+                Some(disk_sector_storage.port_index() as u32),
+            )),
+            DiskSectorStorage::Nvme(disk_sector_storage) => Some(DiskLocation::Pci(disk_sector_storage.device_function(), Some(disk_sector_storage.namespace_id()))),
+            DiskSectorStorage::Atapi(disk_sector_storage) => Some(DiskLocation::Pata(disk_sector_storage.device())),
+            DiskSectorStorage::UefiBlockIo(_) => None,
+        }
+    }
+}
+
+fn pci_secondary_bus_number(mmconfig_base: *mut u8, device_function: virtio_drivers::transport::pci::bus::DeviceFunction) -> u8 {
+    let offset = (device_function.bus as usize) << 20 | (device_function.device as usize) << 15 | (device_function.function as usize) << 12;
+    unsafe { mmconfig_base.add(offset + 0x19).read_volatile() }
+}
+
+fn uefi_device_path_location(handle: uefi::Handle, mmconfig_base: Option<*mut u8>) -> Option<DiskLocation> {
+    use uefi::proto::device_path::{DeviceSubType, DeviceType};
+    let boot_services = unsafe { super::SYSTEM_TABLE.as_ref().unwrap() }.boot_services();
+    let device_path = unsafe {
+        boot_services.open_protocol::<uefi::proto::device_path::DevicePath>(
+            uefi::table::boot::OpenProtocolParams {
+                handle,
+                agent: super::IMAGE_HANDLE.unwrap(),
+                controller: None,
+            },
+            uefi::table::boot::OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+    let mut device_function: Option<virtio_drivers::transport::pci::bus::DeviceFunction> = None;
+    for node in device_path.node_iter() {
+        let data = node.data();
+        match (node.device_type(), node.sub_type()) {
+            (DeviceType::HARDWARE, DeviceSubType::HARDWARE_PCI) => {
+                let bus = match device_function {
+                    Some(bridge_device_function) => pci_secondary_bus_number(mmconfig_base?, bridge_device_function),
+                    None => 0,
+                };
+                device_function = Some(virtio_drivers::transport::pci::bus::DeviceFunction {
+                    bus,
+                    device: data[1],
+                    function: data[0],
+                });
+            }
+            (DeviceType::MESSAGING, DeviceSubType::MESSAGING_ATAPI) => {
+                return Some(DiskLocation::Pata(match (data[0], data[1]) {
+                    (0, 0) => super::pata::Device::PrimaryMaster,
+                    (0, _) => super::pata::Device::PrimarySlave,
+                    (_, 0) => super::pata::Device::SecondaryMaster,
+                    (_, _) => super::pata::Device::SecondarySlave,
+                }))
+            }
+            (DeviceType::MESSAGING, DeviceSubType::MESSAGING_SATA) => {
+                return Some(DiskLocation::Pci(device_function?, Some(u16::from_le_bytes([data[0], data[1]]) as u32)))
+            }
+            (DeviceType::MESSAGING, DeviceSubType::MESSAGING_NVME_NAMESPACE) => {
+                return Some(DiskLocation::Pci(device_function?, Some(u32::from_le_bytes(data[0..4].try_into().unwrap()))))
+            }
+            _ => (),
+        }
+    }
+    Some(DiskLocation::Pci(device_function?, None))
 }
 
 #[derive(Debug, Default)]
@@ -103,21 +287,29 @@ pub struct DiscoveryResult {
 
 pub fn discover() -> DiscoveryResult {
     let mut discovery_result = DiscoveryResult::default();
+    let command_line = super::COMMAND_LINE.wait();
     log::debug!("UEFI GOP");
-    if let Some(display) = super::gop::Display::new() {
+    if command_line.driver_enabled(Driver::Gop)
+        && let Some(display) = super::gop::Display::new()
+    {
         log::info!("--> display of resolution {:?}", super::display::Display::resolution(&display));
         discovery_result.displays.push(Display::Gop(display));
     }
-    for device in [
-        super::pata::Device::PrimaryMaster,
-        super::pata::Device::PrimarySlave,
-        super::pata::Device::SecondaryMaster,
-        super::pata::Device::SecondarySlave,
-    ] {
-        log::debug!("PATA device {:?}", device);
-        if let Some(disk_sector_storage) = super::pata::DiskSectorStorage::new(device) {
-            log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
-            discovery_result.disk_sector_storages.push(DiskSectorStorage::Pata(disk_sector_storage));
+    if command_line.driver_enabled(Driver::Pata) {
+        for device in [
+            super::pata::Device::PrimaryMaster,
+            super::pata::Device::PrimarySlave,
+            super::pata::Device::SecondaryMaster,
+            super::pata::Device::SecondarySlave,
+        ] {
+            log::debug!("PATA device {:?}", device);
+            if let Some(disk_sector_storage) = super::pata::DiskSectorStorage::new(device) {
+                log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
+                discovery_result.disk_sector_storages.push(DiskSectorStorage::Pata(disk_sector_storage));
+            } else if let Some(disk_sector_storage) = super::pata::AtapiSectorStorage::new(device) {
+                log::info!("--> optical disc with {} sectors", disk_sector_storage.sector_count());
+                discovery_result.disk_sector_storages.push(DiskSectorStorage::Atapi(disk_sector_storage));
+            }
         }
     }
     let mut rsdp_address = None;
@@ -134,29 +326,72 @@ pub fn discover() -> DiscoveryResult {
             }
         }
     }
-    if let Some(rsdp_address) = rsdp_address {
+    let mmconfig_base = rsdp_address.and_then(|rsdp_address| {
         let acpi_tables = unsafe { acpi::AcpiTables::from_rsdp(AcpiHandler, rsdp_address as _) }.unwrap();
-        if let Some(mmconfig_base) = find_mmconfig_base(&acpi_tables) {
-            log::info!("Found PCIe mmconfig base: {:?}", mmconfig_base);
-            let mut pci_root = unsafe { virtio_drivers::transport::pci::bus::PciRoot::new(mmconfig_base, virtio_drivers::transport::pci::bus::Cam::Ecam) };
-            for bus in 0..=255 {
-                for (device_function, device_function_info) in pci_root.enumerate_bus(bus) {
-                    log::info!(
-                        "Found PCIe device {}:{}.{}: {}",
-                        device_function.bus,
-                        device_function.device,
-                        device_function.function,
-                        device_function_info
-                    );
-                    if let Some(display) = super::virtio_gpu::Display::new(&mut pci_root, device_function, device_function_info.clone()) {
-                        log::info!("--> display of resolution {:?}", super::display::Display::resolution(&display));
-                        discovery_result.displays.push(Display::VirtioGpu(display));
+        find_mmconfig_base(&acpi_tables)
+    });
+    if let Some(mmconfig_base) = mmconfig_base {
+        log::info!("Found PCIe mmconfig base: {:?}", mmconfig_base);
+        let mut pci_root = unsafe { virtio_drivers::transport::pci::bus::PciRoot::new(mmconfig_base, virtio_drivers::transport::pci::bus::Cam::Ecam) };
+        for bus in 0..=255 {
+            for (device_function, device_function_info) in pci_root.enumerate_bus(bus) {
+                log::info!(
+                    "Found PCIe device {}:{}.{}: {}",
+                    device_function.bus,
+                    device_function.device,
+                    device_function.function,
+                    device_function_info
+                );
+                if command_line.driver_enabled(Driver::VirtioGpu)
+                    && let Some(display) = super::virtio_gpu::Display::new(&mut pci_root, device_function, device_function_info.clone())
+                {
+                    log::info!("--> display of resolution {:?}", super::display::Display::resolution(&display));
+                    discovery_result.displays.push(Display::VirtioGpu(display));
+                }
+                if command_line.driver_enabled(Driver::Pata) {
+                    for bus_master in super::pata::BusMaster::new_all(&mut pci_root, device_function, &device_function_info) {
+                        log::info!("--> PATA bus master {:?}", bus_master);
+                        for disk_sector_storage in discovery_result.disk_sector_storages.iter_mut() {
+                            if let DiskSectorStorage::Pata(disk_sector_storage) = disk_sector_storage {
+                                disk_sector_storage.attach_bus_master(&bus_master);
+                            }
+                        }
                     }
-                    if let Some(disk_sector_storage) = super::virtio_blk::DiskSectorStorage::new(&mut pci_root, device_function, device_function_info) {
+                }
+                if command_line.driver_enabled(Driver::Ahci) {
+                    for disk_sector_storage in super::ahci::DiskSectorStorage::new_all(&mut pci_root, device_function, &device_function_info) {
                         log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
-                        discovery_result.disk_sector_storages.push(DiskSectorStorage::VirtioBlk(disk_sector_storage));
+                        discovery_result.disk_sector_storages.push(DiskSectorStorage::Ahci(disk_sector_storage));
                     }
                 }
+                if command_line.driver_enabled(Driver::Nvme) {
+                    for disk_sector_storage in super::nvme::DiskSectorStorage::new_all(&mut pci_root, device_function, &device_function_info) {
+                        log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
+                        discovery_result.disk_sector_storages.push(DiskSectorStorage::Nvme(disk_sector_storage));
+                    }
+                }
+                if command_line.driver_enabled(Driver::VirtioBlk)
+                    && let Some(disk_sector_storage) = super::virtio_blk::DiskSectorStorage::new(&mut pci_root, device_function, device_function_info)
+                {
+                    log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
+                    discovery_result.disk_sector_storages.push(DiskSectorStorage::VirtioBlk(disk_sector_storage));
+                }
+            }
+        }
+    }
+    let claimed_disk_locations: Vec<DiskLocation> = discovery_result.disk_sector_storages.iter().filter_map(DiskSectorStorage::location).collect();
+    if command_line.driver_enabled(Driver::UefiBlockIo) {
+        for handle in super::uefi_block_io::handles() {
+            log::debug!("UEFI Block I/O handle {:?}", handle);
+            if let Some(disk_location) = uefi_device_path_location(handle, mmconfig_base)
+                && claimed_disk_locations.contains(&disk_location)
+            {
+                log::debug!("--> already claimed as {:?}", disk_location);
+                continue;
+            }
+            if let Some(disk_sector_storage) = super::uefi_block_io::DiskSectorStorage::new(handle) {
+                log::info!("--> disk with {} sectors", disk_sector_storage.sector_count());
+                discovery_result.disk_sector_storages.push(DiskSectorStorage::UefiBlockIo(disk_sector_storage));
             }
         }
     }
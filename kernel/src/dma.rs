@@ -0,0 +1,35 @@
+use core::{
+    alloc::{Allocator, Layout},
+    ptr::NonNull,
+};
+
+pub struct Buffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl Buffer {
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = alloc::alloc::Global.allocate_zeroed(layout).unwrap().cast::<u8>();
+        Buffer { ptr, layout }
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub fn physical_address(&self) -> u64 {
+        self.ptr.as_ptr() as u64 // memory is identity mapped
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::Global.deallocate(self.ptr, self.layout) };
+    }
+}
@@ -0,0 +1,170 @@
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+// the firmware owns the IDT and the interrupt controller while boot services are running, handlers are hooked in through its protocols
+const CPU_ARCH_PROTOCOL_ID: uefi::Guid = uefi::data_types::Guid::parse_or_panic("26baccb1-6f42-11d4-bce7-0080c73c8881");
+const LEGACY_8259_PROTOCOL_ID: uefi::Guid = uefi::data_types::Guid::parse_or_panic("38321dba-4fe0-4e17-8aec-413055eaedc1");
+
+const IRQ_COUNT: usize = 16;
+const IRQ_TIMER: u8 = 0;
+const IRQ_CASCADE: u8 = 2;
+
+// wakeups to wait for an IRQ, the firmware timer wakes the CPU every few milliseconds
+pub const WAIT_LIMIT: u64 = 1_000;
+
+type InterruptHandler = unsafe extern "efiapi" fn(interrupt_type: isize, system_context: *mut c_void);
+
+#[repr(C)]
+#[uefi::proto::unsafe_protocol(CPU_ARCH_PROTOCOL_ID)]
+struct CpuArchProtocol {
+    flush_data_cache: usize,
+    enable_interrupt: usize,
+    disable_interrupt: usize,
+    get_interrupt_state: usize,
+    init: usize,
+    register_interrupt_handler: unsafe extern "efiapi" fn(this: *const Self, interrupt_type: isize, handler: Option<InterruptHandler>) -> uefi::Status,
+}
+
+#[repr(C)]
+#[uefi::proto::unsafe_protocol(LEGACY_8259_PROTOCOL_ID)]
+struct Legacy8259Protocol {
+    set_vector_base: usize,
+    get_mask: usize,
+    set_mask: usize,
+    set_mode: usize,
+    get_vector: unsafe extern "efiapi" fn(this: *const Self, irq: u32, vector: *mut u8) -> uefi::Status,
+    enable_irq: unsafe extern "efiapi" fn(this: *const Self, irq: u32, level_triggered: bool) -> uefi::Status,
+    disable_irq: unsafe extern "efiapi" fn(this: *const Self, irq: u32) -> uefi::Status,
+    get_interrupt_line: usize,
+    end_of_interrupt: unsafe extern "efiapi" fn(this: *const Self, irq: u32) -> uefi::Status,
+}
+
+struct InterruptController {
+    cpu_arch: uefi::table::boot::ScopedProtocol<'static, CpuArchProtocol>,
+    legacy_8259: uefi::table::boot::ScopedProtocol<'static, Legacy8259Protocol>,
+}
+
+unsafe impl Send for InterruptController {}
+unsafe impl Sync for InterruptController {}
+
+static INTERRUPT_CONTROLLER: spin::Once<Option<InterruptController>> = spin::Once::new();
+// 0 until a handler is registered for the IRQ
+static IRQ_VECTORS: [AtomicU8; IRQ_COUNT] = [const { AtomicU8::new(0) }; IRQ_COUNT];
+static IRQ_PENDING: [AtomicBool; IRQ_COUNT] = [const { AtomicBool::new(false) }; IRQ_COUNT];
+
+fn open_protocol<P: uefi::proto::ProtocolPointer>() -> Option<uefi::table::boot::ScopedProtocol<'static, P>> {
+    let boot_services = unsafe { super::SYSTEM_TABLE.as_ref().unwrap() }.boot_services();
+    let handle = boot_services.get_handle_for_protocol::<P>().ok()?;
+    // shared access, the firmware keeps using the protocol
+    unsafe {
+        boot_services.open_protocol::<P>(
+            uefi::table::boot::OpenProtocolParams {
+                handle,
+                agent: super::IMAGE_HANDLE.unwrap(),
+                controller: None,
+            },
+            uefi::table::boot::OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()
+}
+
+fn interrupt_controller() -> Option<&'static InterruptController> {
+    INTERRUPT_CONTROLLER
+        .call_once(|| {
+            let interrupt_controller = InterruptController {
+                cpu_arch: open_protocol::<CpuArchProtocol>()?,
+                legacy_8259: open_protocol::<Legacy8259Protocol>()?,
+            };
+            Some(interrupt_controller)
+        })
+        .as_ref()
+}
+
+unsafe extern "efiapi" fn handle_interrupt(interrupt_type: isize, _system_context: *mut c_void) {
+    let Some(interrupt_controller) = interrupt_controller() else {
+        return;
+    };
+    let legacy_8259 = &*interrupt_controller.legacy_8259;
+    for irq in 0..IRQ_COUNT {
+        if IRQ_VECTORS[irq].load(Ordering::Acquire) as isize == interrupt_type {
+            // masked until the driver has made its device drop the line, a level-triggered line would fire again right away
+            (legacy_8259.disable_irq)(legacy_8259, irq as u32);
+            (legacy_8259.end_of_interrupt)(legacy_8259, irq as u32);
+            IRQ_PENDING[irq].store(true, Ordering::Release);
+        }
+    }
+}
+
+// an IRQ line of the legacy interrupt controller, raised IRQs are recorded as pending for the driver
+#[derive(Clone, Copy, Debug)]
+pub struct Irq {
+    irq: u8,
+    level_triggered: bool,
+}
+
+impl Irq {
+    pub fn new(irq: u8, level_triggered: bool) -> Option<Self> {
+        if irq as usize >= IRQ_COUNT || irq == IRQ_TIMER || irq == IRQ_CASCADE {
+            return None;
+        }
+        // nothing would wake a halted CPU
+        if !x86::bits64::rflags::read().contains(x86::bits64::rflags::RFlags::FLAGS_IF) {
+            return None;
+        }
+        let interrupt_controller = interrupt_controller()?;
+        if IRQ_VECTORS[irq as usize].load(Ordering::Acquire) == 0 {
+            let legacy_8259 = &*interrupt_controller.legacy_8259;
+            let cpu_arch = &*interrupt_controller.cpu_arch;
+            let mut vector = 0;
+            if unsafe { (legacy_8259.get_vector)(legacy_8259, irq as u32, &mut vector) }.is_error() || vector == 0 {
+                return None;
+            }
+            IRQ_VECTORS[irq as usize].store(vector, Ordering::Release);
+            let status = unsafe { (cpu_arch.register_interrupt_handler)(cpu_arch, vector as isize, Some(handle_interrupt)) };
+            if status.is_error() {
+                IRQ_VECTORS[irq as usize].store(0, Ordering::Release);
+                log::warn!("Failed to register a handler for IRQ {}: {:?}", irq, status);
+                return None;
+            }
+        }
+        let irq = Irq { irq, level_triggered };
+        irq.unmask();
+        Some(irq)
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    // whether the IRQ was raised since the last call, the line stays masked until unmask
+    pub fn take_pending(&self) -> bool {
+        IRQ_PENDING[self.irq as usize].swap(false, Ordering::AcqRel)
+    }
+
+    pub fn unmask(&self) {
+        if let Some(interrupt_controller) = interrupt_controller() {
+            let legacy_8259 = &*interrupt_controller.legacy_8259;
+            unsafe { (legacy_8259.enable_irq)(legacy_8259, self.irq as u32, self.level_triggered) };
+        }
+    }
+
+    // halts until the next interrupt unless the IRQ is pending already, spins when interrupts are disabled
+    pub fn wait(&self) {
+        if !x86::bits64::rflags::read().contains(x86::bits64::rflags::RFlags::FLAGS_IF) {
+            core::hint::spin_loop();
+            return;
+        }
+        unsafe {
+            x86::irq::disable();
+            if IRQ_PENDING[self.irq as usize].load(Ordering::Acquire) {
+                x86::irq::enable();
+            } else {
+                // sti only takes effect after hlt, so an IRQ raised in between still wakes the CPU
+                core::arch::asm!("sti", "hlt", options(nomem, nostack));
+            }
+        }
+    }
+}
@@ -108,5 +108,10 @@ impl log::Log for Logger {
 pub fn init() {
     unsafe { CONSOLE = Some(super::console::Console::new()) };
     log::set_logger(&Logger).unwrap();
-    log::set_max_level(log::STATIC_MAX_LEVEL);
+    log::set_max_level(
+        super::COMMAND_LINE
+            .get()
+            .and_then(|command_line| command_line.log_level)
+            .unwrap_or(log::STATIC_MAX_LEVEL),
+    );
 }
@@ -9,38 +9,58 @@
 
 extern crate alloc;
 
+mod ahci;
 mod allocator;
 mod backtrace;
+mod command_line;
 mod console;
 mod discovery;
 mod display;
-mod ext2;
+mod dma;
 mod formatting;
-mod fs;
 mod gop;
-mod guid;
+mod interrupts;
 mod logger;
+mod nvme;
 mod panic;
-mod partitions;
 mod pata;
-mod sector_storage;
 mod serial;
+mod uefi_block_io;
 mod virtio;
 mod virtio_blk;
 mod virtio_gpu;
 
-use alloc::{string::String, vec::Vec};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::fmt::Write;
 
-use sector_storage::SectorStorage;
+use storage::{
+    cache, crypt, ext2, fs, guid, iso9660, luks2, overlay, partitions, probe, raid, ram_disk,
+    sector_storage::{self, SectorStorage, StorageError},
+    stats, vfs,
+};
 
 include!("../../bootloader/src/common.rs");
 
 static mut SYSTEM_TABLE: Option<uefi::table::SystemTable<uefi::table::Boot>> = None;
+static mut IMAGE_HANDLE: Option<uefi::Handle> = None;
 static BOOTLOADER_PROTOCOL: spin::Once<BootloaderProtocol> = spin::Once::new();
+static COMMAND_LINE: spin::Once<command_line::CommandLine> = spin::Once::new();
 static mut DISPLAY: Option<discovery::Display> = None;
 
-fn print_tree(session: &impl fs::Session, level: usize, inode_index: u64) {
+const ROOT_OVERLAY_DELTA_SIZE: u64 = 16 << 20;
+const ROOT_PASSPHRASE_ATTEMPTS: usize = 3;
+const ROOT_IO_TRACE_CAPACITY: usize = 0; // requests kept in the root filesystem trace, 0 disables it
+
+fn tsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+fn print_tree(vfs: &vfs::Vfs, level: usize, node: vfs::Node) -> Result<(), StorageError> {
     const UNIMPORTANT_STYLE: formatting::Style = formatting::Style {
         reset: false,
         foreground_color: Some(formatting::Color::BrightBlack),
@@ -56,11 +76,8 @@ fn print_tree(session: &impl fs::Session, level: usize, inode_index: u64) {
         foreground_color: Some(formatting::Color::Blue),
         background_color: None,
     };
-    for dir_entry in session.read_dir(inode_index) {
-        if dir_entry.inode_index == 0 {
-            continue;
-        }
-        let file_stat = session.file_stat(dir_entry.inode_index);
+    for dir_entry in vfs.read_dir(node)? {
+        let file_stat = vfs.file_stat(dir_entry.node)?;
         for _ in 0..level {
             logger::print!("  ");
         }
@@ -81,61 +98,318 @@ fn print_tree(session: &impl fs::Session, level: usize, inode_index: u64) {
             file_stat.size,
             formatting::Style::RESET
         );
-        if dir_entry.file_type == Some(fs::FileType::Dir) && dir_entry.name != "." && dir_entry.name != ".." {
-            print_tree(session, level + 1, dir_entry.inode_index);
+        if dir_entry.file_type == Some(fs::FileType::Dir) {
+            print_tree(vfs, level + 1, dir_entry.node)?;
         }
         if dir_entry.file_type == Some(fs::FileType::RegularFile) {
             for _ in 0..level {
                 logger::print!("  ");
             }
-            let file_data = session.read_regular_file_range(dir_entry.inode_index, 0..file_stat.size);
+            let file_data = vfs.read_regular_file_range(dir_entry.node, 0..file_stat.size)?;
             logger::print!("`_Contents: {}", String::from_utf8_lossy(&file_data));
         }
     }
+    Ok(())
+}
+
+fn print_root_tree(vfs: &vfs::Vfs) {
+    const HEADER_STYLE: formatting::Style = formatting::Style {
+        reset: false,
+        foreground_color: Some(formatting::Color::Magenta),
+        background_color: None,
+    };
+    logger::println!("{}Root dir listing:{}", HEADER_STYLE, formatting::Style::RESET);
+    if let Err(error) = print_tree(vfs, 0, vfs.root()) {
+        log::error!("Failed to list root dir: {:?}", error);
+    }
+    logger::println!("{}Root dir listing end{}", HEADER_STYLE, formatting::Style::RESET);
+}
+
+fn log_content(name: &str, sector_storage: &impl SectorStorage) {
+    match probe::probe(sector_storage) {
+        Ok(Some(content)) => log::info!("{}: {:?} label {:?} uuid {:?}", name, content.content_type, content.label, content.uuid),
+        Ok(None) => log::info!("{}: unknown contents", name),
+        Err(error) => log::error!("Failed to probe {}: {:?}", name, error),
+    }
+}
+
+fn find_root_partition<'a>(
+    root: Option<&command_line::RootDevice>,
+    disk_sector_storages: &'a [discovery::DiskSectorStorage],
+    disk_sector_storages_partitions: Vec<(&'a dyn SectorStorage, partitions::Partition)>,
+) -> Option<(&'a dyn SectorStorage, partitions::Partition)> {
+    let mut disk_sector_storages_partitions = disk_sector_storages_partitions.into_iter();
+    match root {
+        None => {
+            disk_sector_storages_partitions.find(|(_, partition)| partition.type_id == guid::TYPE_ID_LINUX && partition.name.as_deref() == Some("kernel_root"))
+        }
+        Some(command_line::RootDevice::PartUuid(id)) => disk_sector_storages_partitions.find(|(_, partition)| partition.id == *id),
+        Some(command_line::RootDevice::PartLabel(name)) => {
+            disk_sector_storages_partitions.find(|(_, partition)| partition.name.as_deref() == Some(name.as_str()))
+        }
+        Some(command_line::RootDevice::Disk {
+            disk_index,
+            partition_number: Some(partition_number),
+        }) => {
+            let disk_sector_storage: &dyn SectorStorage = disk_sector_storages.get(*disk_index)?;
+            let partition_table = partitions::read_partition_table(&disk_sector_storage).ok()??;
+            let partition = partition_table.partitions.into_iter().nth(partition_number.checked_sub(1)?)?;
+            Some((disk_sector_storage, partition))
+        }
+        // the whole disk as a single partition
+        Some(command_line::RootDevice::Disk {
+            disk_index,
+            partition_number: None,
+        }) => {
+            let disk_sector_storage: &dyn SectorStorage = disk_sector_storages.get(*disk_index)?;
+            let partition = partitions::Partition {
+                type_id: guid::ZERO,
+                id: guid::ZERO,
+                starting_sector: 0,
+                ending_sector: disk_sector_storage.sector_count().checked_sub(1)?,
+                flags: 0,
+                name: None,
+            };
+            Some((disk_sector_storage, partition))
+        }
+    }
+}
+
+fn read_passphrase() -> String {
+    use uefi::proto::console::text::Key;
+    let system_table = unsafe { SYSTEM_TABLE.as_mut().unwrap() };
+    let mut passphrase = String::new();
+    loop {
+        logger::update();
+        match system_table.stdin().read_key() {
+            Ok(Some(Key::Printable(character))) => match char::from(character) {
+                '\r' => break,
+                '\u{8}' => {
+                    passphrase.pop();
+                }
+                character => {
+                    passphrase.push(character);
+                    logger::print!("*");
+                }
+            },
+            Ok(_) => system_table.boot_services().stall(10_000),
+            Err(error) => {
+                log::error!("Failed to read key: {:?}", error);
+                break;
+            }
+        }
+    }
+    logger::println!();
+    passphrase
+}
+
+fn unlock_root<SS: SectorStorage>(sector_storage: SS, header: &luks2::Header) -> crypt::CryptSectorStorage<SS> {
+    let key = match BOOTLOADER_PROTOCOL.wait().root_key_file_data {
+        Some(root_key_file_data) => header
+            .unlock(&sector_storage, root_key_file_data)
+            .expect("failed to read root key slots")
+            .expect("root key file does not unlock the root partition"),
+        None => (0..ROOT_PASSPHRASE_ATTEMPTS)
+            .find_map(|_| {
+                logger::print!("Passphrase for {}: ", header.uuid);
+                let key = header
+                    .unlock(&sector_storage, read_passphrase().as_bytes())
+                    .expect("failed to read root key slots");
+                if key.is_none() {
+                    logger::println!("No key available with this passphrase");
+                }
+                key
+            })
+            .expect("failed to unlock root partition"),
+    };
+    crypt::CryptSectorStorage::from_luks2_segment(sector_storage, &key, header.segment()).expect("unsupported root partition encryption")
 }
 
 fn init() {
     serial::init();
     logger::init();
     log::info!("Hello world!");
+    let command_line = COMMAND_LINE.wait();
+    log::info!("Command line: {:?}", command_line);
+    for option in &command_line.unknown_options {
+        log::warn!("Unknown command line option {:?}", option);
+    }
     let mut discovery_result = discovery::discover();
     let display = core::mem::take(&mut discovery_result.displays).into_iter().next().expect("no display found");
     unsafe { DISPLAY = Some(display) };
-    let mut disk_sector_storages_partitions = Vec::new();
+    let mut disk_sector_storages_partitions: Vec<(&dyn SectorStorage, partitions::Partition)> = Vec::new();
+    let mut raid_member_candidates: Vec<Box<dyn SectorStorage + '_>> = Vec::new();
     for disk_device_storage in &discovery_result.disk_sector_storages {
-        let partition_table = partitions::read_partition_table(&disk_device_storage);
-        log::debug!("Partition table: {:?}", partition_table);
-        if let Some(partition_table) = partition_table {
-            for partition in partition_table.partitions {
-                disk_sector_storages_partitions.push((disk_device_storage, partition));
+        match partitions::read_partition_table(&disk_device_storage) {
+            Ok(partition_table) => {
+                log::debug!("Partition table: {:?}", partition_table);
+                match partition_table {
+                    Some(partition_table) => {
+                        for partition in partition_table.partitions {
+                            if partition.type_id == guid::TYPE_ID_LINUX_RAID {
+                                raid_member_candidates.push(Box::new((disk_device_storage, partition)));
+                            } else {
+                                disk_sector_storages_partitions.push((disk_device_storage, partition));
+                            }
+                        }
+                    }
+                    None => {
+                        log_content("Unpartitioned disk", &disk_device_storage);
+                        raid_member_candidates.push(Box::new(disk_device_storage));
+                    }
+                }
             }
+            Err(error) => log::error!("Failed to read partition table: {:?}", error),
         }
     }
-    let root_disk_sector_storage_partition = disk_sector_storages_partitions
+    let raid_members = raid_member_candidates
         .into_iter()
-        .find(|(_, partition)| partition.type_id == guid::TYPE_ID_LINUX && partition.name.as_deref() == Some("kernel_root"))
-        .expect("no root partition found");
-    log::debug!("Root disk sector storage and partition: {:?}", root_disk_sector_storage_partition);
-    let session = ext2::Session::new(&root_disk_sector_storage_partition);
-    logger::println!(
-        "{}Root dir listing:{}",
-        formatting::Style {
-            reset: false,
-            foreground_color: Some(formatting::Color::Magenta),
-            background_color: None,
-        },
-        formatting::Style::RESET
-    );
-    print_tree(&session, 0, 2);
-    logger::println!(
-        "{}Root dir listing end{}",
-        formatting::Style {
-            reset: false,
-            foreground_color: Some(formatting::Color::Magenta),
-            background_color: None,
-        },
-        formatting::Style::RESET
-    );
+        .filter_map(|sector_storage| match raid::read_superblock(&sector_storage) {
+            Ok(superblock) => superblock.map(|superblock| (sector_storage, superblock)),
+            Err(error) => {
+                log::error!("Failed to read md superblock: {:?}", error);
+                None
+            }
+        })
+        .collect();
+    let raid_sector_storages = raid::assemble_arrays(raid_members);
+    for raid_sector_storage in &raid_sector_storages {
+        log::info!("md array {} with {:?} layout", raid_sector_storage.name(), raid_sector_storage.layout());
+        if raid_sector_storage.stale_member_count() != 0 && raid_sector_storage.read_only() {
+            log::warn!("md array has {} stale members but is read-only", raid_sector_storage.stale_member_count());
+        } else if raid_sector_storage.stale_member_count() != 0 {
+            log::info!("Resyncing {} stale md array members", raid_sector_storage.stale_member_count());
+            if let Err(error) = raid_sector_storage.resync() {
+                log::error!("Failed to resync md array: {:?}", error);
+            }
+        }
+        match partitions::read_partition_table(raid_sector_storage) {
+            Ok(Some(partition_table)) => {
+                log::debug!("md array partition table: {:?}", partition_table);
+                for partition in partition_table.partitions {
+                    disk_sector_storages_partitions.push((raid_sector_storage, partition));
+                }
+            }
+            // the whole array as a single partition, identified by the array UUID and name
+            Ok(None) if raid_sector_storage.sector_count() > 0 => {
+                let partition = partitions::Partition {
+                    type_id: guid::TYPE_ID_LINUX,
+                    id: guid::Guid::from_bytes(raid_sector_storage.array_id()),
+                    starting_sector: 0,
+                    ending_sector: raid_sector_storage.sector_count() - 1,
+                    flags: 0,
+                    name: Some(String::from(raid_sector_storage.name())),
+                };
+                disk_sector_storages_partitions.push((raid_sector_storage, partition));
+            }
+            Ok(None) => {}
+            Err(error) => log::error!("Failed to read md array partition table: {:?}", error),
+        }
+    }
+    for disk_sector_storage_partition in &disk_sector_storages_partitions {
+        log_content(&format!("Partition {:?}", disk_sector_storage_partition.1.id), disk_sector_storage_partition);
+    }
+    // an explicit root device takes precedence over the RAM disk
+    let root_disk_sector_storage: Box<dyn SectorStorage + '_> = match (&command_line.root, BOOTLOADER_PROTOCOL.wait().ram_disk_image_data) {
+        (None, Some(ram_disk_image_data)) => {
+            log::debug!("Root RAM disk of {} bytes", ram_disk_image_data.len());
+            Box::new(ram_disk::DiskSectorStorage::from_data(
+                ram_disk::DEFAULT_SECTOR_SIZE,
+                ram_disk_image_data.to_vec(),
+            ))
+        }
+        (root, _) => {
+            let root_disk_sector_storage_partition =
+                find_root_partition(root.as_ref(), &discovery_result.disk_sector_storages, disk_sector_storages_partitions).expect("no root partition found");
+            log::debug!("Root partition: {:?}", root_disk_sector_storage_partition.1);
+            match luks2::read_header(&root_disk_sector_storage_partition).expect("failed to read root partition") {
+                Some(header) => {
+                    log::debug!("Root partition is LUKS2 encrypted, uuid {} label {:?}", header.uuid, header.label);
+                    Box::new(unlock_root(root_disk_sector_storage_partition, &header))
+                }
+                None => Box::new(root_disk_sector_storage_partition),
+            }
+        }
+    };
+    if root_disk_sector_storage.read_only() {
+        log::info!("Root device is read-only");
+    }
+    let root_disk_sector_storage = stats::StatsSectorStorage::new(root_disk_sector_storage, "root device", tsc);
+    let root_writable_sector_storage: Box<dyn SectorStorage + '_> = if command_line.root_read_only {
+        // the root device stays untouched, writes only land in memory
+        let root_delta_sector_storage = ram_disk::DiskSectorStorage::new(
+            root_disk_sector_storage.sector_size(),
+            ROOT_OVERLAY_DELTA_SIZE / root_disk_sector_storage.sector_size(),
+        );
+        Box::new(overlay::OverlaySectorStorage::new(&root_disk_sector_storage, root_delta_sector_storage))
+    } else {
+        log::warn!("Root device is mounted read-write");
+        Box::new(&root_disk_sector_storage)
+    };
+    let root_cached_sector_storage = cache::CachedSectorStorage::new(root_writable_sector_storage, cache::DEFAULT_CAPACITY);
+    // the filesystem requests before the cache, the device stats show what reaches the disk
+    let root_sector_storage = stats::StatsSectorStorage::new(&root_cached_sector_storage, "root fs", tsc);
+    root_sector_storage.set_trace_capacity(ROOT_IO_TRACE_CAPACITY);
+    let root_fs_type = command_line.root_fs_type.unwrap_or_else(|| {
+        let content = probe::probe(&root_sector_storage).expect("failed to probe root filesystem");
+        log::debug!("Root filesystem: {:?}", content);
+        let Some(content) = content else {
+            log::error!("Root device holds no known filesystem");
+            panic::abort()
+        };
+        match content.content_type {
+            probe::ContentType::Ext2 | probe::ContentType::Ext3 => command_line::FsType::Ext2,
+            probe::ContentType::Iso9660 => command_line::FsType::Iso9660,
+            content_type => {
+                log::error!(
+                    "Root device holds {:?} with label {:?} and uuid {:?}, only ext2, ext3 and ISO 9660 root filesystems are supported",
+                    content_type,
+                    content.label,
+                    content.uuid
+                );
+                panic::abort()
+            }
+        }
+    });
+    let root_session: Box<dyn fs::Session + '_> = match root_fs_type {
+        command_line::FsType::Ext2 => {
+            let session = ext2::Session::new(&root_sector_storage)
+                .expect("failed to open root filesystem")
+                .expect("no ext2 root filesystem");
+            // without journal replay the filesystem may be inconsistent
+            if session.needs_recovery() {
+                log::error!("Root filesystem has an ext3 journal that needs recovery, run e2fsck on it before booting");
+                panic::abort()
+            }
+            Box::new(session)
+        }
+        command_line::FsType::Iso9660 => Box::new(
+            iso9660::Session::new(&root_sector_storage)
+                .expect("failed to open root filesystem")
+                .expect("no ISO 9660 root filesystem"),
+        ),
+    };
+    let root_vfs = vfs::Vfs::new(root_session);
+    print_root_tree(&root_vfs);
+    root_sector_storage.log_stats();
+    root_sector_storage.log_trace();
+    root_disk_sector_storage.log_stats();
+    for disk_sector_storage in &discovery_result.disk_sector_storages {
+        let discovery::DiskSectorStorage::Atapi(disk_sector_storage) = disk_sector_storage else {
+            continue;
+        };
+        match iso9660::Session::new(disk_sector_storage) {
+            Ok(Some(session)) => {
+                log::info!("Disc listing:");
+                let disc_vfs = vfs::Vfs::new(Box::new(session));
+                if let Err(error) = print_tree(&disc_vfs, 0, disc_vfs.root()) {
+                    log::error!("Failed to list disc: {:?}", error);
+                }
+            }
+            Ok(None) => log::info!("Disc without an ISO 9660 filesystem"),
+            Err(error) => log::error!("Failed to read disc: {:?}", error),
+        }
+    }
     loop {
         logger::update();
     }
@@ -145,6 +419,7 @@ fn init() {
 fn main(image_handle: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi::table::Boot>) -> uefi::Status {
     unsafe {
         SYSTEM_TABLE = Some(system_table.unsafe_clone());
+        IMAGE_HANDLE = Some(image_handle);
     }
     let gop_handle = system_table
         .boot_services()
@@ -164,6 +439,14 @@ fn main(image_handle: uefi::Handle, mut system_table: uefi::table::SystemTable<u
             .open_protocol_exclusive::<BootloaderProtocol>(image_handle)
             .unwrap()
     });
+    COMMAND_LINE.call_once(|| {
+        let load_options = system_table
+            .boot_services()
+            .open_protocol_exclusive::<uefi::proto::loaded_image::LoadedImage>(image_handle)
+            .ok()
+            .and_then(|loaded_image| loaded_image.load_options_as_cstr16().ok().map(ToString::to_string));
+        command_line::CommandLine::parse(load_options.as_deref().unwrap_or(""))
+    });
     backtrace::init();
     panic::catch_unwind_with_default_handler(init);
     system_table.boot_services().stall(100_000_000);
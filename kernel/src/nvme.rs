@@ -0,0 +1,415 @@
+use alloc::{rc::Rc, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    sync::atomic::{fence, Ordering},
+};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_NVM: u8 = 0x08;
+const PCI_PROG_IF_NVME: u8 = 0x02;
+const PCI_BAR_INDEX_REGISTERS: u8 = 0;
+
+const REGISTER_CAP: usize = 0x00;
+const REGISTER_CC: usize = 0x14;
+const REGISTER_CSTS: usize = 0x1C;
+const REGISTER_AQA: usize = 0x24;
+const REGISTER_ASQ: usize = 0x28;
+const REGISTER_ACQ: usize = 0x30;
+const REGISTER_DOORBELLS: usize = 0x1000;
+
+const CC_BIT_EN: u32 = 1 << 0;
+const CC_IOSQES: u32 = 6 << 16; // 64-byte submission queue entries
+const CC_IOCQES: u32 = 4 << 20; // 16-byte completion queue entries
+
+const CSTS_BIT_RDY: u32 = 1 << 0;
+const CSTS_BIT_CFS: u32 = 1 << 1;
+
+const ADMIN_OPCODE_CREATE_IO_SUBMISSION_QUEUE: u32 = 0x01;
+const ADMIN_OPCODE_CREATE_IO_COMPLETION_QUEUE: u32 = 0x05;
+const ADMIN_OPCODE_IDENTIFY: u32 = 0x06;
+
+const IO_OPCODE_FLUSH: u32 = 0x00;
+const IO_OPCODE_WRITE: u32 = 0x01;
+const IO_OPCODE_READ: u32 = 0x02;
+
+const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const IDENTIFY_CNS_ACTIVE_NAMESPACE_LIST: u32 = 0x02;
+
+const PAGE_SIZE: u64 = 4096;
+const QUEUE_SIZE: u16 = 64;
+const SUBMISSION_QUEUE_ENTRY_SIZE: usize = 64;
+const COMPLETION_QUEUE_ENTRY_SIZE: usize = 16;
+const DATA_BUFFER_SIZE: u64 = 64 * 1024;
+
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+const POLL_LIMIT: u64 = 10_000_000;
+
+struct QueuePair {
+    id: u16,
+    size: u16,
+    submission_queue: super::dma::Buffer,
+    completion_queue: super::dma::Buffer,
+    submission_tail: u16,
+    completion_head: u16,
+    phase: bool,
+    next_command_id: u16,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16) -> Self {
+        QueuePair {
+            id,
+            size,
+            submission_queue: super::dma::Buffer::new(size as usize * SUBMISSION_QUEUE_ENTRY_SIZE, PAGE_SIZE as usize),
+            completion_queue: super::dma::Buffer::new(size as usize * COMPLETION_QUEUE_ENTRY_SIZE, PAGE_SIZE as usize),
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+            next_command_id: 0,
+        }
+    }
+
+    unsafe fn execute(&mut self, registers: *mut u8, doorbell_stride: usize, mut command: [u32; 16]) -> Result<u32, StorageError> {
+        command[0] |= (self.next_command_id as u32) << 16;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        (self.submission_queue.as_ptr() as *mut [u32; 16])
+            .add(self.submission_tail as usize)
+            .write_volatile(command);
+        self.submission_tail = (self.submission_tail + 1) % self.size;
+        fence(Ordering::SeqCst);
+        (registers.add(REGISTER_DOORBELLS + (2 * self.id as usize) * doorbell_stride) as *mut u32).write_volatile(self.submission_tail as u32);
+        for _ in 0..POLL_LIMIT {
+            let completion = (self.completion_queue.as_ptr() as *const [u32; 4])
+                .add(self.completion_head as usize)
+                .read_volatile();
+            let status = (completion[3] >> 16) as u16;
+            if (status & 1 != 0) == self.phase {
+                fence(Ordering::SeqCst);
+                self.completion_head += 1;
+                if self.completion_head == self.size {
+                    self.completion_head = 0;
+                    self.phase = !self.phase;
+                }
+                (registers.add(REGISTER_DOORBELLS + (2 * self.id as usize + 1) * doorbell_stride) as *mut u32).write_volatile(self.completion_head as u32);
+                if status >> 1 != 0 {
+                    return Err(StorageError::DeviceError);
+                }
+                return Ok(completion[0]);
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+}
+
+struct Controller {
+    device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
+    registers: *mut u8,
+    doorbell_stride: usize,
+    max_transfer_size: u64,
+    admin_queue_pair: RefCell<QueuePair>,
+    io_queue_pair: RefCell<QueuePair>,
+    data_buffer: super::dma::Buffer,
+    prp_list: super::dma::Buffer,
+    failed: Cell<bool>, // a timed out command may still complete and write to the data buffer, so no further commands are issued
+}
+
+impl Controller {
+    fn new(pci_root: &mut virtio_drivers::transport::pci::bus::PciRoot, device_function: virtio_drivers::transport::pci::bus::DeviceFunction) -> Option<Self> {
+        pci_root.set_command(
+            device_function,
+            virtio_drivers::transport::pci::bus::Command::MEMORY_SPACE | virtio_drivers::transport::pci::bus::Command::BUS_MASTER,
+        );
+        let Ok(virtio_drivers::transport::pci::bus::BarInfo::Memory { address, .. }) = pci_root.bar_info(device_function, PCI_BAR_INDEX_REGISTERS) else {
+            return None;
+        };
+        let registers = address as *mut u8;
+        let capabilities = unsafe { (registers.add(REGISTER_CAP) as *mut u64).read_volatile() };
+        let queue_size = (QUEUE_SIZE as u64).min((capabilities & 0xFFFF) + 1) as u16; // MQES is zero-based, 0xFFFF is 65536 entries
+        let mut controller = Controller {
+            device_function,
+            registers,
+            doorbell_stride: 4 << ((capabilities >> 32) & 0xF),
+            max_transfer_size: DATA_BUFFER_SIZE,
+            admin_queue_pair: RefCell::new(QueuePair::new(ADMIN_QUEUE_ID, queue_size)),
+            io_queue_pair: RefCell::new(QueuePair::new(IO_QUEUE_ID, queue_size)),
+            data_buffer: super::dma::Buffer::new(DATA_BUFFER_SIZE as usize, PAGE_SIZE as usize),
+            prp_list: super::dma::Buffer::new(PAGE_SIZE as usize, PAGE_SIZE as usize),
+            failed: Cell::new(false),
+        };
+        unsafe {
+            for page_index in 1..DATA_BUFFER_SIZE / PAGE_SIZE {
+                (controller.prp_list.as_ptr() as *mut u64)
+                    .add(page_index as usize - 1)
+                    .write_volatile(controller.data_buffer.physical_address() + page_index * PAGE_SIZE);
+            }
+            controller.write_register(REGISTER_CC, controller.read_register(REGISTER_CC) & !CC_BIT_EN);
+            controller.wait_for_ready(false).ok()?;
+            let admin_queue_pair = controller.admin_queue_pair.borrow();
+            controller.write_register(REGISTER_AQA, (queue_size as u32 - 1) << 16 | (queue_size as u32 - 1));
+            (registers.add(REGISTER_ASQ) as *mut u64).write_volatile(admin_queue_pair.submission_queue.physical_address());
+            (registers.add(REGISTER_ACQ) as *mut u64).write_volatile(admin_queue_pair.completion_queue.physical_address());
+            drop(admin_queue_pair);
+            controller.write_register(REGISTER_CC, CC_BIT_EN | CC_IOSQES | CC_IOCQES);
+            controller.wait_for_ready(true).ok()?;
+            controller.identify(IDENTIFY_CNS_CONTROLLER, 0).ok()?;
+        }
+        let maximum_data_transfer_size = unsafe { *controller.data_buffer.as_ptr().add(77) }; // MDTS
+        if maximum_data_transfer_size != 0 {
+            controller.max_transfer_size = DATA_BUFFER_SIZE.min(PAGE_SIZE << maximum_data_transfer_size);
+        }
+        let io_queue_pair = controller.io_queue_pair.borrow();
+        let mut command = [0; 16];
+        command[0] = ADMIN_OPCODE_CREATE_IO_COMPLETION_QUEUE;
+        command[6] = io_queue_pair.completion_queue.physical_address() as u32;
+        command[7] = (io_queue_pair.completion_queue.physical_address() >> 32) as u32;
+        command[10] = (queue_size as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+        command[11] = 1; // physically contiguous, no interrupts
+        unsafe { controller.execute_admin_command(command) }.ok()?;
+        let mut command = [0; 16];
+        command[0] = ADMIN_OPCODE_CREATE_IO_SUBMISSION_QUEUE;
+        command[6] = io_queue_pair.submission_queue.physical_address() as u32;
+        command[7] = (io_queue_pair.submission_queue.physical_address() >> 32) as u32;
+        command[10] = (queue_size as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+        command[11] = (IO_QUEUE_ID as u32) << 16 | 1; // completion queue, physically contiguous
+        unsafe { controller.execute_admin_command(command) }.ok()?;
+        drop(io_queue_pair);
+        Some(controller)
+    }
+
+    unsafe fn read_register(&self, register: usize) -> u32 {
+        (self.registers.add(register) as *mut u32).read_volatile()
+    }
+
+    unsafe fn write_register(&self, register: usize, value: u32) {
+        (self.registers.add(register) as *mut u32).write_volatile(value)
+    }
+
+    unsafe fn wait_for_ready(&self, ready: bool) -> Result<(), StorageError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.read_register(REGISTER_CSTS);
+            if status & CSTS_BIT_CFS != 0 {
+                return Err(StorageError::DeviceError);
+            }
+            if (status & CSTS_BIT_RDY != 0) == ready {
+                return Ok(());
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+
+    unsafe fn execute(&self, queue_pair: &RefCell<QueuePair>, command: [u32; 16]) -> Result<u32, StorageError> {
+        if self.failed.get() {
+            return Err(StorageError::DeviceError);
+        }
+        let result = queue_pair.borrow_mut().execute(self.registers, self.doorbell_stride, command);
+        if result == Err(StorageError::Timeout) {
+            log::error!("NVMe command timed out, disabling controller {:?}", self.device_function);
+            self.failed.set(true);
+        }
+        result
+    }
+
+    unsafe fn execute_admin_command(&self, command: [u32; 16]) -> Result<u32, StorageError> {
+        self.execute(&self.admin_queue_pair, command)
+    }
+
+    unsafe fn execute_io_command(&self, command: [u32; 16]) -> Result<u32, StorageError> {
+        self.execute(&self.io_queue_pair, command)
+    }
+
+    unsafe fn identify(&self, cns: u32, namespace_id: u32) -> Result<Vec<u8>, StorageError> {
+        let mut command = [0; 16];
+        command[0] = ADMIN_OPCODE_IDENTIFY;
+        command[1] = namespace_id;
+        command[6] = self.data_buffer.physical_address() as u32;
+        command[7] = (self.data_buffer.physical_address() >> 32) as u32;
+        command[10] = cns;
+        self.execute_admin_command(command)?;
+        Ok(core::slice::from_raw_parts(self.data_buffer.as_ptr(), PAGE_SIZE as usize).to_vec())
+    }
+
+    unsafe fn transfer(&self, opcode: u32, namespace_id: u32, lba: u64, lba_count: u64, len: u64) -> Result<(), StorageError> {
+        assert!(len > 0 && len <= self.max_transfer_size);
+        let mut command = [0; 16];
+        command[0] = opcode;
+        command[1] = namespace_id;
+        command[6] = self.data_buffer.physical_address() as u32;
+        command[7] = (self.data_buffer.physical_address() >> 32) as u32;
+        let prp2 = match len.div_ceil(PAGE_SIZE) {
+            1 => 0,
+            2 => self.data_buffer.physical_address() + PAGE_SIZE,
+            _ => self.prp_list.physical_address(),
+        };
+        command[8] = prp2 as u32;
+        command[9] = (prp2 >> 32) as u32;
+        command[10] = lba as u32;
+        command[11] = (lba >> 32) as u32;
+        command[12] = (lba_count - 1) as u32;
+        self.execute_io_command(command)?;
+        Ok(())
+    }
+}
+
+// disabling the controller stops it from using the queues and the data buffer before they are freed
+impl Drop for Controller {
+    fn drop(&mut self) {
+        unsafe {
+            self.write_register(REGISTER_CC, self.read_register(REGISTER_CC) & !CC_BIT_EN);
+            if self.wait_for_ready(false).is_err() {
+                log::error!("NVMe controller {:?} did not disable", self.device_function);
+            }
+        }
+    }
+}
+
+pub struct DiskSectorStorage {
+    controller: Rc<Controller>,
+    namespace_id: u32,
+    lba_size: u64,
+    lba_count: u64,
+}
+
+impl Debug for DiskSectorStorage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DiskSectorStorage")
+            .field("device_function", &self.controller.device_function)
+            .field("namespace_id", &self.namespace_id)
+            .field("lba_size", &self.lba_size)
+            .finish()
+    }
+}
+
+impl DiskSectorStorage {
+    pub fn new_all(
+        pci_root: &mut virtio_drivers::transport::pci::bus::PciRoot,
+        device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
+        device_function_info: &virtio_drivers::transport::pci::bus::DeviceFunctionInfo,
+    ) -> Vec<Self> {
+        if (device_function_info.class, device_function_info.subclass, device_function_info.prog_if)
+            != (PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_NVM, PCI_PROG_IF_NVME)
+        {
+            return Vec::new();
+        }
+        let Some(controller) = Controller::new(pci_root, device_function) else {
+            return Vec::new();
+        };
+        let controller = Rc::new(controller);
+        let Ok(namespace_list_data) = (unsafe { controller.identify(IDENTIFY_CNS_ACTIVE_NAMESPACE_LIST, 0) }) else {
+            return Vec::new();
+        };
+        bytemuck::cast_slice::<u8, u32>(&namespace_list_data)
+            .iter()
+            .copied()
+            .take_while(|&namespace_id| namespace_id != 0)
+            .filter_map(|namespace_id| {
+                log::debug!("NVMe namespace {}", namespace_id);
+                DiskSectorStorage::new(controller.clone(), namespace_id)
+            })
+            .collect()
+    }
+
+    pub fn device_function(&self) -> virtio_drivers::transport::pci::bus::DeviceFunction {
+        self.controller.device_function
+    }
+
+    pub fn namespace_id(&self) -> u32 {
+        self.namespace_id
+    }
+
+    fn new(controller: Rc<Controller>, namespace_id: u32) -> Option<Self> {
+        let namespace_data = unsafe { controller.identify(IDENTIFY_CNS_NAMESPACE, namespace_id) }.ok()?;
+        let lba_count = u64::from_le_bytes(namespace_data[0..8].try_into().unwrap()); // NSZE
+        let lba_format_index = (namespace_data[26] & 0xF) as usize; // FLBAS
+        let lba_format = u32::from_le_bytes(namespace_data[128 + lba_format_index * 4..128 + lba_format_index * 4 + 4].try_into().unwrap());
+        let lba_size: u64 = 1 << ((lba_format >> 16) & 0xFF); // LBADS
+        if lba_size > controller.max_transfer_size {
+            log::warn!("Unsupported NVMe LBA size {}", lba_size);
+            return None;
+        }
+        Some(DiskSectorStorage {
+            controller,
+            namespace_id,
+            lba_size,
+            lba_count,
+        })
+    }
+
+    fn read_lbas(&self, lba: u64, lba_count: u64) -> Result<Vec<u8>, StorageError> {
+        let mut lbas_data = Vec::with_capacity((lba_count * self.lba_size) as usize);
+        let max_lba_count_per_command = self.controller.max_transfer_size / self.lba_size;
+        for chunk_lba in (lba..lba + lba_count).step_by(max_lba_count_per_command as usize) {
+            let chunk_lba_count = (lba + lba_count - chunk_lba).min(max_lba_count_per_command);
+            let chunk_len = chunk_lba_count * self.lba_size;
+            unsafe {
+                self.controller
+                    .transfer(IO_OPCODE_READ, self.namespace_id, chunk_lba, chunk_lba_count, chunk_len)?;
+                lbas_data.extend_from_slice(core::slice::from_raw_parts(self.controller.data_buffer.as_ptr(), chunk_len as usize));
+            }
+        }
+        Ok(lbas_data)
+    }
+
+    fn write_lbas(&self, lba: u64, lbas_data: &[u8]) -> Result<(), StorageError> {
+        let max_lba_count_per_command = self.controller.max_transfer_size / self.lba_size;
+        for (chunk_index, chunk_data) in lbas_data.chunks((max_lba_count_per_command * self.lba_size) as usize).enumerate() {
+            unsafe {
+                core::slice::from_raw_parts_mut(self.controller.data_buffer.as_ptr(), chunk_data.len()).copy_from_slice(chunk_data);
+                self.controller.transfer(
+                    IO_OPCODE_WRITE,
+                    self.namespace_id,
+                    lba + chunk_index as u64 * max_lba_count_per_command,
+                    chunk_data.len() as u64 / self.lba_size,
+                    chunk_data.len() as u64,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.lba_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.lba_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.lba_count {
+            return Err(StorageError::OutOfRange);
+        }
+        self.read_lbas(sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.lba_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.lba_size > self.lba_count {
+            return Err(StorageError::OutOfRange);
+        }
+        self.write_lbas(sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let mut command = [0; 16];
+        command[0] = IO_OPCODE_FLUSH;
+        command[1] = self.namespace_id;
+        unsafe { self.controller.execute_io_command(command)? };
+        Ok(())
+    }
+}
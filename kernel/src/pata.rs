@@ -1,4 +1,16 @@
-use super::sector_storage::{SectorStorage, SECTOR_SIZE};
+use alloc::{rc::Rc, vec::Vec};
+use core::{fmt::Debug, ops::Range};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_IDE: u8 = 0x01;
+const PCI_PROG_IF_BIT_PRIMARY_NATIVE: u8 = 1 << 0;
+const PCI_PROG_IF_BIT_SECONDARY_NATIVE: u8 = 1 << 2;
+const PCI_PROG_IF_BIT_BUS_MASTER: u8 = 1 << 7;
+const PCI_BAR_INDEX_BUS_MASTER: u8 = 4;
+
+const SECTOR_SIZE: u64 = 512;
 
 const PORT_BASE_CONTROL_PRIMARY: u16 = 0x3F6;
 const PORT_BASE_CONTROL_SECONDARY: u16 = 0x376;
@@ -8,7 +20,18 @@ const PORT_BASE_IO_SECONDARY: u16 = 0x170;
 
 const PORT_OFFSET_CONTROL: u16 = 0x0;
 
+const IRQ_PRIMARY: u8 = 14;
+const IRQ_SECONDARY: u8 = 15;
+
+const PORT_OFFSET_BUS_MASTER_PRIMARY: u16 = 0x0;
+const PORT_OFFSET_BUS_MASTER_SECONDARY: u16 = 0x8;
+
+const PORT_OFFSET_BUS_MASTER_COMMAND: u16 = 0x0;
+const PORT_OFFSET_BUS_MASTER_STATUS: u16 = 0x2;
+const PORT_OFFSET_BUS_MASTER_PRD_TABLE_ADDRESS: u16 = 0x4;
+
 const PORT_OFFSET_DATA: u16 = 0x0;
+const PORT_OFFSET_FEATURES: u16 = 0x1;
 const PORT_OFFSET_SECTOR_COUNT: u16 = 0x2;
 const PORT_OFFSET_SECTOR_NUMBER: u16 = 0x3;
 const PORT_OFFSET_CYLINDER_LOW: u16 = 0x4;
@@ -19,16 +42,62 @@ const PORT_OFFSET_STATUS: u16 = 0x7;
 
 const CONTROL_BIT_NIEN: u8 = 1 << 3;
 
+const BUS_MASTER_COMMAND_BIT_START: u8 = 1 << 0;
+const BUS_MASTER_COMMAND_BIT_READ: u8 = 1 << 3; // device to memory
+
+const BUS_MASTER_STATUS_BIT_ACTIVE: u8 = 1 << 0;
+const BUS_MASTER_STATUS_BIT_ERROR: u8 = 1 << 1;
+const BUS_MASTER_STATUS_BIT_INTERRUPT: u8 = 1 << 2;
+
+const PRD_BIT_END_OF_TABLE: u32 = 1 << 31;
+
 const STATUS_BIT_ERR: u8 = 1 << 0;
 const STATUS_BIT_DRQ: u8 = 1 << 3;
 const STATUS_BIT_BSY: u8 = 1 << 7;
 
 const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
 const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
-const COMMAND_FLUSH_CACHE: u8 = 0xE7;
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_WRITE_DMA_FUA_EXT: u8 = 0x3D;
+const COMMAND_DATA_SET_MANAGEMENT: u8 = 0x06;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
 const COMMAND_IDENTIFY: u8 = 0xEC;
+const COMMAND_PACKET: u8 = 0xA0;
+const COMMAND_IDENTIFY_PACKET_DEVICE: u8 = 0xA1;
+
+const FEATURE_TRIM: u16 = 0x01; // for DATA SET MANAGEMENT
+
+const PACKET_COMMAND_READ_CAPACITY: u8 = 0x25;
+const PACKET_COMMAND_READ_10: u8 = 0x28;
+
+const PACKET_BYTE_COUNT_LIMIT: u16 = 0xF800;
+const MAX_BLOCK_COUNT_PER_PACKET_COMMAND: u64 = 32;
 
-#[derive(Copy, Clone, Debug)]
+const MAX_SECTOR_COUNT_PER_COMMAND: u64 = 65536;
+const MAX_SECTOR_COUNT_PER_DMA_COMMAND: u64 = 128; // a single PRD entry of 64 KiB
+const MAX_SECTOR_COUNT_PER_TRIM_RANGE: u64 = 0xFFFF;
+const TRIM_RANGE_COUNT_PER_BLOCK: usize = 64;
+
+const STATUS_POLL_LIMIT: u64 = 10_000_000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+impl Channel {
+    // the legacy ports come with the legacy edge-triggered IRQs
+    fn irq(self) -> Option<super::interrupts::Irq> {
+        match self {
+            Channel::Primary => super::interrupts::Irq::new(IRQ_PRIMARY, false),
+            Channel::Secondary => super::interrupts::Irq::new(IRQ_SECONDARY, false),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Device {
     PrimaryMaster,
     PrimarySlave,
@@ -37,6 +106,15 @@ pub enum Device {
 }
 
 impl Device {
+    fn channel(self) -> Channel {
+        match self {
+            Device::PrimaryMaster => Channel::Primary,
+            Device::PrimarySlave => Channel::Primary,
+            Device::SecondaryMaster => Channel::Secondary,
+            Device::SecondarySlave => Channel::Secondary,
+        }
+    }
+
     fn port_base_control(self) -> u16 {
         match self {
             Device::PrimaryMaster => PORT_BASE_CONTROL_PRIMARY,
@@ -65,7 +143,14 @@ impl Device {
     }
 }
 
-pub fn identify(device: Device) -> Option<u64> {
+#[derive(Clone, Copy, Debug)]
+pub struct Identity {
+    pub sector_count: u64,
+    pub write_fua: bool,
+    pub trim: bool,
+}
+
+pub fn identify(device: Device) -> Option<Identity> {
     unsafe {
         let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
         if status == 0xFF {
@@ -78,127 +163,569 @@ pub fn identify(device: Device) -> Option<u64> {
         x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, 0);
         x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, 0);
         x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_IDENTIFY);
-        for _ in 0..15 {
-            // wait for the device to be selected (to read the status register of the correct one)
-            x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-        }
-        loop {
-            let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-            if status & STATUS_BIT_BSY != 0 {
-                continue;
-            }
-            if status & STATUS_BIT_ERR != 0 || status & STATUS_BIT_DRQ == 0 {
-                return None; // probably not an ATA drive
-            }
-            break;
+        // also waits for the device to be selected (to read the status register of the correct one)
+        let Ok(status) = wait_while_busy(device) else {
+            log::warn!("PATA device {:?} stays busy", device);
+            return None;
+        };
+        if status & STATUS_BIT_ERR != 0 || status & STATUS_BIT_DRQ == 0 {
+            return None; // probably not an ATA drive
         }
         let mut identify_data: [u16; SECTOR_SIZE as usize / 2] = [0; SECTOR_SIZE as usize / 2];
         for word in identify_data.iter_mut() {
             *word = x86::io::inw(device.port_base_io() + PORT_OFFSET_DATA);
         }
-        assert!(identify_data[83] & (1 << 10) != 0); // LBA48 mode is supported
-        return Some(u64::from_le_bytes(bytemuck::cast_slice(&identify_data[100..104]).try_into().unwrap()));
+        if identify_data[83] & (1 << 10) == 0 {
+            log::warn!("PATA device {:?} does not support LBA48", device);
+            return None;
+        }
+        return Some(Identity {
+            sector_count: u64::from_le_bytes(bytemuck::cast_slice(&identify_data[100..104]).try_into().unwrap()),
+            write_fua: identify_data[84] & (1 << 6) != 0,
+            trim: identify_data[169] & (1 << 0) != 0,
+        });
+    }
+}
+
+unsafe fn wait_for_status(device: Device) {
+    for _ in 0..15 {
+        // wait for the status register to become valid
+        x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
+    }
+}
+
+unsafe fn wait_while_busy(device: Device) -> Result<u8, StorageError> {
+    wait_for_status(device);
+    for _ in 0..STATUS_POLL_LIMIT {
+        let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
+        if status & STATUS_BIT_BSY == 0 {
+            return Ok(status);
+        }
     }
+    Err(StorageError::Timeout)
 }
 
-pub fn read_sector(device: Device, sector_index: u64) -> [u8; SECTOR_SIZE as usize] {
+unsafe fn wait_for_data_request(device: Device) -> Result<(), StorageError> {
+    let status = wait_while_busy(device)?;
+    if status & STATUS_BIT_ERR != 0 || status & STATUS_BIT_DRQ == 0 {
+        return Err(StorageError::DeviceError);
+    }
+    Ok(())
+}
+
+unsafe fn send_lba48_command(device: Device, sector_index: u64, sector_count: u64, command: u8, features: u16) -> Result<(), StorageError> {
     let sector_index_bytes: [u8; 8] = sector_index.to_le_bytes();
-    assert!(sector_index_bytes[6] == 0 && sector_index_bytes[7] == 0);
+    if sector_index_bytes[6] != 0 || sector_index_bytes[7] != 0 {
+        return Err(StorageError::OutOfRange);
+    }
+    assert!(sector_count > 0 && sector_count <= MAX_SECTOR_COUNT_PER_COMMAND);
+    let sector_count_bytes: [u8; 8] = (sector_count % MAX_SECTOR_COUNT_PER_COMMAND).to_le_bytes(); // 0 means 65536
+    let features_bytes: [u8; 2] = features.to_le_bytes();
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, 0x40 | device.device_bit());
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_FEATURES, features_bytes[1]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, sector_count_bytes[1]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[3]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[4]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[5]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_FEATURES, features_bytes[0]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, sector_count_bytes[0]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[0]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[1]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[2]);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, command);
+    Ok(())
+}
+
+pub fn read_sectors(device: Device, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+    let mut sectors_data = Vec::with_capacity((sector_count * SECTOR_SIZE) as usize);
     unsafe {
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, 0x40 | device.device_bit());
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, 0);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[3]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[4]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[5]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, 1);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[0]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[1]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[2]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_READ_SECTORS_EXT);
-        for _ in 0..15 {
-            x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-        }
-        loop {
-            let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-            if status & STATUS_BIT_BSY != 0 {
-                continue;
+        send_lba48_command(device, sector_index, sector_count, COMMAND_READ_SECTORS_EXT, 0)?;
+        for _ in 0..sector_count {
+            wait_for_data_request(device)?;
+            for _ in 0..SECTOR_SIZE / 2 {
+                sectors_data.extend_from_slice(&x86::io::inw(device.port_base_io() + PORT_OFFSET_DATA).to_le_bytes());
             }
-            assert!(status & STATUS_BIT_ERR == 0 && status & STATUS_BIT_DRQ != 0);
-            break;
         }
-        let mut sector_data: [u16; SECTOR_SIZE as usize / 2] = [0; SECTOR_SIZE as usize / 2];
-        for word in sector_data.iter_mut() {
-            *word = x86::io::inw(device.port_base_io() + PORT_OFFSET_DATA);
+    }
+    Ok(sectors_data)
+}
+
+pub fn write_sectors(device: Device, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+    assert!(sectors_data.len() as u64 % SECTOR_SIZE == 0);
+    let sector_count = sectors_data.len() as u64 / SECTOR_SIZE;
+    unsafe {
+        send_lba48_command(device, sector_index, sector_count, COMMAND_WRITE_SECTORS_EXT, 0)?;
+        for sector_data in sectors_data.chunks(SECTOR_SIZE as usize) {
+            wait_for_data_request(device)?;
+            for word in sector_data.array_chunks() {
+                x86::io::outw(device.port_base_io() + PORT_OFFSET_DATA, u16::from_le_bytes(*word));
+            }
+        }
+        if wait_while_busy(device)? & STATUS_BIT_ERR != 0 {
+            return Err(StorageError::DeviceError);
         }
-        return bytemuck::cast_slice(&sector_data).try_into().unwrap();
     }
+    Ok(())
 }
 
-pub fn write_sector(device: Device, sector_index: u64, sector_data: [u8; SECTOR_SIZE as usize]) {
-    let sector_index_bytes: [u8; 8] = sector_index.to_le_bytes();
-    assert!(sector_index_bytes[6] == 0 && sector_index_bytes[7] == 0);
-    let sector_data: [u16; SECTOR_SIZE as usize / 2] = bytemuck::cast_slice(&sector_data).try_into().unwrap();
+pub fn flush_cache(device: Device) -> Result<(), StorageError> {
     unsafe {
         x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, 0x40 | device.device_bit());
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, 0);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[3]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[4]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[5]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_COUNT, 1);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_SECTOR_NUMBER, sector_index_bytes[0]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, sector_index_bytes[1]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, sector_index_bytes[2]);
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_WRITE_SECTORS_EXT);
-        for _ in 0..15 {
-            x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-        }
-        loop {
-            let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-            if status & STATUS_BIT_BSY != 0 {
-                continue;
+        x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_FLUSH_CACHE_EXT);
+        if wait_while_busy(device)? & STATUS_BIT_ERR != 0 {
+            return Err(StorageError::DeviceError);
+        }
+    }
+    Ok(())
+}
+
+pub fn identify_packet_device(device: Device) -> bool {
+    unsafe {
+        let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
+        if status == 0xFF {
+            return false; // disconnected
+        }
+        x86::io::outb(device.port_base_control() + PORT_OFFSET_CONTROL, CONTROL_BIT_NIEN); // do not send IRQs
+        x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, 0xA0 | device.device_bit());
+        x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_IDENTIFY_PACKET_DEVICE);
+        if wait_for_data_request(device).is_err() {
+            return false;
+        }
+        let mut identify_data: [u16; SECTOR_SIZE as usize / 2] = [0; SECTOR_SIZE as usize / 2];
+        for word in identify_data.iter_mut() {
+            *word = x86::io::inw(device.port_base_io() + PORT_OFFSET_DATA);
+        }
+        identify_data[0] >> 14 == 0b10 && (identify_data[0] >> 8) & 0x1F == 0x05 // ATAPI CD/DVD device
+    }
+}
+
+unsafe fn send_packet_command(device: Device, packet: [u8; 12]) -> Result<(), StorageError> {
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, 0xA0 | device.device_bit());
+    wait_while_busy(device)?;
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_FEATURES, 0); // PIO
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW, PACKET_BYTE_COUNT_LIMIT as u8);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH, (PACKET_BYTE_COUNT_LIMIT >> 8) as u8);
+    x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_PACKET);
+    wait_for_data_request(device)?;
+    for word in packet.array_chunks() {
+        x86::io::outw(device.port_base_io() + PORT_OFFSET_DATA, u16::from_le_bytes(*word));
+    }
+    Ok(())
+}
+
+unsafe fn read_packet_data(device: Device, len: usize) -> Result<Vec<u8>, StorageError> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        wait_for_data_request(device)?;
+        let byte_count = x86::io::inb(device.port_base_io() + PORT_OFFSET_CYLINDER_LOW) as usize
+            | (x86::io::inb(device.port_base_io() + PORT_OFFSET_CYLINDER_HIGH) as usize) << 8;
+        for _ in 0..byte_count.div_ceil(2) {
+            data.extend_from_slice(&x86::io::inw(device.port_base_io() + PORT_OFFSET_DATA).to_le_bytes());
+        }
+    }
+    if wait_while_busy(device)? & STATUS_BIT_ERR != 0 {
+        return Err(StorageError::DeviceError);
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+pub fn read_capacity(device: Device) -> Result<(u64, u64), StorageError> {
+    let packet = [PACKET_COMMAND_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let capacity_data = unsafe {
+        send_packet_command(device, packet)?;
+        read_packet_data(device, 8)?
+    };
+    let last_block_index = u32::from_be_bytes(capacity_data[0..4].try_into().unwrap()) as u64;
+    let block_size = u32::from_be_bytes(capacity_data[4..8].try_into().unwrap()) as u64;
+    Ok((last_block_index + 1, block_size))
+}
+
+pub fn read_blocks(device: Device, block_index: u64, block_count: u64, block_size: u64) -> Result<Vec<u8>, StorageError> {
+    let block_index_bytes: [u8; 4] = u32::try_from(block_index).map_err(|_| StorageError::OutOfRange)?.to_be_bytes();
+    let block_count_bytes: [u8; 2] = u16::try_from(block_count).unwrap().to_be_bytes();
+    let packet = [
+        PACKET_COMMAND_READ_10,
+        0,
+        block_index_bytes[0],
+        block_index_bytes[1],
+        block_index_bytes[2],
+        block_index_bytes[3],
+        0,
+        block_count_bytes[0],
+        block_count_bytes[1],
+        0,
+        0,
+        0,
+    ];
+    unsafe {
+        send_packet_command(device, packet)?;
+        read_packet_data(device, (block_count * block_size) as usize)
+    }
+}
+
+pub struct BusMaster {
+    channel: Channel,
+    port_base: u16,
+    irq: Option<super::interrupts::Irq>,
+    prd_table: super::dma::Buffer,
+    data_buffer: super::dma::Buffer,
+}
+
+impl Debug for BusMaster {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BusMaster")
+            .field("channel", &self.channel)
+            .field("port_base", &self.port_base)
+            .field("irq", &self.irq)
+            .finish()
+    }
+}
+
+impl BusMaster {
+    pub fn new_all(
+        pci_root: &mut virtio_drivers::transport::pci::bus::PciRoot,
+        device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
+        device_function_info: &virtio_drivers::transport::pci::bus::DeviceFunctionInfo,
+    ) -> Vec<Rc<Self>> {
+        if (device_function_info.class, device_function_info.subclass) != (PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_IDE)
+            || device_function_info.prog_if & PCI_PROG_IF_BIT_BUS_MASTER == 0
+        {
+            return Vec::new();
+        }
+        let Ok(virtio_drivers::transport::pci::bus::BarInfo::IO { address, .. }) = pci_root.bar_info(device_function, PCI_BAR_INDEX_BUS_MASTER) else {
+            return Vec::new();
+        };
+        pci_root.set_command(
+            device_function,
+            virtio_drivers::transport::pci::bus::Command::IO_SPACE | virtio_drivers::transport::pci::bus::Command::BUS_MASTER,
+        );
+        let mut bus_masters = Vec::new();
+        for (channel, port_offset, prog_if_bit_native) in [
+            (Channel::Primary, PORT_OFFSET_BUS_MASTER_PRIMARY, PCI_PROG_IF_BIT_PRIMARY_NATIVE),
+            (Channel::Secondary, PORT_OFFSET_BUS_MASTER_SECONDARY, PCI_PROG_IF_BIT_SECONDARY_NATIVE),
+        ] {
+            if device_function_info.prog_if & prog_if_bit_native != 0 {
+                continue; // the channel does not use the legacy ports
+            }
+            let bus_master = BusMaster {
+                channel,
+                port_base: address as u16 + port_offset,
+                irq: channel.irq(),
+                prd_table: super::dma::Buffer::new(8, 4),
+                data_buffer: super::dma::Buffer::new((MAX_SECTOR_COUNT_PER_DMA_COMMAND * SECTOR_SIZE) as usize, 0x10000), // must not cross a 64 KiB boundary
+            };
+            if bus_master.prd_table.physical_address() > u32::MAX as u64 || bus_master.data_buffer.physical_address() > u32::MAX as u64 {
+                continue; // the controller can only address 32 bits
             }
-            assert!(status & STATUS_BIT_ERR == 0 && status & STATUS_BIT_DRQ != 0);
-            break;
+            bus_masters.push(Rc::new(bus_master));
         }
-        for word in sector_data {
-            x86::io::outw(device.port_base_io() + PORT_OFFSET_DATA, word);
+        bus_masters
+    }
+
+    unsafe fn transfer(&self, device: Device, command: u8, features: u16, sector_index: u64, sector_count: u64, write: bool) -> Result<(), StorageError> {
+        assert!(sector_count > 0 && sector_count <= MAX_SECTOR_COUNT_PER_DMA_COMMAND);
+        let direction = if write { 0 } else { BUS_MASTER_COMMAND_BIT_READ };
+        (self.prd_table.as_ptr() as *mut [u32; 2]).write_volatile([
+            self.data_buffer.physical_address() as u32,
+            PRD_BIT_END_OF_TABLE | ((sector_count * SECTOR_SIZE) as u32 & 0xFFFF), // 0 means 64 KiB
+        ]);
+        x86::io::outl(
+            self.port_base + PORT_OFFSET_BUS_MASTER_PRD_TABLE_ADDRESS,
+            self.prd_table.physical_address() as u32,
+        );
+        x86::io::outb(self.port_base + PORT_OFFSET_BUS_MASTER_COMMAND, direction);
+        x86::io::outb(
+            self.port_base + PORT_OFFSET_BUS_MASTER_STATUS,
+            BUS_MASTER_STATUS_BIT_ERROR | BUS_MASTER_STATUS_BIT_INTERRUPT,
+        );
+        if self.irq.is_some() {
+            x86::io::outb(device.port_base_control() + PORT_OFFSET_CONTROL, 0); // send an IRQ on completion
         }
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_DRIVE_HEAD, device.device_bit());
-        x86::io::outb(device.port_base_io() + PORT_OFFSET_COMMAND, COMMAND_FLUSH_CACHE);
-        loop {
-            let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
-            if status & STATUS_BIT_BSY != 0 {
+        let result = send_lba48_command(device, sector_index, sector_count, command, features).and_then(|()| {
+            x86::io::outb(self.port_base + PORT_OFFSET_BUS_MASTER_COMMAND, direction | BUS_MASTER_COMMAND_BIT_START);
+            match self.irq {
+                Some(irq) => self.wait_for_interrupt(irq),
+                None => self.wait_for_completion(device),
+            }
+        });
+        x86::io::outb(self.port_base + PORT_OFFSET_BUS_MASTER_COMMAND, direction);
+        let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS); // also acknowledges the device IRQ
+        x86::io::outb(
+            self.port_base + PORT_OFFSET_BUS_MASTER_STATUS,
+            BUS_MASTER_STATUS_BIT_ERROR | BUS_MASTER_STATUS_BIT_INTERRUPT,
+        );
+        if let Some(irq) = self.irq {
+            // PIO commands are polled, so IRQs go back to being disabled by nIEN
+            x86::io::outb(device.port_base_control() + PORT_OFFSET_CONTROL, CONTROL_BIT_NIEN);
+            irq.take_pending();
+            irq.unmask();
+        }
+        result?;
+        if status & STATUS_BIT_ERR != 0 {
+            return Err(StorageError::DeviceError);
+        }
+        Ok(())
+    }
+
+    // done once the device has raised the channel IRQ at the end of the transfer
+    unsafe fn wait_for_interrupt(&self, irq: super::interrupts::Irq) -> Result<(), StorageError> {
+        for _ in 0..super::interrupts::WAIT_LIMIT {
+            if !irq.take_pending() {
+                irq.wait();
                 continue;
             }
-            break;
+            let bus_master_status = x86::io::inb(self.port_base + PORT_OFFSET_BUS_MASTER_STATUS);
+            if bus_master_status & BUS_MASTER_STATUS_BIT_ERROR != 0 {
+                return Err(StorageError::DeviceError);
+            }
+            if bus_master_status & BUS_MASTER_STATUS_BIT_INTERRUPT != 0 {
+                return Ok(());
+            }
+            irq.unmask(); // raised by something else sharing the line
+        }
+        Err(StorageError::Timeout)
+    }
+
+    // done once the bus master has transferred the whole PRD table and the device is no longer busy
+    unsafe fn wait_for_completion(&self, device: Device) -> Result<(), StorageError> {
+        wait_for_status(device);
+        for _ in 0..STATUS_POLL_LIMIT {
+            let bus_master_status = x86::io::inb(self.port_base + PORT_OFFSET_BUS_MASTER_STATUS);
+            if bus_master_status & BUS_MASTER_STATUS_BIT_ERROR != 0 {
+                return Err(StorageError::DeviceError);
+            }
+            let status = x86::io::inb(device.port_base_io() + PORT_OFFSET_STATUS);
+            if status & STATUS_BIT_BSY == 0 {
+                if status & STATUS_BIT_ERR != 0 {
+                    return Err(StorageError::DeviceError);
+                }
+                if bus_master_status & BUS_MASTER_STATUS_BIT_ACTIVE == 0 {
+                    return Ok(());
+                }
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+
+    fn read_sectors(&self, device: Device, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        unsafe {
+            self.transfer(device, COMMAND_READ_DMA_EXT, 0, sector_index, sector_count, false)?;
+            Ok(core::slice::from_raw_parts(self.data_buffer.as_ptr(), (sector_count * SECTOR_SIZE) as usize).to_vec())
+        }
+    }
+
+    fn write_sectors(&self, device: Device, sector_index: u64, sectors_data: &[u8], fua: bool) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % SECTOR_SIZE == 0);
+        let command = if fua { COMMAND_WRITE_DMA_FUA_EXT } else { COMMAND_WRITE_DMA_EXT };
+        unsafe {
+            core::slice::from_raw_parts_mut(self.data_buffer.as_ptr(), sectors_data.len()).copy_from_slice(sectors_data);
+            self.transfer(device, command, 0, sector_index, sectors_data.len() as u64 / SECTOR_SIZE, true)
+        }
+    }
+
+    // ranges are (sector index, sector count) with at most 0xFFFF sectors each
+    fn trim(&self, device: Device, ranges: &[(u64, u64)]) -> Result<(), StorageError> {
+        assert!(ranges.len() <= TRIM_RANGE_COUNT_PER_BLOCK);
+        let mut block = [0u8; SECTOR_SIZE as usize];
+        for (entry, &(sector_index, sector_count)) in block.chunks_mut(8).zip(ranges) {
+            entry.copy_from_slice(&(sector_index | sector_count << 48).to_le_bytes());
+        }
+        unsafe {
+            core::slice::from_raw_parts_mut(self.data_buffer.as_ptr(), block.len()).copy_from_slice(&block);
+            self.transfer(device, COMMAND_DATA_SET_MANAGEMENT, FEATURE_TRIM, 0, 1, true)
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct DiskSectorStorage {
     device: Device,
-    sector_count: u64,
+    identity: Identity,
+    bus_master: Option<Rc<BusMaster>>,
 }
 
 impl DiskSectorStorage {
     pub fn new(device: Device) -> Option<Self> {
-        identify(device).map(|sector_count| DiskSectorStorage { device, sector_count })
+        identify(device).map(|identity| DiskSectorStorage {
+            device,
+            identity,
+            bus_master: None,
+        })
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    pub fn attach_bus_master(&mut self, bus_master: &Rc<BusMaster>) {
+        if bus_master.channel == self.device.channel() {
+            self.bus_master = Some(bus_master.clone());
+        }
+    }
+
+    fn write(&self, sector_index: u64, sectors_data: &[u8], fua: bool) -> Result<(), StorageError> {
+        if sector_index + sectors_data.len() as u64 / SECTOR_SIZE > self.identity.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if let Some(bus_master) = &self.bus_master {
+            for (chunk_index, chunk_data) in sectors_data.chunks((MAX_SECTOR_COUNT_PER_DMA_COMMAND * SECTOR_SIZE) as usize).enumerate() {
+                bus_master.write_sectors(
+                    self.device,
+                    sector_index + chunk_index as u64 * MAX_SECTOR_COUNT_PER_DMA_COMMAND,
+                    chunk_data,
+                    fua,
+                )?;
+            }
+            return Ok(());
+        }
+        for (chunk_index, chunk_data) in sectors_data.chunks((MAX_SECTOR_COUNT_PER_COMMAND * SECTOR_SIZE) as usize).enumerate() {
+            write_sectors(self.device, sector_index + chunk_index as u64 * MAX_SECTOR_COUNT_PER_COMMAND, chunk_data)?;
+        }
+        Ok(())
     }
 }
 
 impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        SECTOR_SIZE
+    }
+
     fn sector_count(&self) -> u64 {
-        self.sector_count
+        self.identity.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.identity.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut sectors_data = Vec::with_capacity((sector_count * SECTOR_SIZE) as usize);
+        if let Some(bus_master) = &self.bus_master {
+            for chunk_sector_index in (sector_index..sector_index + sector_count).step_by(MAX_SECTOR_COUNT_PER_DMA_COMMAND as usize) {
+                let chunk_sector_count = (sector_index + sector_count - chunk_sector_index).min(MAX_SECTOR_COUNT_PER_DMA_COMMAND);
+                sectors_data.extend(bus_master.read_sectors(self.device, chunk_sector_index, chunk_sector_count)?);
+            }
+            return Ok(sectors_data);
+        }
+        for chunk_sector_index in (sector_index..sector_index + sector_count).step_by(MAX_SECTOR_COUNT_PER_COMMAND as usize) {
+            let chunk_sector_count = (sector_index + sector_count - chunk_sector_index).min(MAX_SECTOR_COUNT_PER_COMMAND);
+            sectors_data.extend(read_sectors(self.device, chunk_sector_index, chunk_sector_count)?);
+        }
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        self.write(sector_index, sectors_data, false)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        flush_cache(self.device)
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        if self.bus_master.is_some() && self.identity.write_fua {
+            return self.write(sector_index, sectors_data, true);
+        }
+        self.write(sector_index, sectors_data, false)?;
+        self.flush()
+    }
+
+    // TRIM needs DMA, without it the discard is a no-op
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.identity.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let Some(bus_master) = self.bus_master.as_ref().filter(|_| self.identity.trim) else {
+            return Ok(());
+        };
+        let ranges: Vec<(u64, u64)> = sectors
+            .clone()
+            .step_by(MAX_SECTOR_COUNT_PER_TRIM_RANGE as usize)
+            .map(|range_sector_index| (range_sector_index, (sectors.end - range_sector_index).min(MAX_SECTOR_COUNT_PER_TRIM_RANGE)))
+            .collect();
+        for block_ranges in ranges.chunks(TRIM_RANGE_COUNT_PER_BLOCK) {
+            bus_master.trim(self.device, block_ranges)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AtapiSectorStorage {
+    device: Device,
+    block_count: u64,
+    block_size: u64,
+}
+
+impl AtapiSectorStorage {
+    pub fn new(device: Device) -> Option<Self> {
+        if !identify_packet_device(device) {
+            return None;
+        }
+        match read_capacity(device) {
+            Ok((block_count, block_size)) if block_size > 0 => Some(AtapiSectorStorage {
+                device,
+                block_count,
+                block_size,
+            }),
+            Ok((_, block_size)) => {
+                log::warn!("Unsupported ATAPI block size {}", block_size);
+                None
+            }
+            Err(error) => {
+                log::warn!("Failed to read ATAPI capacity (no medium?): {:?}", error);
+                None
+            }
+        }
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+}
+
+impl SectorStorage for AtapiSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, _sector_index: u64, _sector_data: &[u8]) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.block_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut sectors_data = Vec::with_capacity((sector_count * self.block_size) as usize);
+        for chunk_sector_index in (sector_index..sector_index + sector_count).step_by(MAX_BLOCK_COUNT_PER_PACKET_COMMAND as usize) {
+            let chunk_sector_count = (sector_index + sector_count - chunk_sector_index).min(MAX_BLOCK_COUNT_PER_PACKET_COMMAND);
+            sectors_data.extend(read_blocks(self.device, chunk_sector_index, chunk_sector_count, self.block_size)?);
+        }
+        Ok(sectors_data)
     }
 
-    fn read_sector(&self, sector_index: u64) -> [u8; SECTOR_SIZE as usize] {
-        assert!(sector_index < self.sector_count);
-        read_sector(self.device, sector_index)
+    fn write_sectors(&self, _sector_index: u64, _sectors_data: &[u8]) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
     }
 
-    fn write_sector(&self, sector_index: u64, sector_data: [u8; SECTOR_SIZE as usize]) {
-        assert!(sector_index < self.sector_count);
-        write_sector(self.device, sector_index, sector_data)
+    fn read_only(&self) -> bool {
+        true
     }
 }
@@ -0,0 +1,150 @@
+use alloc::vec::Vec;
+use core::{cell::RefCell, fmt::Debug};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+pub fn handles() -> Vec<uefi::Handle> {
+    let boot_services = unsafe { super::SYSTEM_TABLE.as_ref().unwrap() }.boot_services();
+    match boot_services.locate_handle_buffer(uefi::table::boot::SearchType::from_proto::<uefi::proto::media::block::BlockIO>()) {
+        Ok(handle_buffer) => handle_buffer.to_vec(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub struct DiskSectorStorage {
+    handle: uefi::Handle,
+    block_io: RefCell<uefi::table::boot::ScopedProtocol<'static, uefi::proto::media::block::BlockIO>>,
+    media_id: u32,
+    block_size: u64,
+    block_count: u64,
+    io_align: usize,
+    read_only: bool,
+}
+
+impl Debug for DiskSectorStorage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DiskSectorStorage")
+            .field("handle", &self.handle)
+            .field("block_size", &self.block_size)
+            .field("block_count", &self.block_count)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+impl DiskSectorStorage {
+    pub fn new(handle: uefi::Handle) -> Option<Self> {
+        let boot_services = unsafe { super::SYSTEM_TABLE.as_ref().unwrap() }.boot_services();
+        // shared access, the firmware drivers keep using the protocol
+        let block_io = unsafe {
+            boot_services.open_protocol::<uefi::proto::media::block::BlockIO>(
+                uefi::table::boot::OpenProtocolParams {
+                    handle,
+                    agent: super::IMAGE_HANDLE.unwrap(),
+                    controller: None,
+                },
+                uefi::table::boot::OpenProtocolAttributes::GetProtocol,
+            )
+        }
+        .ok()?;
+        let media = block_io.media();
+        if media.is_logical_partition() || !media.is_media_present() {
+            return None;
+        }
+        let block_size = media.block_size() as u64;
+        if block_size == 0 {
+            log::warn!("Unsupported UEFI Block I/O block size {}", block_size);
+            return None;
+        }
+        Some(DiskSectorStorage {
+            handle,
+            media_id: media.media_id(),
+            block_size,
+            block_count: media.last_block() + 1,
+            io_align: (media.io_align() as usize).max(1),
+            read_only: media.is_read_only(),
+            block_io: RefCell::new(block_io),
+        })
+    }
+
+    pub fn handle(&self) -> uefi::Handle {
+        self.handle
+    }
+
+    fn read_blocks(&self, block_index: u64, block_count: u64) -> Result<Vec<u8>, StorageError> {
+        let buffer = super::dma::Buffer::new((block_count * self.block_size) as usize, self.io_align);
+        let buffer_data = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr(), buffer.size()) };
+        self.block_io
+            .borrow()
+            .read_blocks(self.media_id, block_index, buffer_data)
+            .map_err(|_| StorageError::DeviceError)?;
+        Ok(buffer_data.to_vec())
+    }
+
+    fn write_blocks(&self, block_index: u64, blocks_data: &[u8]) -> Result<(), StorageError> {
+        let buffer = super::dma::Buffer::new(blocks_data.len(), self.io_align);
+        let buffer_data = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr(), buffer.size()) };
+        buffer_data.copy_from_slice(blocks_data);
+        self.block_io
+            .borrow_mut()
+            .write_blocks(self.media_id, block_index, buffer_data)
+            .map_err(|error| {
+                if error.status() == uefi::Status::WRITE_PROTECTED {
+                    StorageError::MediaReadOnly
+                } else {
+                    StorageError::DeviceError
+                }
+            })
+    }
+}
+
+impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.block_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if sector_count == 0 {
+            return Ok(Vec::new());
+        }
+        self.read_blocks(sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.block_size == 0);
+        let sector_count = sectors_data.len() as u64 / self.block_size;
+        if sector_index + sector_count > self.block_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only {
+            return Err(StorageError::MediaReadOnly);
+        }
+        if sector_count == 0 {
+            return Ok(());
+        }
+        self.write_blocks(sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.block_io.borrow_mut().flush_blocks().map_err(|_| StorageError::DeviceError)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -1,16 +1,323 @@
-use core::{cell::RefCell, fmt::Debug};
-use virtio_drivers::transport::Transport;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    cell::RefCell,
+    fmt::Debug,
+    ops::Range,
+    sync::atomic::{fence, Ordering},
+};
+use virtio_drivers::transport::{DeviceStatus, Transport};
 
-use super::{sector_storage::SECTOR_SIZE, SectorStorage};
+use super::{sector_storage::StorageError, SectorStorage};
+
+const VIRTIO_SECTOR_SIZE: u64 = 512;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+const VIRTIO_BLK_CONFIG_OFFSET_CAPACITY: usize = 0;
+const VIRTIO_BLK_CONFIG_OFFSET_BLK_SIZE: usize = 20;
+const VIRTIO_BLK_CONFIG_OFFSET_MAX_DISCARD_SECTORS: usize = 36;
+const VIRTIO_BLK_CONFIG_OFFSET_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const REQUEST_HEADER_SIZE: usize = 16;
+const SEGMENT_SIZE: usize = 16; // for discard and write zeroes
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_DESC_SIZE: usize = 16;
+const VIRTQ_USED_ELEM_SIZE: usize = 8;
+
+const QUEUE_INDEX: u16 = 0;
+const MAX_QUEUE_SIZE: u32 = 128;
+const DESCRIPTORS_PER_REQUEST: usize = 3; // header, data, status (flushes have no data)
+const PAGE_SIZE: usize = 4096;
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+const POLL_LIMIT: u64 = 10_000_000;
+
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+const PCI_CONFIG_OFFSET_INTERRUPT_LINE: u32 = 0x3C;
+const PCI_INTERRUPT_LINE_NONE: u8 = 0xFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompletionMode {
+    Interrupt, // the used ring is drained once the device raised its INTx line
+    Polling,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct RequestId(u64);
+
+struct InFlightRequest {
+    id: RequestId,
+    header: super::dma::Buffer, // request header followed by the status byte
+    data: Option<super::dma::Buffer>,
+    data_len: usize,
+    read: bool,
+}
+
+struct Queue {
+    transport: virtio_drivers::transport::pci::PciTransport,
+    irq: Option<super::interrupts::Irq>,
+    completion_mode: CompletionMode,
+    size: u16,
+    ring: super::dma::Buffer,
+    used_offset: usize,
+    free_descriptors: Vec<u16>,
+    avail_index: u16,
+    used_index: u16,
+    next_request_id: u64,
+    in_flight_requests: BTreeMap<u16, InFlightRequest>, // by head descriptor
+    completed_requests: BTreeMap<RequestId, Result<Vec<u8>, StorageError>>,
+}
+
+impl Queue {
+    fn new(mut transport: virtio_drivers::transport::pci::PciTransport, irq: Option<super::interrupts::Irq>) -> Option<Self> {
+        if transport.queue_used(QUEUE_INDEX) {
+            return None;
+        }
+        let max_queue_size = MAX_QUEUE_SIZE.min(transport.max_queue_size());
+        if (max_queue_size as usize) < DESCRIPTORS_PER_REQUEST {
+            return None;
+        }
+        // the legacy layout needs a power of two size, with the descriptor table and available ring followed by the page aligned used ring
+        let size = 1 << (31 - max_queue_size.leading_zeros());
+        let avail_offset = size as usize * VIRTQ_DESC_SIZE;
+        let used_offset = (avail_offset + 6 + 2 * size as usize).next_multiple_of(PAGE_SIZE);
+        let ring = super::dma::Buffer::new(used_offset + (6 + VIRTQ_USED_ELEM_SIZE * size as usize).next_multiple_of(PAGE_SIZE), PAGE_SIZE);
+        transport.queue_set(
+            QUEUE_INDEX,
+            size as u32,
+            ring.physical_address() as usize,
+            ring.physical_address() as usize + avail_offset,
+            ring.physical_address() as usize + used_offset,
+        );
+        let mut queue = Queue {
+            transport,
+            irq,
+            completion_mode: CompletionMode::Polling,
+            size,
+            ring,
+            used_offset,
+            free_descriptors: (0..size).rev().collect(),
+            avail_index: 0,
+            used_index: 0,
+            next_request_id: 0,
+            in_flight_requests: BTreeMap::new(),
+            completed_requests: BTreeMap::new(),
+        };
+        queue.set_completion_mode(if irq.is_some() { CompletionMode::Interrupt } else { CompletionMode::Polling });
+        Some(queue)
+    }
+
+    // interrupts need the INTx line to be routed
+    fn set_completion_mode(&mut self, completion_mode: CompletionMode) -> bool {
+        if completion_mode == CompletionMode::Interrupt && self.irq.is_none() {
+            return false;
+        }
+        self.completion_mode = completion_mode;
+        let flags = match completion_mode {
+            CompletionMode::Interrupt => 0,
+            CompletionMode::Polling => VIRTQ_AVAIL_F_NO_INTERRUPT,
+        };
+        unsafe { self.avail_ring().write_volatile(flags) };
+        fence(Ordering::SeqCst);
+        self.process_used_ring(); // requests used while no interrupt was asked for
+        true
+    }
+
+    fn avail_ring(&self) -> *mut u16 {
+        unsafe { self.ring.as_ptr().add(self.size as usize * VIRTQ_DESC_SIZE) as *mut u16 }
+    }
+
+    fn used_ring(&self) -> *mut u8 {
+        unsafe { self.ring.as_ptr().add(self.used_offset) }
+    }
+
+    unsafe fn write_descriptor(&self, index: u16, address: u64, len: u32, flags: u16, next: u16) {
+        let descriptor = self.ring.as_ptr().add(index as usize * VIRTQ_DESC_SIZE);
+        (descriptor as *mut u64).write_volatile(address);
+        (descriptor.add(8) as *mut u32).write_volatile(len);
+        (descriptor.add(12) as *mut u16).write_volatile(flags);
+        (descriptor.add(14) as *mut u16).write_volatile(next);
+    }
+
+    fn submit(&mut self, request_type: u32, sector: u64, data: Option<super::dma::Buffer>, data_len: usize) -> Result<RequestId, StorageError> {
+        self.poll_until(|queue| queue.free_descriptors.len() >= DESCRIPTORS_PER_REQUEST)?;
+        let read = request_type == VIRTIO_BLK_T_IN;
+        let header = super::dma::Buffer::new(REQUEST_HEADER_SIZE + 1, 16);
+        let descriptor_count = if data.is_some() {
+            DESCRIPTORS_PER_REQUEST
+        } else {
+            DESCRIPTORS_PER_REQUEST - 1
+        };
+        let descriptors: Vec<u16> = (0..descriptor_count).map(|_| self.free_descriptors.pop().unwrap()).collect();
+        unsafe {
+            (header.as_ptr() as *mut u32).write_volatile(request_type);
+            (header.as_ptr().add(8) as *mut u64).write_volatile(sector);
+            header.as_ptr().add(REQUEST_HEADER_SIZE).write_volatile(0xFF);
+            self.write_descriptor(
+                descriptors[0],
+                header.physical_address(),
+                REQUEST_HEADER_SIZE as u32,
+                VIRTQ_DESC_F_NEXT,
+                descriptors[1],
+            );
+            if let Some(data) = &data {
+                let data_flags = if read { VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE } else { VIRTQ_DESC_F_NEXT };
+                self.write_descriptor(descriptors[1], data.physical_address(), data_len as u32, data_flags, descriptors[2]);
+            }
+            self.write_descriptor(
+                descriptors[descriptor_count - 1],
+                header.physical_address() + REQUEST_HEADER_SIZE as u64,
+                1,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            self.avail_ring()
+                .add(2 + (self.avail_index % self.size) as usize)
+                .write_volatile(descriptors[0]);
+            fence(Ordering::SeqCst);
+            self.avail_index = self.avail_index.wrapping_add(1);
+            self.avail_ring().add(1).write_volatile(self.avail_index);
+            fence(Ordering::SeqCst);
+        }
+        let id = RequestId(self.next_request_id);
+        self.next_request_id += 1;
+        self.in_flight_requests.insert(
+            descriptors[0],
+            InFlightRequest {
+                id,
+                header,
+                data,
+                data_len,
+                read,
+            },
+        );
+        self.transport.notify(QUEUE_INDEX);
+        Ok(id)
+    }
+
+    // moves the requests the device is done with to the completed requests
+    fn process_used_ring(&mut self) {
+        loop {
+            let device_used_index = unsafe { (self.used_ring().add(2) as *const u16).read_volatile() };
+            if device_used_index == self.used_index {
+                break;
+            }
+            fence(Ordering::SeqCst);
+            let head =
+                unsafe { (self.used_ring().add(4 + (self.used_index % self.size) as usize * VIRTQ_USED_ELEM_SIZE) as *const u32).read_volatile() as u16 };
+            self.used_index = self.used_index.wrapping_add(1);
+            let Some(request) = self.in_flight_requests.remove(&head) else {
+                log::warn!("virtio-blk completed unknown descriptor {}", head);
+                continue;
+            };
+            let mut descriptor = head;
+            loop {
+                self.free_descriptors.push(descriptor);
+                let descriptor_pointer = unsafe { self.ring.as_ptr().add(descriptor as usize * VIRTQ_DESC_SIZE) };
+                if unsafe { (descriptor_pointer.add(12) as *const u16).read_volatile() } & VIRTQ_DESC_F_NEXT == 0 {
+                    break;
+                }
+                descriptor = unsafe { (descriptor_pointer.add(14) as *const u16).read_volatile() };
+            }
+            let status = unsafe { request.header.as_ptr().add(REQUEST_HEADER_SIZE).read_volatile() };
+            let result = match (status, &request.data) {
+                (VIRTIO_BLK_S_OK, Some(data)) if request.read => Ok(unsafe { core::slice::from_raw_parts(data.as_ptr(), request.data_len) }.to_vec()),
+                (VIRTIO_BLK_S_OK, _) => Ok(Vec::new()),
+                _ => Err(StorageError::DeviceError),
+            };
+            self.completed_requests.insert(request.id, result);
+        }
+    }
+
+    fn poll(&mut self) {
+        match (self.completion_mode, self.irq) {
+            (CompletionMode::Interrupt, Some(irq)) => {
+                if !irq.take_pending() {
+                    return;
+                }
+                // reading the ISR status lowers the line before it is unmasked, used requests after it raise it again
+                self.transport.ack_interrupt();
+                irq.unmask();
+                self.process_used_ring();
+            }
+            _ => self.process_used_ring(),
+        }
+    }
+
+    // halts between interrupts, or spins when polling
+    fn poll_until(&mut self, done: impl Fn(&Self) -> bool) -> Result<(), StorageError> {
+        let limit = match self.completion_mode {
+            CompletionMode::Interrupt => super::interrupts::WAIT_LIMIT,
+            CompletionMode::Polling => POLL_LIMIT,
+        };
+        for _ in 0..limit {
+            self.poll();
+            if done(self) {
+                return Ok(());
+            }
+            if let (CompletionMode::Interrupt, Some(irq)) = (self.completion_mode, self.irq) {
+                irq.wait();
+            }
+        }
+        Err(StorageError::Timeout)
+    }
+
+    fn wait(&mut self, request_id: RequestId) -> Result<Vec<u8>, StorageError> {
+        self.poll_until(|queue| queue.completed_requests.contains_key(&request_id))?;
+        self.completed_requests.remove(&request_id).unwrap()
+    }
+}
+
+// the firmware routes INTx to a legacy IRQ and records it in the interrupt line register
+fn pci_interrupt_line(device_function: virtio_drivers::transport::pci::bus::DeviceFunction) -> Option<u8> {
+    let address = 1 << 31
+        | (device_function.bus as u32) << 16
+        | (device_function.device as u32) << 11
+        | (device_function.function as u32) << 8
+        | PCI_CONFIG_OFFSET_INTERRUPT_LINE;
+    let interrupt_line = unsafe {
+        x86::io::outl(PCI_CONFIG_ADDRESS_PORT, address);
+        x86::io::inl(PCI_CONFIG_DATA_PORT) as u8
+    };
+    (interrupt_line != PCI_INTERRUPT_LINE_NONE).then_some(interrupt_line)
+}
 
 pub struct DiskSectorStorage {
     device_function: virtio_drivers::transport::pci::bus::DeviceFunction,
-    device: RefCell<virtio_drivers::device::blk::VirtIOBlk<super::virtio::Hal, virtio_drivers::transport::pci::PciTransport>>,
+    sector_size: u64,
+    sector_count: u64,
+    features: u64,
+    max_discard_sector_count: u64,
+    max_write_zeroes_sector_count: u64,
+    queue: RefCell<Queue>,
 }
 
 impl Debug for DiskSectorStorage {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("DiskSectorStorage").field("device_function", &self.device_function).finish()
+        let queue = self.queue.borrow();
+        f.debug_struct("DiskSectorStorage")
+            .field("device_function", &self.device_function)
+            .field("sector_size", &self.sector_size)
+            .field("sector_count", &self.sector_count)
+            .field("features", &self.features)
+            .field("queue_size", &queue.size)
+            .field("irq", &queue.irq)
+            .field("completion_mode", &queue.completion_mode)
+            .finish()
     }
 }
 
@@ -29,32 +336,259 @@ impl DiskSectorStorage {
                     | virtio_drivers::transport::pci::bus::Command::BUS_MASTER,
             );
             let mut transport = virtio_drivers::transport::pci::PciTransport::new::<super::virtio::Hal>(pci_root, device_function).ok()?;
-            transport.set_status(virtio_drivers::transport::DeviceStatus::empty());
-            let device = virtio_drivers::device::blk::VirtIOBlk::<super::virtio::Hal, _>::new(transport).ok()?;
+            transport.set_status(DeviceStatus::empty());
+            transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+            let features = transport.read_device_features()
+                & (VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES);
+            transport.write_driver_features(features);
+            transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK);
+            if !transport.get_status().contains(DeviceStatus::FEATURES_OK) {
+                log::warn!("virtio-blk device rejected the features {:#x}", features);
+                transport.set_status(DeviceStatus::FAILED);
+                return None;
+            }
+            transport.set_guest_page_size(PAGE_SIZE as u32);
+            let config_space = transport.config_space::<u8>().ok()?;
+            let capacity = unsafe { (config_space.as_ptr().add(VIRTIO_BLK_CONFIG_OFFSET_CAPACITY) as *const u64).read_volatile() }; // in 512-byte units
+            let sector_size = if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+                unsafe { (config_space.as_ptr().add(VIRTIO_BLK_CONFIG_OFFSET_BLK_SIZE) as *const u32).read_volatile() as u64 }
+            } else {
+                VIRTIO_SECTOR_SIZE
+            };
+            if sector_size < VIRTIO_SECTOR_SIZE || sector_size % VIRTIO_SECTOR_SIZE != 0 {
+                log::warn!("Unsupported virtio-blk block size {}", sector_size);
+                transport.set_status(DeviceStatus::FAILED);
+                return None;
+            }
+            // the limits are in 512-byte units
+            let max_discard_sector_count = match features & VIRTIO_BLK_F_DISCARD {
+                0 => 0,
+                _ => unsafe { (config_space.as_ptr().add(VIRTIO_BLK_CONFIG_OFFSET_MAX_DISCARD_SECTORS) as *const u32).read_volatile() as u64 },
+            } / (sector_size / VIRTIO_SECTOR_SIZE);
+            let max_write_zeroes_sector_count = match features & VIRTIO_BLK_F_WRITE_ZEROES {
+                0 => 0,
+                _ => unsafe { (config_space.as_ptr().add(VIRTIO_BLK_CONFIG_OFFSET_MAX_WRITE_ZEROES_SECTORS) as *const u32).read_volatile() as u64 },
+            } / (sector_size / VIRTIO_SECTOR_SIZE);
+            let irq = pci_interrupt_line(device_function).and_then(|irq| super::interrupts::Irq::new(irq, true)); // PCI INTx is level-triggered
+            let mut queue = Queue::new(transport, irq)?;
+            queue
+                .transport
+                .set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK | DeviceStatus::DRIVER_OK);
             Some(DiskSectorStorage {
                 device_function,
-                device: RefCell::new(device),
+                sector_size,
+                sector_count: capacity / (sector_size / VIRTIO_SECTOR_SIZE),
+                features,
+                max_discard_sector_count,
+                max_write_zeroes_sector_count,
+                queue: RefCell::new(queue),
             })
         } else {
             None
         }
     }
+
+    pub fn device_function(&self) -> virtio_drivers::transport::pci::bus::DeviceFunction {
+        self.device_function
+    }
+
+    pub fn completion_mode(&self) -> CompletionMode {
+        self.queue.borrow().completion_mode
+    }
+
+    // returns false when interrupts are asked for without a usable IRQ
+    pub fn set_completion_mode(&self, completion_mode: CompletionMode) -> bool {
+        self.queue.borrow_mut().set_completion_mode(completion_mode)
+    }
+
+    // requests stay queued on the device until they are waited for
+    pub fn submit_read(&self, sector_index: u64, sector_count: u64) -> Result<RequestId, StorageError> {
+        if sector_count == 0 || sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let len = (sector_count * self.sector_size) as usize;
+        self.queue.borrow_mut().submit(
+            VIRTIO_BLK_T_IN,
+            sector_index * (self.sector_size / VIRTIO_SECTOR_SIZE),
+            Some(super::dma::Buffer::new(len, PAGE_SIZE)),
+            len,
+        )
+    }
+
+    pub fn submit_write(&self, sector_index: u64, sectors_data: &[u8]) -> Result<RequestId, StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sectors_data.is_empty() || sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        let data = super::dma::Buffer::new(sectors_data.len(), PAGE_SIZE);
+        unsafe { core::ptr::copy_nonoverlapping(sectors_data.as_ptr(), data.as_ptr(), sectors_data.len()) };
+        self.queue.borrow_mut().submit(
+            VIRTIO_BLK_T_OUT,
+            sector_index * (self.sector_size / VIRTIO_SECTOR_SIZE),
+            Some(data),
+            sectors_data.len(),
+        )
+    }
+
+    // completes after all the writes completed before it are durable
+    pub fn submit_flush(&self) -> Result<RequestId, StorageError> {
+        self.queue.borrow_mut().submit(VIRTIO_BLK_T_FLUSH, 0, None, 0)
+    }
+
+    fn submit_segment(&self, request_type: u32, sector_index: u64, sector_count: u64) -> Result<RequestId, StorageError> {
+        let segment = super::dma::Buffer::new(SEGMENT_SIZE, 16);
+        unsafe {
+            (segment.as_ptr() as *mut u64).write_volatile(sector_index * (self.sector_size / VIRTIO_SECTOR_SIZE));
+            (segment.as_ptr().add(8) as *mut u32).write_volatile((sector_count * (self.sector_size / VIRTIO_SECTOR_SIZE)) as u32);
+            (segment.as_ptr().add(12) as *mut u32).write_volatile(0); // flags
+        }
+        self.queue.borrow_mut().submit(request_type, 0, Some(segment), SEGMENT_SIZE)
+    }
+
+    // a single segment per request, limited to the maximum sector count of the device
+    fn segment_requests(&self, request_type: u32, sectors: Range<u64>, max_sector_count: u64) -> Result<(), StorageError> {
+        let mut request_ids = Vec::new();
+        for request_sector_index in sectors.clone().step_by(max_sector_count as usize) {
+            match self.submit_segment(request_type, request_sector_index, max_sector_count.min(sectors.end - request_sector_index)) {
+                Ok(request_id) => request_ids.push(request_id),
+                Err(error) => {
+                    let _ = self.wait_all(request_ids);
+                    return Err(error);
+                }
+            }
+        }
+        self.wait_all(request_ids).map(|_| ())
+    }
+
+    pub fn is_complete(&self, request_id: RequestId) -> bool {
+        let mut queue = self.queue.borrow_mut();
+        queue.poll();
+        queue.completed_requests.contains_key(&request_id)
+    }
+
+    // returns the read data, or nothing for writes
+    pub fn wait(&self, request_id: RequestId) -> Result<Vec<u8>, StorageError> {
+        self.queue.borrow_mut().wait(request_id)
+    }
+
+    fn wait_all(&self, request_ids: Vec<RequestId>) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::new();
+        let mut result = Ok(());
+        for request_id in request_ids {
+            // every request has to be waited for even after an error so that none is left behind
+            match self.wait(request_id) {
+                Ok(request_data) => data.extend_from_slice(&request_data),
+                Err(error) => result = result.and(Err(error)),
+            }
+        }
+        result.map(|_| data)
+    }
 }
 
 impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
     fn sector_count(&self) -> u64 {
-        self.device.borrow().capacity()
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        // all chunks are in flight at the same time
+        let sectors_per_request = MAX_REQUEST_SIZE / self.sector_size;
+        let mut request_ids = Vec::new();
+        for request_sector_index in (sector_index..sector_index + sector_count).step_by(sectors_per_request as usize) {
+            match self.submit_read(
+                request_sector_index,
+                sectors_per_request.min(sector_index + sector_count - request_sector_index),
+            ) {
+                Ok(request_id) => request_ids.push(request_id),
+                Err(error) => {
+                    let _ = self.wait_all(request_ids);
+                    return Err(error);
+                }
+            }
+        }
+        self.wait_all(request_ids)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let sectors_per_request = MAX_REQUEST_SIZE / self.sector_size;
+        let mut request_ids = Vec::new();
+        for (index, request_data) in sectors_data.chunks((sectors_per_request * self.sector_size) as usize).enumerate() {
+            match self.submit_write(sector_index + index as u64 * sectors_per_request, request_data) {
+                Ok(request_id) => request_ids.push(request_id),
+                Err(error) => {
+                    let _ = self.wait_all(request_ids);
+                    return Err(error);
+                }
+            }
+        }
+        self.wait_all(request_ids).map(|_| ())
     }
 
-    fn read_sector(&self, sector_index: u64) -> [u8; SECTOR_SIZE as usize] {
-        assert!(sector_index < self.device.borrow().capacity());
-        let mut sector_data = [0; SECTOR_SIZE as usize];
-        self.device.borrow_mut().read_block(sector_index as usize, &mut sector_data).unwrap();
-        sector_data
+    // without the flush feature the device has no volatile write cache
+    fn flush(&self) -> Result<(), StorageError> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        let request_id = self.submit_flush()?;
+        self.wait(request_id).map(|_| ())
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        if self.max_discard_sector_count == 0 {
+            return Ok(());
+        }
+        self.segment_requests(VIRTIO_BLK_T_DISCARD, sectors, self.max_discard_sector_count)
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        if self.max_write_zeroes_sector_count > 0 {
+            return self.segment_requests(VIRTIO_BLK_T_WRITE_ZEROES, sectors, self.max_write_zeroes_sector_count);
+        }
+        let sectors_per_request = MAX_REQUEST_SIZE / self.sector_size;
+        let zeroes = alloc::vec![0; MAX_REQUEST_SIZE as usize];
+        for request_sector_index in sectors.clone().step_by(sectors_per_request as usize) {
+            let request_sector_count = sectors_per_request.min(sectors.end - request_sector_index);
+            self.write_sectors(request_sector_index, &zeroes[..(request_sector_count * self.sector_size) as usize])?;
+        }
+        Ok(())
     }
 
-    fn write_sector(&self, sector_index: u64, sector_data: [u8; SECTOR_SIZE as usize]) {
-        assert!(sector_index < self.device.borrow().capacity());
-        self.device.borrow_mut().write_block(sector_index as usize, &sector_data).unwrap();
+    // the device rejects writes with VIRTIO_BLK_S_IOERR, so they are refused before reaching it
+    fn read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
     }
 }
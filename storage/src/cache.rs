@@ -0,0 +1,235 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{cell::RefCell, fmt::Debug, ops::Range};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+pub const DEFAULT_CAPACITY: usize = 4096; // in sectors
+
+struct CacheEntry {
+    sector_data: Vec<u8>,
+    dirty: bool,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: BTreeMap<u64, CacheEntry>, // sector index -> entry
+    lru: BTreeMap<u64, u64>,            // last use -> sector index
+    clock: u64,
+}
+
+impl Cache {
+    fn touch(&mut self, sector_index: u64) {
+        let entry = self.entries.get_mut(&sector_index).unwrap();
+        self.lru.remove(&entry.last_use);
+        self.clock += 1;
+        entry.last_use = self.clock;
+        self.lru.insert(self.clock, sector_index);
+    }
+
+    fn insert(&mut self, sector_index: u64, sector_data: Vec<u8>, dirty: bool) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.insert(
+            sector_index,
+            CacheEntry {
+                sector_data,
+                dirty,
+                last_use: self.clock,
+            },
+        ) {
+            self.lru.remove(&entry.last_use);
+        }
+        self.lru.insert(self.clock, sector_index);
+    }
+
+    fn remove(&mut self, sectors: Range<u64>) {
+        let sector_indices: Vec<u64> = self.entries.range(sectors).map(|(&sector_index, _)| sector_index).collect();
+        for sector_index in sector_indices {
+            let entry = self.entries.remove(&sector_index).unwrap();
+            self.lru.remove(&entry.last_use);
+        }
+    }
+
+    fn pop_least_recently_used(&mut self) -> Option<(u64, CacheEntry)> {
+        let (_, sector_index) = self.lru.pop_first()?;
+        let entry = self.entries.remove(&sector_index).unwrap();
+        Some((sector_index, entry))
+    }
+}
+
+pub struct CachedSectorStorage<SS: SectorStorage> {
+    sector_storage: SS,
+    capacity: usize,
+    cache: RefCell<Cache>,
+}
+
+impl<SS: SectorStorage + Debug> Debug for CachedSectorStorage<SS> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let cache = self.cache.borrow();
+        f.debug_struct("CachedSectorStorage")
+            .field("sector_storage", &self.sector_storage)
+            .field("capacity", &self.capacity)
+            .field("len", &cache.entries.len())
+            .field("dirty_len", &cache.entries.values().filter(|entry| entry.dirty).count())
+            .finish()
+    }
+}
+
+impl<SS: SectorStorage> CachedSectorStorage<SS> {
+    pub fn new(sector_storage: SS, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        CachedSectorStorage {
+            sector_storage,
+            capacity,
+            cache: RefCell::new(Cache::default()),
+        }
+    }
+
+    fn make_room(&self, cache: &mut Cache) -> Result<(), StorageError> {
+        while cache.entries.len() >= self.capacity {
+            let (sector_index, entry) = cache.pop_least_recently_used().unwrap();
+            if entry.dirty {
+                if let Err(error) = self.sector_storage.write_sector(sector_index, &entry.sector_data) {
+                    cache.insert(sector_index, entry.sector_data, true);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // writes the dirty sectors to the underlying storage
+    pub fn write_back(&self) -> Result<(), StorageError> {
+        let mut cache = self.cache.borrow_mut();
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (&sector_index, entry) in cache.entries.iter().filter(|(_, entry)| entry.dirty) {
+            match runs.last_mut() {
+                Some((run_start, run_data)) if *run_start + run_data.len() as u64 / self.sector_size() == sector_index => {
+                    run_data.extend_from_slice(&entry.sector_data);
+                }
+                _ => runs.push((sector_index, entry.sector_data.to_vec())),
+            }
+        }
+        for (run_start, run_data) in runs {
+            self.sector_storage.write_sectors(run_start, &run_data)?;
+            for sector_index in run_start..run_start + run_data.len() as u64 / self.sector_size() {
+                cache.entries.get_mut(&sector_index).unwrap().dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn invalidate(&self) -> Result<(), StorageError> {
+        self.write_back()?;
+        *self.cache.borrow_mut() = Cache::default();
+        Ok(())
+    }
+}
+
+impl<SS: SectorStorage> Drop for CachedSectorStorage<SS> {
+    fn drop(&mut self) {
+        if let Err(error) = SectorStorage::flush(self) {
+            log::error!("Failed to flush sector cache: {:?}", error);
+        }
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for CachedSectorStorage<SS> {
+    fn sector_size(&self) -> u64 {
+        self.sector_storage.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_storage.sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        let mut cache = self.cache.borrow_mut();
+        if cache.entries.contains_key(&sector_index) {
+            cache.touch(sector_index);
+            return Ok(cache.entries[&sector_index].sector_data.clone());
+        }
+        let sector_data = self.sector_storage.read_sector(sector_index)?;
+        self.make_room(&mut cache)?;
+        cache.insert(sector_index, sector_data.clone(), false);
+        Ok(sector_data)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sector_data.len() as u64 == self.sector_size());
+        if sector_index >= self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        let mut cache = self.cache.borrow_mut();
+        if !cache.entries.contains_key(&sector_index) {
+            self.make_room(&mut cache)?;
+        }
+        cache.insert(sector_index, sector_data.to_vec(), true);
+        Ok(())
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        let mut cache = self.cache.borrow_mut();
+        let mut sectors_data = Vec::with_capacity((sector_count * self.sector_size()) as usize);
+        let end_sector_index = sector_index + sector_count;
+        let mut current_sector_index = sector_index;
+        while current_sector_index < end_sector_index {
+            if cache.entries.contains_key(&current_sector_index) {
+                cache.touch(current_sector_index);
+                sectors_data.extend_from_slice(&cache.entries[&current_sector_index].sector_data);
+                current_sector_index += 1;
+            } else {
+                let missing_end_sector_index = (current_sector_index..end_sector_index)
+                    .find(|sector_index| cache.entries.contains_key(sector_index))
+                    .unwrap_or(end_sector_index);
+                let missing_sectors_data = self
+                    .sector_storage
+                    .read_sectors(current_sector_index, missing_end_sector_index - current_sector_index)?;
+                for (index, sector_data) in missing_sectors_data.chunks(self.sector_size() as usize).enumerate() {
+                    self.make_room(&mut cache)?;
+                    cache.insert(current_sector_index + index as u64, sector_data.to_vec(), false);
+                }
+                sectors_data.extend_from_slice(&missing_sectors_data);
+                current_sector_index = missing_end_sector_index;
+            }
+        }
+        Ok(sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.write_back()?;
+        self.sector_storage.flush()
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size() == 0);
+        self.sector_storage.write_sectors_fua(sector_index, sectors_data)?;
+        let mut cache = self.cache.borrow_mut();
+        for (index, sector_data) in sectors_data.chunks(self.sector_size() as usize).enumerate() {
+            if let Some(entry) = cache.entries.get_mut(&(sector_index + index as u64)) {
+                entry.sector_data.copy_from_slice(sector_data);
+                entry.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        self.sector_storage.discard(sectors.clone())?;
+        self.cache.borrow_mut().remove(sectors);
+        Ok(())
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        self.sector_storage.write_zeroes(sectors.clone())?;
+        self.cache.borrow_mut().remove(sectors);
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.sector_storage.read_only()
+    }
+}
@@ -0,0 +1,193 @@
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use alloc::vec::Vec;
+use core::fmt::Debug;
+
+use super::{
+    luks2,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const BLOCK_SIZE: usize = 16;
+
+#[allow(clippy::large_enum_variant)]
+enum Aes {
+    Aes128(aes::Aes128),
+    Aes256(aes::Aes256),
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> Option<Self> {
+        match key.len() {
+            16 => Some(Aes::Aes128(aes::Aes128::new(GenericArray::from_slice(key)))),
+            32 => Some(Aes::Aes256(aes::Aes256::new(GenericArray::from_slice(key)))),
+            _ => None,
+        }
+    }
+
+    fn encrypt_block(&self, block: &mut [u8]) {
+        match self {
+            Aes::Aes128(aes) => aes.encrypt_block(GenericArray::from_mut_slice(block)),
+            Aes::Aes256(aes) => aes.encrypt_block(GenericArray::from_mut_slice(block)),
+        }
+    }
+
+    fn decrypt_block(&self, block: &mut [u8]) {
+        match self {
+            Aes::Aes128(aes) => aes.decrypt_block(GenericArray::from_mut_slice(block)),
+            Aes::Aes256(aes) => aes.decrypt_block(GenericArray::from_mut_slice(block)),
+        }
+    }
+}
+
+pub struct Xts {
+    data_aes: Aes,
+    tweak_aes: Aes,
+}
+
+impl Xts {
+    // the key is the data key followed by the tweak key
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() % 2 != 0 {
+            return None;
+        }
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        Some(Xts {
+            data_aes: Aes::new(data_key)?,
+            tweak_aes: Aes::new(tweak_key)?,
+        })
+    }
+
+    fn process_sector(&self, sector_number: u64, sector_data: &mut [u8], encrypt: bool) {
+        assert!(sector_data.len() % BLOCK_SIZE == 0);
+        let mut tweak = [0; BLOCK_SIZE]; // plain64 IV
+        tweak[..8].copy_from_slice(&sector_number.to_le_bytes());
+        self.tweak_aes.encrypt_block(&mut tweak);
+        for block in sector_data.chunks_mut(BLOCK_SIZE) {
+            block.iter_mut().zip(tweak).for_each(|(byte, tweak_byte)| *byte ^= tweak_byte);
+            if encrypt {
+                self.data_aes.encrypt_block(block);
+            } else {
+                self.data_aes.decrypt_block(block);
+            }
+            block.iter_mut().zip(tweak).for_each(|(byte, tweak_byte)| *byte ^= tweak_byte);
+            // multiplication by x in GF(2^128)
+            let carry = tweak[BLOCK_SIZE - 1] >> 7;
+            for index in (1..BLOCK_SIZE).rev() {
+                tweak[index] = tweak[index] << 1 | tweak[index - 1] >> 7;
+            }
+            tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+        }
+    }
+
+    pub fn encrypt_sector(&self, sector_number: u64, sector_data: &mut [u8]) {
+        self.process_sector(sector_number, sector_data, true);
+    }
+
+    pub fn decrypt_sector(&self, sector_number: u64, sector_data: &mut [u8]) {
+        self.process_sector(sector_number, sector_data, false);
+    }
+}
+
+pub struct CryptSectorStorage<SS: SectorStorage> {
+    sector_storage: SS,
+    xts: Xts,
+    start: u64, // in bytes
+    sector_size: u64,
+    sector_count: u64,
+    iv_offset: u64, // in sectors
+}
+
+impl<SS: SectorStorage + Debug> Debug for CryptSectorStorage<SS> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CryptSectorStorage")
+            .field("sector_storage", &self.sector_storage)
+            .field("start", &self.start)
+            .field("sector_size", &self.sector_size)
+            .field("sector_count", &self.sector_count)
+            .finish()
+    }
+}
+
+impl<SS: SectorStorage> CryptSectorStorage<SS> {
+    pub fn new(sector_storage: SS, key: &[u8], start: u64, sector_size: u64, sector_count: u64, iv_offset: u64) -> Option<Self> {
+        if sector_size % sector_storage.sector_size() != 0 || start + sector_count * sector_size > sector_storage.len() {
+            return None;
+        }
+        Some(CryptSectorStorage {
+            xts: Xts::new(key)?,
+            sector_storage,
+            start,
+            sector_size,
+            sector_count,
+            iv_offset,
+        })
+    }
+
+    pub fn from_luks2_segment(sector_storage: SS, key: &[u8], segment: &luks2::Segment) -> Option<Self> {
+        if segment.encryption != "aes-xts-plain64" {
+            log::warn!("Unsupported LUKS2 segment encryption {}", segment.encryption);
+            return None;
+        }
+        let sector_count = match segment.size {
+            Some(size) => size / segment.sector_size,
+            None => sector_storage.len().checked_sub(segment.offset)? / segment.sector_size,
+        };
+        // the IV tweak is in 512-byte units
+        let iv_offset = segment.iv_tweak / (segment.sector_size / 512);
+        CryptSectorStorage::new(sector_storage, key, segment.offset, segment.sector_size, sector_count, iv_offset)
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for CryptSectorStorage<SS> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut sectors_data = self
+            .sector_storage
+            .read_bytes(self.start + sector_index * self.sector_size, sector_count * self.sector_size)?;
+        for (index, sector_data) in sectors_data.chunks_mut(self.sector_size as usize).enumerate() {
+            self.xts.decrypt_sector(self.iv_offset + sector_index + index as u64, sector_data);
+        }
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut encrypted_sectors_data = sectors_data.to_vec();
+        for (index, sector_data) in encrypted_sectors_data.chunks_mut(self.sector_size as usize).enumerate() {
+            self.xts.encrypt_sector(self.iv_offset + sector_index + index as u64, sector_data);
+        }
+        self.sector_storage
+            .write_bytes(self.start + sector_index * self.sector_size, &encrypted_sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.sector_storage.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.sector_storage.read_only()
+    }
+
+    // discard and write_zeroes keep their defaults, forwarded discards would reveal the unused sectors and the zeroes have to be encrypted
+}
@@ -8,7 +8,7 @@ use core::{cmp::min, ops::Range};
 
 use super::{
     fs::{FileStat, FileType, Mode},
-    sector_storage::SectorStorage,
+    sector_storage::{SectorStorage, StorageError},
 };
 
 bitflags! {
@@ -76,7 +76,7 @@ impl Superblock {
     const MAGIC: u16 = 0xEF53;
     const SIZE: u64 = 1024;
 
-    fn of_bytes(superblock_data: &[u8]) -> Self {
+    fn of_bytes(superblock_data: &[u8]) -> Option<Self> {
         let mut superblock_data_cursor = Cursor::new(superblock_data);
         let inodes_count = superblock_data_cursor.read_u32::<LittleEndian>().unwrap() as u64;
         let blocks_count = superblock_data_cursor.read_u32::<LittleEndian>().unwrap() as u64;
@@ -109,10 +109,10 @@ impl Superblock {
         let features_compat = FeaturesCompat::from_bits_retain(superblock_data_cursor.read_u32::<LittleEndian>().unwrap());
         let features_incompat = FeaturesIncompat::from_bits_retain(superblock_data_cursor.read_u32::<LittleEndian>().unwrap());
         let features_ro_compat = FeaturesRoCompat::from_bits_retain(superblock_data_cursor.read_u32::<LittleEndian>().unwrap());
-        assert_eq!(log_block_size, log_fragment_size);
-        assert_eq!(block_count_per_block_group, fragment_count_per_group);
-        assert_eq!(magic, Superblock::MAGIC);
-        Superblock {
+        if magic != Superblock::MAGIC || log_block_size != log_fragment_size || block_count_per_block_group != fragment_count_per_group {
+            return None;
+        }
+        Some(Superblock {
             inodes_count,
             blocks_count,
             reserved_blocks_count,
@@ -141,7 +141,7 @@ impl Superblock {
             features_compat,
             features_incompat,
             features_ro_compat,
-        }
+        })
     }
 
     fn update_bytes(self, superblock_data: &mut [u8]) {
@@ -457,24 +457,62 @@ pub struct Session<'ss, SS: SectorStorage> {
 }
 
 impl<'ss, SS: SectorStorage> Session<'ss, SS> {
-    pub fn new(sector_storage: &'ss SS) -> Self {
-        let superblock = Superblock::of_bytes(&sector_storage.read_aligned(Superblock::INITIAL_START, Superblock::SIZE));
+    pub fn new(sector_storage: &'ss SS) -> Result<Option<Self>, StorageError> {
+        if Superblock::INITIAL_START + Superblock::SIZE > sector_storage.len() {
+            return Ok(None);
+        }
+        let Some(superblock) = Superblock::of_bytes(&sector_storage.read_bytes(Superblock::INITIAL_START, Superblock::SIZE)?) else {
+            return Ok(None);
+        };
         let mut session = Session {
             sector_storage,
             superblock,
             block_group_descriptors: Vec::new(),
         };
-        session.read_block_group_descriptors();
-        session
+        session.read_block_group_descriptors()?;
+        Ok(Some(session))
+    }
+
+    fn read_block(&self, block_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.sector_storage
+            .read_bytes(block_index * self.superblock.block_size(), self.superblock.block_size())
     }
 
-    fn read_block(&self, block_index: u64) -> Vec<u8> {
+    fn read_blocks(&self, block_index: u64, block_count: u64) -> Result<Vec<u8>, StorageError> {
         self.sector_storage
-            .read_aligned(block_index * self.superblock.block_size(), self.superblock.block_size())
+            .read_bytes(block_index * self.superblock.block_size(), block_count * self.superblock.block_size())
+    }
+
+    fn write_block(&self, block_index: u64, block_data: &[u8]) -> Result<(), StorageError> {
+        self.sector_storage.write_bytes(block_index * self.superblock.block_size(), block_data)
+    }
+
+    // the sectors of a block, unless blocks are smaller than sectors
+    fn block_sectors(&self, block_index: u64) -> Option<Range<u64>> {
+        let sector_size = self.sector_storage.sector_size();
+        if self.superblock.block_size() % sector_size != 0 {
+            return None;
+        }
+        let sector_count_per_block = self.superblock.block_size() / sector_size;
+        Some(block_index * sector_count_per_block..(block_index + 1) * sector_count_per_block)
+    }
+
+    // an ext3 journal that was not replayed, replaying it later would undo changes made without it
+    pub fn needs_recovery(&self) -> bool {
+        self.superblock.features_incompat.contains(FeaturesIncompat::RECOVER)
+    }
+
+    // called at the start of every modifying operation so that nothing is left half done
+    fn check_writable(&self) -> Result<(), StorageError> {
+        if self.sector_storage.read_only() || self.needs_recovery() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        Ok(())
     }
 
-    fn write_block(&self, block_index: u64, block_data: &[u8]) {
-        self.sector_storage.write_aligned(block_index * self.superblock.block_size(), block_data)
+    // makes the changes durable, called at the end of every modifying operation
+    fn commit(&self) -> Result<(), StorageError> {
+        self.sector_storage.flush()
     }
 
     fn block_group_ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
@@ -494,7 +532,7 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
         self.block_group_ranges().count() as u64
     }
 
-    fn read_block_group_descriptors(&mut self) {
+    fn read_block_group_descriptors(&mut self) -> Result<(), StorageError> {
         assert!(self.superblock.block_size() % BlockGroupDescriptor::SIZE == 0);
         for block_group_index in 0..self.block_group_count() {
             let block_group_descriptor_offset = block_group_index * BlockGroupDescriptor::SIZE;
@@ -505,12 +543,13 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
                 .nth(1 + (block_group_descriptor_offset / self.superblock.block_size()) as usize)
                 .unwrap();
             let block_group_descriptor_block_offset = block_group_descriptor_offset % self.superblock.block_size();
-            let block_group_descriptor_block_data = self.read_block(block_group_descriptor_block_index);
+            let block_group_descriptor_block_data = self.read_block(block_group_descriptor_block_index)?;
             let block_group_descriptor_data = &block_group_descriptor_block_data
                 [block_group_descriptor_block_offset as usize..(block_group_descriptor_block_offset + BlockGroupDescriptor::SIZE) as usize];
             let block_group_descriptor = BlockGroupDescriptor::of_bytes(block_group_descriptor_data);
             self.block_group_descriptors.push(block_group_descriptor);
         }
+        Ok(())
     }
 
     fn block_group_contains_superblock_and_block_group_descriptor_table_copies(&self, block_group_index: u64) -> bool {
@@ -522,18 +561,18 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
     }
 
     #[allow(clippy::iter_nth_zero)]
-    fn update_superblock_and_block_group_descriptor_table_copies(&self) {
+    fn update_superblock_and_block_group_descriptor_table_copies(&self) -> Result<(), StorageError> {
         for (block_group_index, block_group_range) in self.block_group_ranges().enumerate() {
             let block_group_index = block_group_index as u64;
             if self.block_group_contains_superblock_and_block_group_descriptor_table_copies(block_group_index) {
                 let superblock_block_index = block_group_range.clone().nth(0).unwrap();
-                let mut superblock_data = self.read_block(superblock_block_index);
+                let mut superblock_data = self.read_block(superblock_block_index)?;
                 Superblock {
                     block_group_index,
                     ..self.superblock
                 }
                 .update_bytes(&mut superblock_data);
-                self.write_block(superblock_block_index, &superblock_data);
+                self.write_block(superblock_block_index, &superblock_data)?;
                 for (block_group_index, &block_group_descriptor) in self.block_group_descriptors.iter().enumerate() {
                     let block_group_index = block_group_index as u64;
                     let block_group_descriptor_offset = block_group_index * BlockGroupDescriptor::SIZE;
@@ -542,99 +581,107 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
                         .nth(1 + (block_group_descriptor_offset / self.superblock.block_size()) as usize)
                         .unwrap();
                     let block_group_descriptor_block_offset = block_group_descriptor_offset % self.superblock.block_size();
-                    let mut block_group_descriptor_block_data = self.read_block(block_group_descriptor_block_index);
+                    let mut block_group_descriptor_block_data = self.read_block(block_group_descriptor_block_index)?;
                     let block_group_descriptor_data = &mut block_group_descriptor_block_data
                         [block_group_descriptor_block_offset as usize..(block_group_descriptor_block_offset + BlockGroupDescriptor::SIZE) as usize];
                     block_group_descriptor.update_bytes(block_group_descriptor_data);
-                    self.write_block(block_group_descriptor_block_index, &block_group_descriptor_block_data);
+                    self.write_block(block_group_descriptor_block_index, &block_group_descriptor_block_data)?;
                 }
             }
         }
+        Ok(())
     }
 
-    fn read_block_bitmap(&self, block_group_index: u64) -> Bitmap {
-        Bitmap {
-            data: self.read_block(self.block_group_descriptors[block_group_index as usize].block_bitmap_block_index),
-        }
+    fn read_block_bitmap(&self, block_group_index: u64) -> Result<Bitmap, StorageError> {
+        Ok(Bitmap {
+            data: self.read_block(self.block_group_descriptors[block_group_index as usize].block_bitmap_block_index)?,
+        })
     }
 
-    fn update_block_bitmap(&self, block_group_index: u64, block_bitmap: Bitmap) {
+    fn update_block_bitmap(&self, block_group_index: u64, block_bitmap: Bitmap) -> Result<(), StorageError> {
         self.write_block(
             self.block_group_descriptors[block_group_index as usize].block_bitmap_block_index,
             &block_bitmap.data,
-        );
+        )
     }
 
-    fn allocate_block(&mut self) -> u64 {
+    fn allocate_block(&mut self) -> Result<u64, StorageError> {
         let (block_group_index, (block_group_range, _)) = self
             .block_group_ranges()
             .zip(&self.block_group_descriptors)
             .enumerate()
             .find(|(_, (_, block_group_descriptor))| block_group_descriptor.free_blocks_count > 0)
-            .ok_or("no free blocks")
-            .unwrap();
+            .ok_or(StorageError::NoSpace)?;
         let block_group_index = block_group_index as u64;
-        let mut block_bitmap = self.read_block_bitmap(block_group_index);
+        let mut block_bitmap = self.read_block_bitmap(block_group_index)?;
         for block_index in block_group_range.clone() {
             if !block_bitmap.get((block_index - block_group_range.start) as usize) {
                 self.superblock.free_blocks_count -= 1;
                 self.block_group_descriptors[block_group_index as usize].free_blocks_count -= 1;
-                self.update_superblock_and_block_group_descriptor_table_copies();
+                self.update_superblock_and_block_group_descriptor_table_copies()?;
                 block_bitmap.set((block_index - block_group_range.start) as usize, true);
-                self.update_block_bitmap(block_group_index, block_bitmap);
-                return block_index;
+                self.update_block_bitmap(block_group_index, block_bitmap)?;
+                return Ok(block_index);
             }
         }
-        panic!("no free blocks inside block group");
+        Err(StorageError::NoSpace)
     }
 
-    fn allocate_zeroed_block(&mut self) -> u64 {
-        let block_index = self.allocate_block();
-        self.write_block(block_index, &vec![0; self.superblock.block_size() as usize]);
-        block_index
+    fn allocate_zeroed_block(&mut self) -> Result<u64, StorageError> {
+        let block_index = self.allocate_block()?;
+        match self.block_sectors(block_index) {
+            Some(block_sectors) => self.sector_storage.write_zeroes(block_sectors)?,
+            None => self.write_block(block_index, &vec![0; self.superblock.block_size() as usize])?,
+        }
+        Ok(block_index)
     }
 
-    fn free_block(&mut self, block_index: u64) {
+    fn free_block(&mut self, block_index: u64) -> Result<(), StorageError> {
         let (block_group_index, (block_group_range, _)) = self
             .block_group_ranges()
             .zip(&self.block_group_descriptors)
             .enumerate()
             .find(|(_, (block_group_range, _))| block_group_range.contains(&block_index))
-            .ok_or("block group not found")
-            .unwrap();
+            .ok_or(StorageError::OutOfRange)?;
         let block_group_index = block_group_index as u64;
-        let mut block_bitmap = self.read_block_bitmap(block_group_index);
-        assert!(block_bitmap.get((block_index - block_group_range.start) as usize));
+        let mut block_bitmap = self.read_block_bitmap(block_group_index)?;
+        // a block that is already free means a corrupt filesystem
+        if !block_bitmap.get((block_index - block_group_range.start) as usize) {
+            return Err(StorageError::OutOfRange);
+        }
         self.superblock.free_blocks_count += 1;
         self.block_group_descriptors[block_group_index as usize].free_blocks_count += 1;
-        self.update_superblock_and_block_group_descriptor_table_copies();
+        self.update_superblock_and_block_group_descriptor_table_copies()?;
         block_bitmap.set((block_index - block_group_range.start) as usize, false);
-        self.update_block_bitmap(block_group_index, block_bitmap);
+        self.update_block_bitmap(block_group_index, block_bitmap)?;
+        match self.block_sectors(block_index) {
+            Some(block_sectors) => self.sector_storage.discard(block_sectors),
+            None => Ok(()),
+        }
     }
 
-    fn read_inode_bitmap(&self, block_group_index: u64) -> Bitmap {
-        Bitmap {
-            data: self.read_block(self.block_group_descriptors[block_group_index as usize].inode_bitmap_block_index),
-        }
+    fn read_inode_bitmap(&self, block_group_index: u64) -> Result<Bitmap, StorageError> {
+        Ok(Bitmap {
+            data: self.read_block(self.block_group_descriptors[block_group_index as usize].inode_bitmap_block_index)?,
+        })
     }
 
-    fn update_inode_bitmap(&self, block_group_index: u64, inode_bitmap: Bitmap) {
+    fn update_inode_bitmap(&self, block_group_index: u64, inode_bitmap: Bitmap) -> Result<(), StorageError> {
         self.write_block(
             self.block_group_descriptors[block_group_index as usize].inode_bitmap_block_index,
             &inode_bitmap.data,
-        );
+        )
     }
 
-    fn allocate_inode(&mut self) -> u64 {
+    fn allocate_inode(&mut self) -> Result<u64, StorageError> {
         let (block_group_index, _) = self
             .block_group_descriptors
             .iter()
             .enumerate()
             .find(|(_, block_group_descriptor)| block_group_descriptor.free_inodes_count > 0)
-            .ok_or("no gree inodes")
-            .unwrap();
+            .ok_or(StorageError::NoSpace)?;
         let block_group_index = block_group_index as u64;
-        let mut inode_bitmap = self.read_inode_bitmap(block_group_index);
+        let mut inode_bitmap = self.read_inode_bitmap(block_group_index)?;
         let inode_range = 1 + block_group_index * self.superblock.inode_count_per_block_group
             ..1 + block_group_index * self.superblock.inode_count_per_block_group + self.superblock.inode_count_per_block_group;
         for inode_index in inode_range.clone() {
@@ -642,78 +689,89 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
                 assert!(inode_index >= self.superblock.first_usable_inode_index);
                 self.superblock.free_inodes_count -= 1;
                 self.block_group_descriptors[block_group_index as usize].free_inodes_count -= 1;
-                self.update_superblock_and_block_group_descriptor_table_copies();
+                self.update_superblock_and_block_group_descriptor_table_copies()?;
                 inode_bitmap.set((inode_index - inode_range.start) as usize, true);
-                self.update_inode_bitmap(block_group_index, inode_bitmap);
-                return inode_index;
+                self.update_inode_bitmap(block_group_index, inode_bitmap)?;
+                return Ok(inode_index);
             }
         }
-        panic!("no free inodes inside block group");
+        Err(StorageError::NoSpace)
     }
 
-    fn free_inode(&mut self, inode_index: u64) {
-        assert_ne!(inode_index, 0);
+    fn free_inode(&mut self, inode_index: u64) -> Result<(), StorageError> {
+        if inode_index == 0 || inode_index > self.superblock.inodes_count {
+            return Err(StorageError::OutOfRange);
+        }
         let block_group_index = (inode_index - 1) / self.superblock.inode_count_per_block_group;
-        let mut inode_bitmap = self.read_inode_bitmap(block_group_index);
-        assert!(inode_bitmap.get(((inode_index - 1) % self.superblock.inode_count_per_block_group) as usize));
+        let mut inode_bitmap = self.read_inode_bitmap(block_group_index)?;
+        // an inode that is already free means a corrupt filesystem
+        if !inode_bitmap.get(((inode_index - 1) % self.superblock.inode_count_per_block_group) as usize) {
+            return Err(StorageError::OutOfRange);
+        }
         self.superblock.free_inodes_count += 1;
         self.block_group_descriptors[block_group_index as usize].free_inodes_count += 1;
-        self.update_superblock_and_block_group_descriptor_table_copies();
+        self.update_superblock_and_block_group_descriptor_table_copies()?;
         inode_bitmap.set(((inode_index - 1) % self.superblock.inode_count_per_block_group) as usize, false);
-        self.update_inode_bitmap(block_group_index, inode_bitmap);
+        self.update_inode_bitmap(block_group_index, inode_bitmap)
     }
 
-    fn read_inode(&self, inode_index: u64) -> Inode {
-        assert!(self.superblock.block_size() % self.superblock.inode_size == 0);
-        assert_ne!(inode_index, 0);
+    // the block holding the inode and its offset in it
+    fn inode_location(&self, inode_index: u64) -> Result<(u64, u64), StorageError> {
+        if inode_index == 0 || inode_index > self.superblock.inodes_count {
+            return Err(StorageError::OutOfRange);
+        }
         let inode_block_group_index = (inode_index - 1) / self.superblock.inode_count_per_block_group;
         let inode_block_group_inode_index = (inode_index - 1) % self.superblock.inode_count_per_block_group;
         let inode_offset = inode_block_group_inode_index * self.superblock.inode_size;
-        let inode_block_index =
-            self.block_group_descriptors[inode_block_group_index as usize].inode_table_first_block_index + inode_offset / self.superblock.block_size();
-        let inode_block_offset = inode_offset % self.superblock.block_size();
-        let inode_block_data = self.read_block(inode_block_index);
+        let block_group_descriptor = self
+            .block_group_descriptors
+            .get(inode_block_group_index as usize)
+            .ok_or(StorageError::OutOfRange)?;
+        Ok((
+            block_group_descriptor.inode_table_first_block_index + inode_offset / self.superblock.block_size(),
+            inode_offset % self.superblock.block_size(),
+        ))
+    }
+
+    fn read_inode(&self, inode_index: u64) -> Result<Inode, StorageError> {
+        assert!(self.superblock.block_size() % self.superblock.inode_size == 0);
+        let (inode_block_index, inode_block_offset) = self.inode_location(inode_index)?;
+        let inode_block_data = self.read_block(inode_block_index)?;
         let inode_data = &inode_block_data[inode_block_offset as usize..(inode_block_offset + self.superblock.inode_size) as usize];
-        Inode::of_bytes(inode_data)
+        Ok(Inode::of_bytes(inode_data))
     }
 
-    fn update_inode(&self, inode_index: u64, inode: &Inode) {
-        assert_ne!(inode_index, 0);
-        let inode_block_group_index = (inode_index - 1) / self.superblock.inode_count_per_block_group;
-        let inode_block_group_inode_index = (inode_index - 1) % self.superblock.inode_count_per_block_group;
-        let inode_offset = inode_block_group_inode_index * self.superblock.inode_size;
-        let inode_block_index =
-            self.block_group_descriptors[inode_block_group_index as usize].inode_table_first_block_index + inode_offset / self.superblock.block_size();
-        let inode_block_offset = inode_offset % self.superblock.block_size();
-        let mut inode_block_data = self.read_block(inode_block_index);
+    fn update_inode(&self, inode_index: u64, inode: &Inode) -> Result<(), StorageError> {
+        let (inode_block_index, inode_block_offset) = self.inode_location(inode_index)?;
+        let mut inode_block_data = self.read_block(inode_block_index)?;
         let inode_data = &mut inode_block_data[inode_block_offset as usize..(inode_block_offset + self.superblock.inode_size) as usize];
         inode.update_bytes(inode_data);
-        self.write_block(inode_block_index, &inode_block_data);
+        self.write_block(inode_block_index, &inode_block_data)
     }
 
     fn block_indices_per_block(&self) -> u64 {
         self.superblock.block_size() / 4
     }
 
-    fn read_block_indices(&self, block_index: u64) -> Vec<u64> {
-        let block_data = self.read_block(block_index);
+    fn read_block_indices(&self, block_index: u64) -> Result<Vec<u64>, StorageError> {
+        let block_data = self.read_block(block_index)?;
         let mut block_data_cursor = Cursor::new(&block_data);
         let mut block_indices = Vec::new();
         while !block_data_cursor.is_empty() {
             block_indices.push(block_data_cursor.read_u32::<LittleEndian>().unwrap() as u64);
         }
         assert_eq!(block_indices.len() as u64, self.block_indices_per_block());
-        block_indices
+        Ok(block_indices)
     }
 
-    fn write_block_indices(&self, block_index: u64, block_indices: &[u64]) {
+    fn write_block_indices(&self, block_index: u64, block_indices: &[u64]) -> Result<(), StorageError> {
         assert_eq!(block_indices.len() as u64, self.block_indices_per_block());
         let mut block_data = vec![0; self.superblock.block_size() as usize];
         let mut block_data_cursor = Cursor::new(&mut block_data[..]);
         for &block_index in block_indices {
             block_data_cursor.write_u32::<LittleEndian>(block_index.try_into().unwrap()).unwrap();
         }
-        self.write_block(block_index, &block_data);
+        self.write_block(block_index, &block_data)
     }
 
     fn inode_block_path(&self, mut inode_block_index: u64) -> Vec<u64> {
@@ -748,32 +806,61 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
         }
     }
 
-    fn inode_read_data_block(&self, inode: &Inode, inode_block_index: u64) -> Vec<u8> {
+    fn inode_data_block_index(&self, inode: &Inode, inode_block_index: u64) -> Result<u64, StorageError> {
+        let inode_block_path = self.inode_block_path(inode_block_index);
         let mut data_block_index = 0;
         let mut data_block_indices = inode.data_block_map.to_vec();
-        for data_block_indices_index in self.inode_block_path(inode_block_index) {
+        for (path_element_index, &data_block_indices_index) in inode_block_path.iter().enumerate() {
             data_block_index = data_block_indices[data_block_indices_index as usize];
             if data_block_index == 0 {
-                return vec![0; self.superblock.block_size() as usize];
+                return Ok(0);
+            }
+            if path_element_index + 1 < inode_block_path.len() {
+                data_block_indices = self.read_block_indices(data_block_index)?;
             }
-            data_block_indices = self.read_block_indices(data_block_index);
         }
-        self.read_block(data_block_index)
+        Ok(data_block_index)
     }
 
-    fn inode_read_data_range(&self, inode: &Inode, range: Range<u64>) -> Vec<u8> {
-        (range.start / self.superblock.block_size()..range.end.div_ceil(self.superblock.block_size()))
-            .flat_map(|inode_block_index| self.inode_read_data_block(inode, inode_block_index))
-            .skip((range.start / self.superblock.block_size()) as usize)
-            .take(range.count())
-            .collect()
+    fn inode_read_data_block(&self, inode: &Inode, inode_block_index: u64) -> Result<Vec<u8>, StorageError> {
+        match self.inode_data_block_index(inode, inode_block_index)? {
+            0 => Ok(vec![0; self.superblock.block_size() as usize]),
+            data_block_index => self.read_block(data_block_index),
+        }
     }
 
-    fn inode_read_data(&self, inode: &Inode) -> Vec<u8> {
+    fn inode_read_data_range(&self, inode: &Inode, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::new();
+        let mut run: Option<(u64, u64)> = None; // first data block index, block count
+        for inode_block_index in range.start / self.superblock.block_size()..range.end.div_ceil(self.superblock.block_size()) {
+            let data_block_index = self.inode_data_block_index(inode, inode_block_index)?;
+            match &mut run {
+                Some((run_start, run_len)) if data_block_index != 0 && *run_start + *run_len == data_block_index => *run_len += 1,
+                _ => {
+                    if let Some((run_start, run_len)) = run.take() {
+                        data.extend(self.read_blocks(run_start, run_len)?);
+                    }
+                    if data_block_index == 0 {
+                        data.resize(data.len() + self.superblock.block_size() as usize, 0);
+                    } else {
+                        run = Some((data_block_index, 1));
+                    }
+                }
+            }
+        }
+        if let Some((run_start, run_len)) = run {
+            data.extend(self.read_blocks(run_start, run_len)?);
+        }
+        data.drain(..(range.start % self.superblock.block_size()) as usize);
+        data.truncate((range.end - range.start) as usize);
+        Ok(data)
+    }
+
+    fn inode_read_data(&self, inode: &Inode) -> Result<Vec<u8>, StorageError> {
         self.inode_read_data_range(inode, 0..inode.size)
     }
 
-    fn inode_write_data_block(&mut self, inode: &mut Inode, inode_block_index: u64, data_block_data: &[u8]) {
+    fn inode_write_data_block(&mut self, inode: &mut Inode, inode_block_index: u64, data_block_data: &[u8]) -> Result<(), StorageError> {
         let inode_block_path = self.inode_block_path(inode_block_index);
         let mut data_block_index = 0;
         let mut data_block_index_history = Vec::new();
@@ -781,76 +868,81 @@ impl<'ss, SS: SectorStorage> Session<'ss, SS> {
         let mut data_block_indices_history = Vec::new();
         for (path_element_index, &data_block_indices_index) in inode_block_path.iter().enumerate() {
             if data_block_indices[data_block_indices_index as usize] == 0 {
-                data_block_indices[data_block_indices_index as usize] = self.allocate_zeroed_block();
+                data_block_indices[data_block_indices_index as usize] = self.allocate_zeroed_block()?;
+                inode.sector_count += self.superblock.block_size() / 512;
                 if path_element_index == 0 {
                     inode.data_block_map = data_block_indices.clone().try_into().unwrap();
                 } else {
-                    self.write_block_indices(data_block_index, &data_block_indices);
+                    self.write_block_indices(data_block_index, &data_block_indices)?;
                 }
             }
             data_block_index = data_block_indices[data_block_indices_index as usize];
             data_block_index_history.push(data_block_index);
             data_block_indices_history.push(data_block_indices);
-            data_block_indices = self.read_block_indices(data_block_index);
+            data_block_indices = self.read_block_indices(data_block_index)?;
         }
-        self.write_block(data_block_index, data_block_data);
+        self.write_block(data_block_index, data_block_data)?;
         for path_element_index in (0..inode_block_path.len()).rev() {
-            if self.read_block(data_block_index_history[path_element_index]) != vec![0; self.superblock.block_size() as usize] {
+            if self.read_block(data_block_index_history[path_element_index])? != vec![0; self.superblock.block_size() as usize] {
                 break;
             }
             let data_block_indices_index = inode_block_path[path_element_index];
             let data_block_indices = &mut data_block_indices_history[path_element_index];
-            self.free_block(data_block_indices[data_block_indices_index as usize]);
+            self.free_block(data_block_indices[data_block_indices_index as usize])?;
+            inode.sector_count -= self.superblock.block_size() / 512;
             data_block_indices[data_block_indices_index as usize] = 0;
             if path_element_index == 0 {
                 inode.data_block_map = data_block_indices.clone().try_into().unwrap();
             } else {
-                self.write_block_indices(data_block_index_history[path_element_index], data_block_indices);
+                // the indices live in the parent block
+                self.write_block_indices(data_block_index_history[path_element_index - 1], data_block_indices)?;
             }
         }
+        Ok(())
     }
 
-    fn inode_write_data_range(&mut self, inode: &mut Inode, range: Range<u64>, data: &[u8]) {
+    fn inode_write_data_range(&mut self, inode: &mut Inode, range: Range<u64>, data: &[u8]) -> Result<(), StorageError> {
         assert_eq!(range.clone().count(), data.len());
         if range.is_empty() {
-            return;
+            return Ok(());
         }
         let first_inode_block_index = range.start / self.superblock.block_size();
         let last_inode_block_index = range.end.div_ceil(self.superblock.block_size()) - 1;
         if first_inode_block_index == last_inode_block_index {
-            let mut block_data = self.inode_read_data_block(inode, first_inode_block_index);
+            let mut block_data = self.inode_read_data_block(inode, first_inode_block_index)?;
             block_data[(range.start % self.superblock.block_size()) as usize..((range.end - 1) % self.superblock.block_size() + 1) as usize]
                 .copy_from_slice(data);
-            self.inode_write_data_block(inode, first_inode_block_index, &block_data);
+            self.inode_write_data_block(inode, first_inode_block_index, &block_data)?;
         } else {
-            let mut first_block_data = self.inode_read_data_block(inode, first_inode_block_index);
-            first_block_data[(first_inode_block_index * self.superblock.block_size() + self.superblock.block_size() - range.start) as usize..]
-                .copy_from_slice(&data[..(range.start - first_inode_block_index * self.superblock.block_size()) as usize]);
-            self.inode_write_data_block(inode, first_inode_block_index, &first_block_data);
+            let mut first_block_data = self.inode_read_data_block(inode, first_inode_block_index)?;
+            first_block_data[(range.start - first_inode_block_index * self.superblock.block_size()) as usize..]
+                .copy_from_slice(&data[..(first_inode_block_index * self.superblock.block_size() + self.superblock.block_size() - range.start) as usize]);
+            self.inode_write_data_block(inode, first_inode_block_index, &first_block_data)?;
             for inode_block_index in first_inode_block_index + 1..=last_inode_block_index - 1 {
                 self.inode_write_data_block(
                     inode,
                     inode_block_index,
                     &data[(inode_block_index * self.superblock.block_size() - range.start) as usize
                         ..(inode_block_index * self.superblock.block_size() + self.superblock.block_size() - range.start) as usize],
-                );
+                )?;
             }
-            let mut last_block_data = self.inode_read_data_block(inode, last_inode_block_index);
+            let mut last_block_data = self.inode_read_data_block(inode, last_inode_block_index)?;
             last_block_data[..(range.end - last_inode_block_index * self.superblock.block_size()) as usize]
                 .copy_from_slice(&data[(last_inode_block_index * self.superblock.block_size() - range.start) as usize..]);
-            self.inode_write_data_block(inode, last_inode_block_index, &last_block_data);
+            self.inode_write_data_block(inode, last_inode_block_index, &last_block_data)?;
         }
+        Ok(())
     }
 
-    fn inode_write_data(&mut self, inode: &mut Inode, data: &[u8]) {
-        self.inode_write_data_range(inode, 0..inode.size, data);
+    fn inode_write_data(&mut self, inode: &mut Inode, data: &[u8]) -> Result<(), StorageError> {
+        self.inode_write_data_range(inode, 0..inode.size, data)
     }
 
-    fn inode_resize(&mut self, inode: &mut Inode, new_size: u64) {
-        self.inode_write_data_range(inode, inode.size..new_size, &vec![0; (inode.size..new_size).count()]);
-        self.inode_write_data_range(inode, new_size..inode.size, &vec![0; (new_size..inode.size).count()]);
+    fn inode_resize(&mut self, inode: &mut Inode, new_size: u64) -> Result<(), StorageError> {
+        self.inode_write_data_range(inode, inode.size..new_size, &vec![0; (inode.size..new_size).count()])?;
+        self.inode_write_data_range(inode, new_size..inode.size, &vec![0; (new_size..inode.size).count()])?;
         inode.size = new_size;
-        inode.sector_count = new_size * self.superblock.block_size() / 512;
+        Ok(())
     }
 }
 
@@ -859,9 +951,13 @@ impl<'ss, SS: SectorStorage> super::fs::Session for Session<'ss, SS> {
         2
     }
 
-    fn file_stat(&self, inode_index: u64) -> FileStat {
-        let inode = self.read_inode(inode_index);
-        FileStat {
+    fn read_only(&self) -> bool {
+        self.sector_storage.read_only() || self.needs_recovery()
+    }
+
+    fn file_stat(&self, inode_index: u64) -> Result<FileStat, StorageError> {
+        let inode = self.read_inode(inode_index)?;
+        Ok(FileStat {
             mode: inode.mode,
             uid: inode.uid,
             gid: inode.gid,
@@ -870,11 +966,12 @@ impl<'ss, SS: SectorStorage> super::fs::Session for Session<'ss, SS> {
             access_time: inode.access_time,
             creation_time: inode.creation_time,
             modification_time: inode.modification_time,
-        }
+        })
     }
 
-    fn create(&mut self, file_type: FileType, permissions: u32) -> u64 {
-        let inode_index = self.allocate_inode();
+    fn create(&mut self, file_type: FileType, permissions: u32) -> Result<u64, StorageError> {
+        self.check_writable()?;
+        let inode_index = self.allocate_inode()?;
         let inode = Inode {
             mode: Mode::from_file_type_and_permissions(permissions, file_type),
             uid: 0,
@@ -895,50 +992,65 @@ impl<'ss, SS: SectorStorage> super::fs::Session for Session<'ss, SS> {
             faddr: 0,
             os_dependent_2: [0; 12],
         };
-        self.update_inode(inode_index, &inode);
-        inode_index
+        self.update_inode(inode_index, &inode)?;
+        self.commit()?;
+        Ok(inode_index)
     }
 
-    fn remove(&mut self, inode_index: u64) {
-        self.free_inode(inode_index);
+    fn remove(&mut self, inode_index: u64) -> Result<(), StorageError> {
+        self.check_writable()?;
+        self.free_inode(inode_index)?;
+        self.commit()
     }
 
-    fn set_links_count(&mut self, inode_index: u64, links_count: u16) {
-        let mut inode = self.read_inode(inode_index);
+    fn set_links_count(&mut self, inode_index: u64, links_count: u16) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let mut inode = self.read_inode(inode_index)?;
         inode.links_count = links_count;
-        self.update_inode(inode_index, &inode);
+        self.update_inode(inode_index, &inode)?;
+        self.commit()
     }
 
-    fn read_regular_file_range(&self, inode_index: u64, range: Range<u64>) -> Vec<u8> {
-        let inode = self.read_inode(inode_index);
+    fn read_regular_file_range(&self, inode_index: u64, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+        let inode = self.read_inode(inode_index)?;
         self.inode_read_data_range(&inode, range)
     }
 
-    fn write_regular_file_range(&mut self, inode_index: u64, range: Range<u64>, data: &[u8]) {
-        let mut inode = self.read_inode(inode_index);
-        self.inode_write_data_range(&mut inode, range, data);
-        self.update_inode(inode_index, &inode);
+    fn write_regular_file_range(&mut self, inode_index: u64, range: Range<u64>, data: &[u8]) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let mut inode = self.read_inode(inode_index)?;
+        // the inode is updated even after a failed write, so that it keeps the blocks allocated so far
+        let result = self.inode_write_data_range(&mut inode, range, data);
+        self.update_inode(inode_index, &inode)?;
+        self.commit()?;
+        result
     }
 
-    fn resize_regular_file(&mut self, inode_index: u64, size: u64) {
-        let mut inode = self.read_inode(inode_index);
-        self.inode_resize(&mut inode, size);
-        self.update_inode(inode_index, &inode);
+    fn resize_regular_file(&mut self, inode_index: u64, size: u64) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let mut inode = self.read_inode(inode_index)?;
+        let result = self.inode_resize(&mut inode, size);
+        self.update_inode(inode_index, &inode)?;
+        self.commit()?;
+        result
     }
 
-    fn read_dir(&self, inode_index: u64) -> Vec<super::fs::DirEntry> {
-        let inode = self.read_inode(inode_index);
-        let dir_entries_data = self.inode_read_data(&inode);
-        DirEntry::many_of_bytes(&dir_entries_data)
+    fn read_dir(&self, inode_index: u64) -> Result<Vec<super::fs::DirEntry>, StorageError> {
+        let inode = self.read_inode(inode_index)?;
+        let dir_entries_data = self.inode_read_data(&inode)?;
+        Ok(DirEntry::many_of_bytes(&dir_entries_data)
             .into_iter()
             .map(|dir_entry| dir_entry.into())
-            .collect()
+            .collect())
     }
 
-    fn write_dir(&mut self, inode_index: u64, dir_entries: &[super::fs::DirEntry]) {
-        let mut inode = self.read_inode(inode_index);
+    fn write_dir(&mut self, inode_index: u64, dir_entries: &[super::fs::DirEntry]) -> Result<(), StorageError> {
+        self.check_writable()?;
+        let mut inode = self.read_inode(inode_index)?;
         let dir_entries_data = DirEntry::many_to_bytes(&dir_entries.iter().map(|dir_entry| dir_entry.clone().into()).collect::<Vec<_>>());
-        self.inode_write_data(&mut inode, &dir_entries_data);
-        self.update_inode(inode_index, &inode);
+        let result = self.inode_write_data(&mut inode, &dir_entries_data);
+        self.update_inode(inode_index, &inode)?;
+        self.commit()?;
+        result
     }
 }
@@ -0,0 +1,86 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+#[derive(Debug)]
+pub struct DiskSectorStorage {
+    file: File,
+    sector_size: u64,
+    sector_count: u64,
+    read_only: bool,
+}
+
+impl DiskSectorStorage {
+    pub fn open(path: impl AsRef<Path>, sector_size: u64) -> io::Result<Self> {
+        Self::from_file(OpenOptions::new().read(true).write(true).open(path)?, sector_size, false)
+    }
+
+    pub fn open_read_only(path: impl AsRef<Path>, sector_size: u64) -> io::Result<Self> {
+        Self::from_file(File::open(path)?, sector_size, true)
+    }
+
+    fn from_file(file: File, sector_size: u64, read_only: bool) -> io::Result<Self> {
+        let sector_count = file.metadata()?.len() / sector_size; // a trailing partial sector is ignored
+        Ok(DiskSectorStorage {
+            file,
+            sector_size,
+            sector_count,
+            read_only,
+        })
+    }
+}
+
+impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut sectors_data = vec![0; (sector_count * self.sector_size) as usize];
+        self.file
+            .read_exact_at(&mut sectors_data, sector_index * self.sector_size)
+            .map_err(|_| StorageError::DeviceError)?;
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only {
+            return Err(StorageError::MediaReadOnly);
+        }
+        self.file
+            .write_all_at(sectors_data, sector_index * self.sector_size)
+            .map_err(|_| StorageError::DeviceError)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.file.sync_data().map_err(|_| StorageError::DeviceError)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
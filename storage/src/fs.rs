@@ -2,6 +2,8 @@ use alloc::{string::String, vec::Vec};
 use bitflags::bitflags;
 use core::ops::Range;
 
+use super::sector_storage::StorageError;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum FileType {
     RegularFile,
@@ -80,15 +82,20 @@ impl Mode {
     }
 
     pub fn file_type(self) -> FileType {
+        self.try_file_type().expect("unknown mode")
+    }
+
+    // None for file type bits read from a corrupt or foreign file system
+    pub fn try_file_type(self) -> Option<FileType> {
         match self.difference(Mode::from_bits_retain(0x0fff)) {
-            Mode::FIFO => FileType::Fifo,
-            Mode::CHARACTER_DEVICE => FileType::CharacterDevice,
-            Mode::DIR => FileType::Dir,
-            Mode::BLOCK_DEVICE => FileType::BlockDevice,
-            Mode::REGULAR_FILE => FileType::RegularFile,
-            Mode::SYMBOLIC_LINK => FileType::SymbolicLink,
-            Mode::SOCKET => FileType::Socket,
-            _ => panic!("unknown mode"),
+            Mode::FIFO => Some(FileType::Fifo),
+            Mode::CHARACTER_DEVICE => Some(FileType::CharacterDevice),
+            Mode::DIR => Some(FileType::Dir),
+            Mode::BLOCK_DEVICE => Some(FileType::BlockDevice),
+            Mode::REGULAR_FILE => Some(FileType::RegularFile),
+            Mode::SYMBOLIC_LINK => Some(FileType::SymbolicLink),
+            Mode::SOCKET => Some(FileType::Socket),
+            _ => None,
         }
     }
 
@@ -119,21 +126,24 @@ pub struct DirEntry {
 pub trait Session {
     fn root(&self) -> u64;
 
-    fn file_stat(&self, inode_index: u64) -> FileStat;
+    // the modifying calls fail with MediaReadOnly
+    fn read_only(&self) -> bool;
+
+    fn file_stat(&self, inode_index: u64) -> Result<FileStat, StorageError>;
 
-    fn create(&mut self, file_type: FileType, permissions: u32) -> u64;
+    fn create(&mut self, file_type: FileType, permissions: u32) -> Result<u64, StorageError>;
 
-    fn remove(&mut self, inode_index: u64);
+    fn remove(&mut self, inode_index: u64) -> Result<(), StorageError>;
 
-    fn set_links_count(&mut self, inode_index: u64, links_count: u16);
+    fn set_links_count(&mut self, inode_index: u64, links_count: u16) -> Result<(), StorageError>;
 
-    fn read_regular_file_range(&self, inode_index: u64, range: Range<u64>) -> Vec<u8>;
+    fn read_regular_file_range(&self, inode_index: u64, range: Range<u64>) -> Result<Vec<u8>, StorageError>;
 
-    fn write_regular_file_range(&mut self, inode_index: u64, range: Range<u64>, data: &[u8]);
+    fn write_regular_file_range(&mut self, inode_index: u64, range: Range<u64>, data: &[u8]) -> Result<(), StorageError>;
 
-    fn resize_regular_file(&mut self, inode_index: u64, size: u64);
+    fn resize_regular_file(&mut self, inode_index: u64, size: u64) -> Result<(), StorageError>;
 
-    fn read_dir(&self, inode_index: u64) -> Vec<DirEntry>;
+    fn read_dir(&self, inode_index: u64) -> Result<Vec<DirEntry>, StorageError>;
 
-    fn write_dir(&mut self, inode_index: u64, dir_entries: &[DirEntry]);
+    fn write_dir(&mut self, inode_index: u64, dir_entries: &[DirEntry]) -> Result<(), StorageError>;
 }
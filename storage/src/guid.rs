@@ -0,0 +1,31 @@
+use core::fmt::{Debug, Display, Formatter};
+
+#[derive(PartialEq, Eq)]
+pub struct Guid(uefi::data_types::Guid);
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Guid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid(uefi::data_types::Guid::from_bytes(bytes))
+    }
+
+    pub fn parse(string: &str) -> Option<Guid> {
+        uefi::data_types::Guid::try_parse(string).ok().map(Guid)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.0.to_bytes()
+    }
+}
+
+pub const ZERO: Guid = Guid(uefi::data_types::Guid::ZERO);
+pub const TYPE_ID_LINUX: Guid = Guid(uefi::data_types::Guid::parse_or_panic("0FC63DAF-8483-4772-8E79-3D69D8477DE4"));
+pub const TYPE_ID_LINUX_RAID: Guid = Guid(uefi::data_types::Guid::parse_or_panic("A19D880F-05FC-4D3B-A006-743F0F84911E"));
+pub const TYPE_ID_LINUX_SWAP: Guid = Guid(uefi::data_types::Guid::parse_or_panic("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"));
+pub const TYPE_ID_EFI_SYSTEM: Guid = Guid(uefi::data_types::Guid::parse_or_panic("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+pub const TYPE_ID_MICROSOFT_BASIC_DATA: Guid = Guid(uefi::data_types::Guid::parse_or_panic("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"));
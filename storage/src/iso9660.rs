@@ -0,0 +1,333 @@
+use alloc::{string::String, vec::Vec};
+use core::ops::Range;
+
+use super::{
+    fs::{DirEntry, FileStat, FileType, Mode},
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const LOGICAL_SECTOR_SIZE: u64 = 2048;
+const VOLUME_DESCRIPTOR_SET_START: u64 = 16 * LOGICAL_SECTOR_SIZE;
+const MAX_VOLUME_DESCRIPTOR_COUNT: u64 = 64;
+const VOLUME_DESCRIPTOR_TYPE_PRIMARY: u8 = 1;
+const VOLUME_DESCRIPTOR_TYPE_TERMINATOR: u8 = 255;
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+
+const DIR_RECORD_MIN_LEN: usize = 33;
+const FILE_FLAG_DIR: u8 = 1 << 1;
+
+const MAX_CONTINUATION_AREA_COUNT: usize = 16;
+const NM_FLAG_CURRENT: u8 = 1 << 1;
+const NM_FLAG_PARENT: u8 = 1 << 2;
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn unix_time_of_bytes(recording_time_data: &[u8]) -> u64 {
+    let days = days_from_civil(
+        1900 + recording_time_data[0] as i64,
+        recording_time_data[1] as i64,
+        recording_time_data[2] as i64,
+    );
+    let seconds = days * 86400 + recording_time_data[3] as i64 * 3600 + recording_time_data[4] as i64 * 60 + recording_time_data[5] as i64;
+    let gmt_offset = recording_time_data[6] as i8 as i64 * 15 * 60; // in 15 minute intervals
+    (seconds - gmt_offset).max(0) as u64
+}
+
+#[derive(Clone, Debug)]
+struct DirRecord {
+    extent_block_index: u64,
+    data_len: u64,
+    recording_time: u64,
+    flags: u8,
+    file_identifier: Vec<u8>,
+    system_use: Vec<u8>,
+}
+
+impl DirRecord {
+    // none for records too short for their file identifier
+    fn of_bytes(dir_record_data: &[u8]) -> Option<Self> {
+        if dir_record_data.len() < DIR_RECORD_MIN_LEN {
+            return None;
+        }
+        let file_identifier_len = dir_record_data[32] as usize;
+        if DIR_RECORD_MIN_LEN + file_identifier_len > dir_record_data.len() {
+            return None;
+        }
+        let system_use_start = (DIR_RECORD_MIN_LEN + file_identifier_len).next_multiple_of(2); // padding byte after even identifiers
+        Some(DirRecord {
+            extent_block_index: u32::from_le_bytes(dir_record_data[2..6].try_into().unwrap()) as u64,
+            data_len: u32::from_le_bytes(dir_record_data[10..14].try_into().unwrap()) as u64,
+            recording_time: unix_time_of_bytes(&dir_record_data[18..25]),
+            flags: dir_record_data[25],
+            file_identifier: dir_record_data[DIR_RECORD_MIN_LEN..DIR_RECORD_MIN_LEN + file_identifier_len].to_vec(),
+            system_use: dir_record_data.get(system_use_start..).unwrap_or(&[]).to_vec(),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.flags & FILE_FLAG_DIR != 0
+    }
+
+    fn name(&self) -> String {
+        match self.file_identifier.as_slice() {
+            [0] => String::from("."),
+            [1] => String::from(".."),
+            file_identifier => {
+                let mut name = file_identifier;
+                if let Some(version_start) = name.iter().position(|&byte| byte == b';') {
+                    name = &name[..version_start];
+                }
+                if let Some((b'.', chopped_name)) = name.split_last() {
+                    name = chopped_name;
+                }
+                String::from_utf8_lossy(name).into_owned()
+            }
+        }
+    }
+}
+
+pub struct Session<'ss, SS: SectorStorage> {
+    sector_storage: &'ss SS,
+    logical_block_size: u64,
+    root_extent_block_index: u64,
+    system_use_skip_len: Option<usize>, // set when Rock Ridge extensions are used
+}
+
+impl<'ss, SS: SectorStorage> Session<'ss, SS> {
+    pub fn new(sector_storage: &'ss SS) -> Result<Option<Self>, StorageError> {
+        for volume_descriptor_index in 0..MAX_VOLUME_DESCRIPTOR_COUNT {
+            let volume_descriptor_start = VOLUME_DESCRIPTOR_SET_START + volume_descriptor_index * LOGICAL_SECTOR_SIZE;
+            if volume_descriptor_start + LOGICAL_SECTOR_SIZE > sector_storage.len() {
+                return Ok(None);
+            }
+            let volume_descriptor_data = sector_storage.read_bytes(volume_descriptor_start, LOGICAL_SECTOR_SIZE)?;
+            if &volume_descriptor_data[1..6] != STANDARD_IDENTIFIER || volume_descriptor_data[0] == VOLUME_DESCRIPTOR_TYPE_TERMINATOR {
+                return Ok(None);
+            }
+            if volume_descriptor_data[0] != VOLUME_DESCRIPTOR_TYPE_PRIMARY {
+                continue;
+            }
+            let logical_block_size = u16::from_le_bytes(volume_descriptor_data[128..130].try_into().unwrap()) as u64;
+            if !logical_block_size.is_power_of_two() || !(512..=LOGICAL_SECTOR_SIZE).contains(&logical_block_size) {
+                return Ok(None);
+            }
+            let Some(root_dir_record) = DirRecord::of_bytes(&volume_descriptor_data[156..190]) else {
+                return Ok(None);
+            };
+            let mut session = Session {
+                sector_storage,
+                logical_block_size,
+                root_extent_block_index: root_dir_record.extent_block_index,
+                system_use_skip_len: None,
+            };
+            let root_current_dir_record = match session.read_dir_record(session.root_extent_block_index * session.logical_block_size) {
+                Ok(root_current_dir_record) => root_current_dir_record,
+                Err(StorageError::OutOfRange) => return Ok(None),
+                Err(error) => return Err(error),
+            };
+            if let [b'S', b'P', _, _, 0xBE, 0xEF, skip_len, ..] = root_current_dir_record.system_use[..] {
+                session.system_use_skip_len = Some(skip_len as usize);
+            }
+            return Ok(Some(session));
+        }
+        Ok(None)
+    }
+
+    // malformed records are out of range, like records past the end of the disc
+    fn read_dir_record(&self, dir_record_start: u64) -> Result<DirRecord, StorageError> {
+        // dir records never cross logical block boundaries
+        let logical_block_end = (dir_record_start / self.logical_block_size + 1) * self.logical_block_size;
+        let data = self.sector_storage.read_bytes(dir_record_start, logical_block_end - dir_record_start)?;
+        data.get(..data[0] as usize).and_then(DirRecord::of_bytes).ok_or(StorageError::OutOfRange)
+    }
+
+    fn read_dir_records(&self, dir_record: &DirRecord) -> Result<Vec<(u64, DirRecord)>, StorageError> {
+        let extent_start = dir_record.extent_block_index * self.logical_block_size;
+        let extent_data = self.sector_storage.read_bytes(extent_start, dir_record.data_len)?;
+        let mut dir_records = Vec::new();
+        let mut offset = 0;
+        while offset < extent_data.len() {
+            let dir_record_len = extent_data[offset] as usize;
+            if dir_record_len == 0 {
+                offset = (offset + 1).next_multiple_of(self.logical_block_size as usize); // padding until the next logical block
+                continue;
+            }
+            let dir_record = extent_data
+                .get(offset..offset + dir_record_len)
+                .and_then(DirRecord::of_bytes)
+                .ok_or(StorageError::OutOfRange)?;
+            dir_records.push((extent_start + offset as u64, dir_record));
+            offset += dir_record_len;
+        }
+        Ok(dir_records)
+    }
+
+    fn rock_ridge_entries(&self, dir_record: &DirRecord) -> Result<Vec<([u8; 2], Vec<u8>)>, StorageError> {
+        let Some(system_use_skip_len) = self.system_use_skip_len else {
+            return Ok(Vec::new());
+        };
+        let mut entries = Vec::new();
+        let mut area = dir_record.system_use.get(system_use_skip_len..).unwrap_or(&[]).to_vec();
+        for _ in 0..MAX_CONTINUATION_AREA_COUNT {
+            let mut continuation_area = None;
+            let mut remaining_area = area.as_slice();
+            while remaining_area.len() >= 4 {
+                let entry_len = remaining_area[2] as usize;
+                if entry_len < 4 || entry_len > remaining_area.len() {
+                    break;
+                }
+                let entry_data = &remaining_area[4..entry_len];
+                match &remaining_area[0..2] {
+                    b"ST" => break,
+                    b"CE" if entry_data.len() >= 24 => {
+                        continuation_area = Some((
+                            u32::from_le_bytes(entry_data[0..4].try_into().unwrap()) as u64,
+                            u32::from_le_bytes(entry_data[8..12].try_into().unwrap()) as u64,
+                            u32::from_le_bytes(entry_data[16..20].try_into().unwrap()) as u64,
+                        ))
+                    }
+                    signature => entries.push(([signature[0], signature[1]], entry_data.to_vec())),
+                }
+                remaining_area = &remaining_area[entry_len..];
+            }
+            let Some((block_index, offset, len)) = continuation_area else {
+                break;
+            };
+            area = self.sector_storage.read_bytes(block_index * self.logical_block_size + offset, len)?;
+        }
+        Ok(entries)
+    }
+
+    fn rock_ridge_name(&self, dir_record: &DirRecord) -> Result<Option<String>, StorageError> {
+        let mut name = None;
+        for (signature, entry_data) in self.rock_ridge_entries(dir_record)? {
+            if &signature != b"NM" || entry_data.is_empty() || entry_data[0] & (NM_FLAG_CURRENT | NM_FLAG_PARENT) != 0 {
+                continue;
+            }
+            name.get_or_insert_with(Vec::new).extend_from_slice(&entry_data[1..]);
+        }
+        Ok(name.map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    fn rock_ridge_posix_attributes(&self, dir_record: &DirRecord) -> Result<Option<(Mode, u16, u16, u16)>, StorageError> {
+        for (signature, entry_data) in self.rock_ridge_entries(dir_record)? {
+            if &signature == b"PX" && entry_data.len() >= 32 {
+                let mut mode = Mode::from_bits_retain(u32::from_le_bytes(entry_data[0..4].try_into().unwrap()));
+                if mode.try_file_type().is_none() {
+                    log::warn!("Rock Ridge file mode {:#o} has an unknown file type", mode.bits());
+                    let file_type = if dir_record.is_dir() { FileType::Dir } else { FileType::RegularFile };
+                    mode = Mode::from_file_type_and_permissions(mode.permissions(), file_type);
+                }
+                return Ok(Some((
+                    mode,
+                    u32::from_le_bytes(entry_data[8..12].try_into().unwrap()) as u16,
+                    u32::from_le_bytes(entry_data[16..20].try_into().unwrap()) as u16,
+                    u32::from_le_bytes(entry_data[24..28].try_into().unwrap()) as u16,
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    fn file_type(&self, dir_record: &DirRecord) -> Result<FileType, StorageError> {
+        Ok(match self.rock_ridge_posix_attributes(dir_record)? {
+            Some((mode, ..)) => mode.file_type(),
+            None if dir_record.is_dir() => FileType::Dir,
+            None => FileType::RegularFile,
+        })
+    }
+
+    // dirs are identified by the start of their extent (their "." record), other files by the start of their record
+    fn inode_index(&self, dir_record_start: u64, dir_record: &DirRecord) -> u64 {
+        if dir_record.is_dir() {
+            dir_record.extent_block_index * self.logical_block_size
+        } else {
+            dir_record_start
+        }
+    }
+}
+
+impl<'ss, SS: SectorStorage> super::fs::Session for Session<'ss, SS> {
+    fn root(&self) -> u64 {
+        self.root_extent_block_index * self.logical_block_size
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn file_stat(&self, inode_index: u64) -> Result<FileStat, StorageError> {
+        let dir_record = self.read_dir_record(inode_index)?;
+        let (mode, links_count, uid, gid) = match self.rock_ridge_posix_attributes(&dir_record)? {
+            Some(posix_attributes) => posix_attributes,
+            None if dir_record.is_dir() => (Mode::from_file_type_and_permissions(0o555, FileType::Dir), 2, 0, 0),
+            None => (Mode::from_file_type_and_permissions(0o444, FileType::RegularFile), 1, 0, 0),
+        };
+        Ok(FileStat {
+            mode,
+            uid,
+            gid,
+            links_count,
+            size: dir_record.data_len,
+            access_time: dir_record.recording_time,
+            creation_time: dir_record.recording_time,
+            modification_time: dir_record.recording_time,
+        })
+    }
+
+    fn create(&mut self, _file_type: FileType, _permissions: u32) -> Result<u64, StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn remove(&mut self, _inode_index: u64) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn set_links_count(&mut self, _inode_index: u64, _links_count: u16) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn read_regular_file_range(&self, inode_index: u64, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+        let dir_record = self.read_dir_record(inode_index)?;
+        let range = range.start.min(dir_record.data_len)..range.end.min(dir_record.data_len);
+        self.sector_storage
+            .read_bytes(dir_record.extent_block_index * self.logical_block_size + range.start, range.end - range.start)
+    }
+
+    fn write_regular_file_range(&mut self, _inode_index: u64, _range: Range<u64>, _data: &[u8]) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn resize_regular_file(&mut self, _inode_index: u64, _size: u64) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+
+    fn read_dir(&self, inode_index: u64) -> Result<Vec<DirEntry>, StorageError> {
+        let dir_record = self.read_dir_record(inode_index)?;
+        self.read_dir_records(&dir_record)?
+            .into_iter()
+            .map(|(dir_record_start, dir_record)| {
+                let name = match dir_record.file_identifier.as_slice() {
+                    [0] | [1] => dir_record.name(),
+                    _ => self.rock_ridge_name(&dir_record)?.unwrap_or_else(|| dir_record.name()),
+                };
+                Ok(DirEntry {
+                    inode_index: self.inode_index(dir_record_start, &dir_record),
+                    file_type: Some(self.file_type(&dir_record)?),
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    fn write_dir(&mut self, _inode_index: u64, _dir_entries: &[DirEntry]) -> Result<(), StorageError> {
+        Err(StorageError::MediaReadOnly)
+    }
+}
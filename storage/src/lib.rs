@@ -0,0 +1,22 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod cache;
+pub mod crypt;
+pub mod ext2;
+#[cfg(feature = "std")]
+pub mod file_disk;
+pub mod fs;
+pub mod guid;
+pub mod iso9660;
+pub mod loop_disk;
+pub mod luks2;
+pub mod overlay;
+pub mod partitions;
+pub mod probe;
+pub mod raid;
+pub mod ram_disk;
+pub mod sector_storage;
+pub mod stats;
+pub mod vfs;
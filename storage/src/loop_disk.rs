@@ -0,0 +1,96 @@
+use alloc::vec::Vec;
+use core::{cell::RefCell, fmt::Debug};
+
+use super::{
+    fs::{FileType, Session},
+    sector_storage::{SectorStorage, StorageError},
+};
+
+// a regular file of a filesystem seen as a disk, the file is neither grown nor shrunk
+pub struct DiskSectorStorage<S: Session> {
+    session: RefCell<S>,
+    inode_index: u64,
+    sector_size: u64,
+    sector_count: u64, // a trailing partial sector of the file is left out
+}
+
+impl<S: Session + Debug> Debug for DiskSectorStorage<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DiskSectorStorage")
+            .field("session", &self.session)
+            .field("inode_index", &self.inode_index)
+            .field("sector_size", &self.sector_size)
+            .field("sector_count", &self.sector_count)
+            .finish()
+    }
+}
+
+impl<S: Session> DiskSectorStorage<S> {
+    pub fn new(session: S, inode_index: u64, sector_size: u64) -> Result<Option<Self>, StorageError> {
+        // no filesystem uses inode 0, the others out of range are refused by file_stat
+        if inode_index == 0 || sector_size == 0 {
+            return Err(StorageError::OutOfRange);
+        }
+        let file_stat = session.file_stat(inode_index)?;
+        if file_stat.mode.try_file_type() != Some(FileType::RegularFile) {
+            return Ok(None);
+        }
+        Ok(Some(DiskSectorStorage {
+            session: RefCell::new(session),
+            inode_index,
+            sector_size,
+            sector_count: file_stat.size / sector_size,
+        }))
+    }
+
+    pub fn inode_index(&self) -> u64 {
+        self.inode_index
+    }
+
+    pub fn into_session(self) -> S {
+        self.session.into_inner()
+    }
+}
+
+impl<S: Session> SectorStorage for DiskSectorStorage<S> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        self.session.borrow().read_regular_file_range(
+            self.inode_index,
+            sector_index * self.sector_size..(sector_index + sector_count) * self.sector_size,
+        )
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let start = sector_index * self.sector_size;
+        self.session
+            .borrow_mut()
+            .write_regular_file_range(self.inode_index, start..start + sectors_data.len() as u64, sectors_data)
+    }
+
+    fn read_only(&self) -> bool {
+        self.session.borrow().read_only()
+    }
+}
@@ -0,0 +1,341 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use base64::Engine;
+use serde_json::Value;
+use sha2::{Digest as _, Sha256, Sha512};
+
+use super::{
+    crypt::Xts,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const MAGIC: &[u8] = b"LUKS\xba\xbe";
+const SECONDARY_MAGIC: &[u8] = b"SKUL\xba\xbe";
+const VERSION: u16 = 2;
+const BINARY_HEADER_SIZE: u64 = 4096;
+const HEADER_OFFSETS: [u64; 10] = [0, 0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000];
+const KEYSLOT_AREA_SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub offset: u64,       // in bytes
+    pub size: Option<u64>, // in bytes, None for the rest of the device
+    pub iv_tweak: u64,
+    pub encryption: String,
+    pub sector_size: u64,
+}
+
+#[derive(Clone, Debug)]
+enum Kdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: Vec<u8>,
+    },
+    Argon2 {
+        algorithm: argon2::Algorithm,
+        time: u32,
+        memory: u32, // in KiB
+        cpus: u32,
+        salt: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Keyslot {
+    key_size: usize,
+    af_stripes: usize,
+    af_hash: String,
+    area_offset: u64,
+    area_encryption: String,
+    area_key_size: usize,
+    kdf: Kdf,
+}
+
+#[derive(Clone, Debug)]
+struct Digest {
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub uuid: String,
+    pub label: String,
+    keyslots: BTreeMap<String, Keyslot>,
+    segment_id: String,
+    segment: Segment,
+    digests: Vec<Digest>,
+}
+
+fn pbkdf2(hash: &str, password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) -> Option<()> {
+    match hash {
+        "sha256" => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, output),
+        "sha512" => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, output),
+        _ => {
+            log::warn!("Unsupported LUKS2 hash {}", hash);
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn diffuse<D: sha2::Digest>(data: &mut [u8]) {
+    for (index, block) in data.chunks_mut(<D as sha2::Digest>::output_size()).enumerate() {
+        let hash = D::new().chain_update((index as u32).to_be_bytes()).chain_update(&block).finalize();
+        block.copy_from_slice(&hash[..block.len()]);
+    }
+}
+
+// anti-forensic merge of the key material stripes
+fn af_merge(split_key: &[u8], key_size: usize, stripes: usize, hash: &str) -> Option<Vec<u8>> {
+    let mut key = vec![0; key_size];
+    for (stripe_index, stripe) in split_key.chunks(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(byte, stripe_byte)| *byte ^= stripe_byte);
+        if stripe_index + 1 < stripes {
+            match hash {
+                "sha256" => diffuse::<Sha256>(&mut key),
+                "sha512" => diffuse::<Sha512>(&mut key),
+                _ => {
+                    log::warn!("Unsupported LUKS2 anti-forensic hash {}", hash);
+                    return None;
+                }
+            }
+        }
+    }
+    Some(key)
+}
+
+fn json_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(string) => string.parse().ok(),
+        Value::Number(number) => number.as_u64(),
+        _ => None,
+    }
+}
+
+fn json_u32(value: &Value) -> Option<u32> {
+    json_u64(value)?.try_into().ok()
+}
+
+fn json_base64(value: &Value) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD.decode(value.as_str()?).ok()
+}
+
+fn json_string_list(value: &Value) -> Option<Vec<String>> {
+    value.as_array()?.iter().map(|value| value.as_str().map(ToString::to_string)).collect()
+}
+
+fn c_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data.split(|&byte| byte == 0).next().unwrap()).into_owned()
+}
+
+impl Kdf {
+    fn of_json(kdf: &Value) -> Option<Self> {
+        match kdf["type"].as_str()? {
+            "pbkdf2" => Some(Kdf::Pbkdf2 {
+                hash: kdf["hash"].as_str()?.to_string(),
+                iterations: json_u32(&kdf["iterations"])?,
+                salt: json_base64(&kdf["salt"])?,
+            }),
+            kdf_type @ ("argon2i" | "argon2id") => Some(Kdf::Argon2 {
+                algorithm: if kdf_type == "argon2i" {
+                    argon2::Algorithm::Argon2i
+                } else {
+                    argon2::Algorithm::Argon2id
+                },
+                time: json_u32(&kdf["time"])?,
+                memory: json_u32(&kdf["memory"])?,
+                cpus: json_u32(&kdf["cpus"])?,
+                salt: json_base64(&kdf["salt"])?,
+            }),
+            kdf_type => {
+                log::warn!("Unsupported LUKS2 KDF {}", kdf_type);
+                None
+            }
+        }
+    }
+
+    fn derive_key(&self, passphrase: &[u8], key_size: usize) -> Option<Vec<u8>> {
+        let mut key = vec![0; key_size];
+        match self {
+            Kdf::Pbkdf2 { hash, iterations, salt } => pbkdf2(hash, passphrase, salt, *iterations, &mut key)?,
+            Kdf::Argon2 {
+                algorithm,
+                time,
+                memory,
+                cpus,
+                salt,
+            } => {
+                let params = argon2::Params::new(*memory, *time, *cpus, Some(key_size)).ok()?;
+                argon2::Argon2::new(*algorithm, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key)
+                    .ok()?;
+            }
+        }
+        Some(key)
+    }
+}
+
+impl Keyslot {
+    fn of_json(keyslot: &Value) -> Option<Self> {
+        if keyslot["type"].as_str()? != "luks2" || keyslot["af"]["type"].as_str()? != "luks1" || keyslot["area"]["type"].as_str()? != "raw" {
+            return None;
+        }
+        Some(Keyslot {
+            key_size: json_u64(&keyslot["key_size"])? as usize,
+            af_stripes: json_u64(&keyslot["af"]["stripes"])? as usize,
+            af_hash: keyslot["af"]["hash"].as_str()?.to_string(),
+            area_offset: json_u64(&keyslot["area"]["offset"])?,
+            area_encryption: keyslot["area"]["encryption"].as_str()?.to_string(),
+            area_key_size: json_u64(&keyslot["area"]["key_size"])? as usize,
+            kdf: Kdf::of_json(&keyslot["kdf"])?,
+        })
+    }
+}
+
+impl Digest {
+    fn of_json(digest: &Value) -> Option<Self> {
+        if digest["type"].as_str()? != "pbkdf2" {
+            return None;
+        }
+        Some(Digest {
+            keyslots: json_string_list(&digest["keyslots"])?,
+            segments: json_string_list(&digest["segments"])?,
+            hash: digest["hash"].as_str()?.to_string(),
+            iterations: json_u32(&digest["iterations"])?,
+            salt: json_base64(&digest["salt"])?,
+            digest: json_base64(&digest["digest"])?,
+        })
+    }
+
+    fn verify(&self, key: &[u8]) -> bool {
+        let mut digest = vec![0; self.digest.len()];
+        pbkdf2(&self.hash, key, &self.salt, self.iterations, &mut digest).is_some() && digest == self.digest
+    }
+}
+
+impl Segment {
+    fn of_json(segment: &Value) -> Option<Self> {
+        if segment["type"].as_str()? != "crypt" {
+            return None;
+        }
+        Some(Segment {
+            offset: json_u64(&segment["offset"])?,
+            size: match segment["size"].as_str()? {
+                "dynamic" => None,
+                _ => Some(json_u64(&segment["size"])?),
+            },
+            iv_tweak: json_u64(&segment["iv_tweak"])?,
+            encryption: segment["encryption"].as_str()?.to_string(),
+            sector_size: json_u64(&segment["sector_size"]).filter(|sector_size| sector_size.is_power_of_two() && (512..=4096).contains(sector_size))?,
+        })
+    }
+}
+
+impl Header {
+    fn of_json(binary_header_data: &[u8], json_data: &[u8]) -> Option<Self> {
+        let metadata: Value = serde_json::from_slice(json_data.split(|&byte| byte == 0).next().unwrap()).ok()?;
+        let (segment_id, segment) = metadata["segments"]
+            .as_object()?
+            .iter()
+            .find_map(|(segment_id, segment)| Some((segment_id.clone(), Segment::of_json(segment)?)))?;
+        Some(Header {
+            uuid: c_string(&binary_header_data[168..208]),
+            label: c_string(&binary_header_data[24..72]),
+            keyslots: metadata["keyslots"]
+                .as_object()?
+                .iter()
+                .filter_map(|(keyslot_id, keyslot)| Some((keyslot_id.clone(), Keyslot::of_json(keyslot)?)))
+                .collect(),
+            segment_id,
+            segment,
+            digests: metadata["digests"].as_object()?.values().filter_map(Digest::of_json).collect(),
+        })
+    }
+
+    pub fn segment(&self) -> &Segment {
+        &self.segment
+    }
+
+    fn unlock_keyslot<SS: SectorStorage>(&self, sector_storage: &SS, keyslot: &Keyslot, passphrase: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if keyslot.area_encryption != "aes-xts-plain64" {
+            log::warn!("Unsupported LUKS2 keyslot encryption {}", keyslot.area_encryption);
+            return Ok(None);
+        }
+        let Some(xts) = keyslot.kdf.derive_key(passphrase, keyslot.area_key_size).as_deref().and_then(Xts::new) else {
+            return Ok(None);
+        };
+        let split_key_size = keyslot.key_size * keyslot.af_stripes;
+        let mut split_key = sector_storage.read_bytes(keyslot.area_offset, split_key_size.next_multiple_of(KEYSLOT_AREA_SECTOR_SIZE) as u64)?;
+        for (sector_number, sector_data) in split_key.chunks_mut(KEYSLOT_AREA_SECTOR_SIZE).enumerate() {
+            xts.decrypt_sector(sector_number as u64, sector_data);
+        }
+        split_key.truncate(split_key_size);
+        Ok(af_merge(&split_key, keyslot.key_size, keyslot.af_stripes, &keyslot.af_hash))
+    }
+
+    // returns the volume key if the passphrase opens one of the keyslots
+    pub fn unlock<SS: SectorStorage>(&self, sector_storage: &SS, passphrase: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        for (keyslot_id, keyslot) in &self.keyslots {
+            let Some(digest) = self
+                .digests
+                .iter()
+                .find(|digest| digest.keyslots.contains(keyslot_id) && digest.segments.contains(&self.segment_id))
+            else {
+                continue;
+            };
+            if let Some(key) = self.unlock_keyslot(sector_storage, keyslot, passphrase)? {
+                if digest.verify(&key) {
+                    return Ok(Some(key));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub fn read_header<SS: SectorStorage>(sector_storage: &SS) -> Result<Option<Header>, StorageError> {
+    let mut best_header_data: Option<(u64, Vec<u8>)> = None; // seqid, header data
+    for header_offset in HEADER_OFFSETS {
+        if header_offset + BINARY_HEADER_SIZE > sector_storage.len() {
+            break;
+        }
+        let binary_header_data = sector_storage.read_bytes(header_offset, BINARY_HEADER_SIZE)?;
+        let magic = if header_offset == 0 { MAGIC } else { SECONDARY_MAGIC };
+        if &binary_header_data[0..6] != magic || u16::from_be_bytes(binary_header_data[6..8].try_into().unwrap()) != VERSION {
+            continue;
+        }
+        let header_size = u64::from_be_bytes(binary_header_data[8..16].try_into().unwrap());
+        let seqid = u64::from_be_bytes(binary_header_data[16..24].try_into().unwrap());
+        if header_size <= BINARY_HEADER_SIZE || header_offset + header_size > sector_storage.len() || c_string(&binary_header_data[72..104]) != "sha256" {
+            continue;
+        }
+        let mut header_data = sector_storage.read_bytes(header_offset, header_size)?;
+        let checksum = header_data[448..448 + 32].to_vec();
+        header_data[448..512].fill(0);
+        if Sha256::digest(&header_data)[..] != checksum[..] {
+            log::warn!("LUKS2 header at {:#x} has a bad checksum", header_offset);
+            continue;
+        }
+        if !matches!(best_header_data, Some((best_seqid, _)) if best_seqid >= seqid) {
+            best_header_data = Some((seqid, header_data));
+        }
+    }
+    let Some((_, header_data)) = best_header_data else {
+        return Ok(None);
+    };
+    let header = Header::of_json(&header_data[..BINARY_HEADER_SIZE as usize], &header_data[BINARY_HEADER_SIZE as usize..]);
+    if header.is_none() {
+        log::warn!("Invalid or unsupported LUKS2 metadata");
+    }
+    Ok(header)
+}
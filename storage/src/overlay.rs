@@ -0,0 +1,212 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cell::RefCell, fmt::Debug};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+#[derive(Default)]
+struct Delta {
+    slots: BTreeMap<u64, u64>, // sector index -> delta sector index
+    slot_count: u64,
+    frozen_slot_count: u64, // delta sectors below this are shared with snapshots and never overwritten in place
+    snapshots: BTreeMap<String, BTreeMap<u64, u64>>,
+}
+
+pub struct OverlaySectorStorage<SS: SectorStorage, DSS: SectorStorage> {
+    base_sector_storage: SS,
+    delta_sector_storage: DSS,
+    delta: RefCell<Delta>,
+}
+
+impl<SS: SectorStorage + Debug, DSS: SectorStorage + Debug> Debug for OverlaySectorStorage<SS, DSS> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let delta = self.delta.borrow();
+        f.debug_struct("OverlaySectorStorage")
+            .field("base_sector_storage", &self.base_sector_storage)
+            .field("delta_sector_storage", &self.delta_sector_storage)
+            .field("len", &delta.slots.len())
+            .field("slot_count", &delta.slot_count)
+            .field("snapshots", &delta.snapshots.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<SS: SectorStorage, DSS: SectorStorage> OverlaySectorStorage<SS, DSS> {
+    pub fn new(base_sector_storage: SS, delta_sector_storage: DSS) -> Self {
+        assert_eq!(base_sector_storage.sector_size(), delta_sector_storage.sector_size());
+        OverlaySectorStorage {
+            base_sector_storage,
+            delta_sector_storage,
+            delta: RefCell::new(Delta::default()),
+        }
+    }
+
+    pub fn base_sector_storage(&self) -> &SS {
+        &self.base_sector_storage
+    }
+
+    pub fn changed_sector_count(&self) -> u64 {
+        self.delta.borrow().slots.len() as u64
+    }
+
+    pub fn snapshot_names(&self) -> Vec<String> {
+        self.delta.borrow().snapshots.keys().cloned().collect()
+    }
+
+    pub fn take_snapshot(&self, name: &str) {
+        let mut delta = self.delta.borrow_mut();
+        let slots = delta.slots.clone();
+        delta.snapshots.insert(name.to_string(), slots);
+        delta.frozen_slot_count = delta.slot_count;
+    }
+
+    pub fn restore_snapshot(&self, name: &str) -> bool {
+        let mut delta = self.delta.borrow_mut();
+        let Some(slots) = delta.snapshots.get(name).cloned() else {
+            return false;
+        };
+        delta.slots = slots;
+        delta.frozen_slot_count = delta.slot_count;
+        true
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> bool {
+        let mut delta = self.delta.borrow_mut();
+        if delta.snapshots.remove(name).is_none() {
+            return false;
+        }
+        if delta.snapshots.is_empty() {
+            delta.frozen_slot_count = 0;
+        }
+        true
+    }
+
+    // drops the changes and all snapshots
+    pub fn discard_changes(&self) {
+        *self.delta.borrow_mut() = Delta::default();
+    }
+
+    // writes the changes to the base and then discards the delta and all snapshots
+    pub fn commit(&self) -> Result<(), StorageError> {
+        let mut delta = self.delta.borrow_mut();
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (&sector_index, &slot) in delta.slots.iter() {
+            let sector_data = self.delta_sector_storage.read_sector(slot)?;
+            match runs.last_mut() {
+                Some((run_start, run_data)) if *run_start + run_data.len() as u64 / self.sector_size() == sector_index => {
+                    run_data.extend_from_slice(&sector_data);
+                }
+                _ => runs.push((sector_index, sector_data)),
+            }
+        }
+        for (run_start, run_data) in runs {
+            self.base_sector_storage.write_sectors(run_start, &run_data)?;
+        }
+        self.base_sector_storage.flush()?;
+        *delta = Delta::default();
+        Ok(())
+    }
+}
+
+impl<SS: SectorStorage, DSS: SectorStorage> SectorStorage for OverlaySectorStorage<SS, DSS> {
+    fn sector_size(&self) -> u64 {
+        self.base_sector_storage.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.base_sector_storage.sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        let delta = self.delta.borrow();
+        let mut sectors_data = Vec::with_capacity((sector_count * self.sector_size()) as usize);
+        let end_sector_index = sector_index + sector_count;
+        let mut current_sector_index = sector_index;
+        while current_sector_index < end_sector_index {
+            match delta.slots.get(&current_sector_index) {
+                Some(&slot) => {
+                    let run_len = (current_sector_index..end_sector_index)
+                        .take_while(|sector_index| delta.slots.get(sector_index) == Some(&(slot + sector_index - current_sector_index)))
+                        .count() as u64;
+                    sectors_data.extend_from_slice(&self.delta_sector_storage.read_sectors(slot, run_len)?);
+                    current_sector_index += run_len;
+                }
+                None => {
+                    let unchanged_end_sector_index = delta
+                        .slots
+                        .range(current_sector_index..end_sector_index)
+                        .next()
+                        .map_or(end_sector_index, |(&sector_index, _)| sector_index);
+                    sectors_data.extend_from_slice(
+                        &self
+                            .base_sector_storage
+                            .read_sectors(current_sector_index, unchanged_end_sector_index - current_sector_index)?,
+                    );
+                    current_sector_index = unchanged_end_sector_index;
+                }
+            }
+        }
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size() == 0);
+        let sector_count = sectors_data.len() as u64 / self.sector_size();
+        if sector_index + sector_count > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut delta = self.delta.borrow_mut();
+        let mut next_slot = delta.slot_count;
+        let slots: Vec<u64> = (sector_index..sector_index + sector_count)
+            .map(|sector_index| match delta.slots.get(&sector_index) {
+                Some(&slot) if slot >= delta.frozen_slot_count => slot,
+                _ => {
+                    next_slot += 1;
+                    next_slot - 1
+                }
+            })
+            .collect();
+        if next_slot > self.delta_sector_storage.sector_count() {
+            return Err(StorageError::NoSpace);
+        }
+        let mut run_start = 0;
+        for index in 1..=slots.len() {
+            if index == slots.len() || slots[index] != slots[index - 1] + 1 {
+                self.delta_sector_storage.write_sectors(
+                    slots[run_start],
+                    &sectors_data[(run_start as u64 * self.sector_size()) as usize..(index as u64 * self.sector_size()) as usize],
+                )?;
+                run_start = index;
+            }
+        }
+        for (index, slot) in slots.into_iter().enumerate() {
+            delta.slots.insert(sector_index + index as u64, slot);
+        }
+        delta.slot_count = next_slot;
+        Ok(())
+    }
+
+    // the base is only written on commit
+    fn flush(&self) -> Result<(), StorageError> {
+        self.delta_sector_storage.flush()
+    }
+
+    // a read-only base is fine as long as the delta is writable
+    fn read_only(&self) -> bool {
+        self.delta_sector_storage.read_only()
+    }
+}
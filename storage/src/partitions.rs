@@ -0,0 +1,564 @@
+use alloc::{string::String, vec, vec::Vec};
+use core::ops::Range;
+
+use super::{
+    guid::{self, Guid},
+    sector_storage::{SectorStorage, StorageError},
+};
+
+pub const FLAG_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+pub const FLAG_READ_ONLY: u64 = 1 << 60;
+
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_STATUS_ACTIVE: u8 = 0x80;
+const MAX_LOGICAL_PARTITION_COUNT: usize = 128;
+const GPT_PARTITION_ENTRY_COUNT: u64 = 128;
+const GPT_PARTITION_ENTRY_SIZE: u64 = 128;
+const GPT_MAX_NAME_LEN: usize = 36; // in UTF-16 code units
+const GPT_ALIGNMENT: u64 = 1 << 20; // in bytes, for the partitions created
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct Partition {
+    pub type_id: Guid,
+    pub id: Guid,
+    pub starting_sector: u64,
+    pub ending_sector: u64, // inclusive
+    pub flags: u64,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PartitionTableKind {
+    Mbr,
+    PrimaryGpt,
+    BackupGpt, // the primary GPT is corrupt
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct PartitionTable {
+    pub id: Guid,
+    pub kind: PartitionTableKind,
+    pub partitions: Vec<Partition>,
+}
+
+// the MBR comes first, a GPT is only looked for behind a protective or hybrid MBR or when there is no MBR at all
+pub fn read_partition_table<SS: SectorStorage>(sector_storage: &SS) -> Result<Option<PartitionTable>, StorageError> {
+    let mbr = read_mbr_entries(sector_storage, 0)?;
+    let protective_mbr = mbr
+        .as_ref()
+        .is_some_and(|(entries, _)| entries.iter().any(|entry| entry.partition_type == MBR_TYPE_PROTECTIVE));
+    if protective_mbr || mbr.is_none() {
+        if let Some(partition_table) = read_gpt_partition_table(sector_storage, protective_mbr)? {
+            return Ok(Some(partition_table));
+        }
+    }
+    match mbr {
+        Some((entries, disk_signature)) => read_mbr_partition_table(sector_storage, entries, disk_signature),
+        None => Ok(None),
+    }
+}
+
+// the backup GPT alone is only trusted behind a protective or hybrid MBR, the last sector may hold anything otherwise
+fn read_gpt_partition_table<SS: SectorStorage>(sector_storage: &SS, protective_mbr: bool) -> Result<Option<PartitionTable>, StorageError> {
+    if sector_storage.sector_count() <= 1 {
+        return Ok(None);
+    }
+    let last_sector_index = sector_storage.sector_count() - 1;
+    if let Some((id, partitions)) = read_gpt(sector_storage, 1)? {
+        return Ok(Some(PartitionTable {
+            id,
+            kind: PartitionTableKind::PrimaryGpt,
+            partitions,
+        }));
+    }
+    if !protective_mbr {
+        return Ok(None);
+    }
+    match read_gpt(sector_storage, last_sector_index)? {
+        Some((id, partitions)) => {
+            log::warn!("Primary GPT is corrupt, using the backup GPT");
+            Ok(Some(PartitionTable {
+                id,
+                kind: PartitionTableKind::BackupGpt,
+                partitions,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_gpt<SS: SectorStorage>(sector_storage: &SS, header_sector_index: u64) -> Result<Option<(Guid, Vec<Partition>)>, StorageError> {
+    let partition_table_header_data = sector_storage.read_sector(header_sector_index)?;
+    if &partition_table_header_data[0..8] != b"EFI PART" {
+        return Ok(None);
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(partition_table_header_data[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(partition_table_header_data[offset..offset + 8].try_into().unwrap());
+    let header_size = u32_at(12) as usize;
+    if !(92..=partition_table_header_data.len()).contains(&header_size) {
+        log::warn!("GPT header at sector {} has an invalid size {}", header_sector_index, header_size);
+        return Ok(None);
+    }
+    let mut checked_header_data = partition_table_header_data[..header_size].to_vec();
+    checked_header_data[16..20].fill(0);
+    if crc32(&checked_header_data) != u32_at(16) {
+        log::warn!("GPT header at sector {} has a bad checksum", header_sector_index);
+        return Ok(None);
+    }
+    let first_usable_sector = u64_at(40);
+    let last_usable_sector = u64_at(48);
+    let partition_entries_starting_sector = u64_at(72);
+    let partition_count = u32_at(80) as u64;
+    let partition_entry_size = u32_at(84) as u64;
+    let partition_entries_sector_count = (partition_count * partition_entry_size).div_ceil(sector_storage.sector_size());
+    if u64_at(24) != header_sector_index
+        || first_usable_sector > last_usable_sector
+        || last_usable_sector >= sector_storage.sector_count()
+        || partition_entry_size < 128
+        || sector_storage.sector_size() % partition_entry_size != 0
+        || partition_entries_starting_sector.saturating_add(partition_entries_sector_count) > sector_storage.sector_count()
+    {
+        log::warn!("GPT header at sector {} has invalid fields", header_sector_index);
+        return Ok(None);
+    }
+    let partition_entries_data = sector_storage.read_sectors(partition_entries_starting_sector, partition_entries_sector_count)?;
+    let partition_entries_data = &partition_entries_data[..(partition_count * partition_entry_size) as usize];
+    if crc32(partition_entries_data) != u32_at(88) {
+        log::warn!("GPT partition entries of the header at sector {} have a bad checksum", header_sector_index);
+        return Ok(None);
+    }
+    let mut partitions = Vec::new();
+    for partition_entry_data in partition_entries_data.chunks(partition_entry_size as usize) {
+        // a name filling the whole entry has no terminating null
+        let name = char::decode_utf16(
+            bytemuck::cast_slice::<u8, u16>(&partition_entry_data[56..])
+                .iter()
+                .copied()
+                .take_while(|&unit| unit != 0),
+        );
+        let partition = Partition {
+            type_id: Guid::from_bytes(bytemuck::cast_slice(&partition_entry_data[0..16]).try_into().unwrap()),
+            id: Guid::from_bytes(bytemuck::cast_slice(&partition_entry_data[16..32]).try_into().unwrap()),
+            starting_sector: u64::from_le_bytes(bytemuck::cast_slice(&partition_entry_data[32..40]).try_into().unwrap()),
+            ending_sector: u64::from_le_bytes(bytemuck::cast_slice(&partition_entry_data[40..48]).try_into().unwrap()),
+            flags: u64::from_le_bytes(bytemuck::cast_slice(&partition_entry_data[48..56]).try_into().unwrap()),
+            name: name.collect::<Result<String, _>>().ok().filter(|name| !name.is_empty()),
+        };
+        if partition.id == guid::ZERO {
+            continue;
+        }
+        if partition.starting_sector < first_usable_sector
+            || partition.starting_sector > partition.ending_sector
+            || partition.ending_sector > last_usable_sector
+        {
+            log::warn!("GPT partition {:?} lies outside the usable sectors", partition.id);
+            return Ok(None);
+        }
+        partitions.push(partition);
+    }
+    Ok(Some((Guid::from_bytes(partition_table_header_data[56..72].try_into().unwrap()), partitions)))
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit_index = 0;
+        while bit_index < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit_index += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data
+        .iter()
+        .fold(!0, |crc, &byte| (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize])
+}
+
+struct MbrEntry {
+    status: u8,
+    partition_type: u8,
+    starting_sector: u64, // relative to the MBR or EBR, or to the extended partition for links to the next EBR
+    sector_count: u64,
+}
+
+fn read_mbr_entries<SS: SectorStorage>(sector_storage: &SS, sector_index: u64) -> Result<Option<([MbrEntry; 4], u32)>, StorageError> {
+    if sector_storage.sector_size() < 512 || sector_index >= sector_storage.sector_count() {
+        return Ok(None);
+    }
+    let sector_data = sector_storage.read_sector(sector_index)?;
+    if sector_data[510..512] != [0x55, 0xAA] {
+        return Ok(None);
+    }
+    let entries = core::array::from_fn(|entry_index| {
+        let entry_data = &sector_data[446 + entry_index * 16..446 + (entry_index + 1) * 16];
+        MbrEntry {
+            status: entry_data[0],
+            partition_type: entry_data[4],
+            starting_sector: u32::from_le_bytes(entry_data[8..12].try_into().unwrap()) as u64,
+            sector_count: u32::from_le_bytes(entry_data[12..16].try_into().unwrap()) as u64,
+        }
+    });
+    let disk_signature = u32::from_le_bytes(sector_data[440..444].try_into().unwrap());
+    Ok(Some((entries, disk_signature)))
+}
+
+fn is_extended(partition_type: u8) -> bool {
+    matches!(partition_type, 0x05 | 0x0F | 0x85)
+}
+
+// other types are kept as a pseudo GUID holding the MBR type in its first byte
+pub fn mbr_type_id(partition_type: u8) -> Guid {
+    match partition_type {
+        0x83 => guid::TYPE_ID_LINUX,
+        0xFD => guid::TYPE_ID_LINUX_RAID,
+        0x82 => guid::TYPE_ID_LINUX_SWAP,
+        0xEF => guid::TYPE_ID_EFI_SYSTEM,
+        0x01 | 0x04 | 0x06 | 0x07 | 0x0B | 0x0C | 0x0E => guid::TYPE_ID_MICROSOFT_BASIC_DATA,
+        _ => {
+            let mut bytes = [0; 16];
+            bytes[0] = partition_type;
+            Guid::from_bytes(bytes)
+        }
+    }
+}
+
+// the Linux PARTUUID of disk signature and partition number, 0 standing for the whole table
+pub fn mbr_partition_id(disk_signature: u32, partition_number: u8) -> Guid {
+    let mut id_bytes = [0; 16];
+    id_bytes[0..4].copy_from_slice(&disk_signature.to_le_bytes());
+    id_bytes[4] = partition_number;
+    Guid::from_bytes(id_bytes)
+}
+
+fn mbr_partition(disk_signature: u32, partition_number: u8, entry: &MbrEntry, starting_sector: u64) -> Partition {
+    Partition {
+        type_id: mbr_type_id(entry.partition_type),
+        id: mbr_partition_id(disk_signature, partition_number),
+        starting_sector,
+        ending_sector: starting_sector + entry.sector_count - 1,
+        flags: if entry.status == MBR_STATUS_ACTIVE { FLAG_LEGACY_BIOS_BOOTABLE } else { 0 },
+        name: None,
+    }
+}
+
+fn read_mbr_partition_table<SS: SectorStorage>(
+    sector_storage: &SS,
+    entries: [MbrEntry; 4],
+    disk_signature: u32,
+) -> Result<Option<PartitionTable>, StorageError> {
+    // rules out boot sectors of unpartitioned file systems
+    if entries.iter().any(|entry| entry.status & !MBR_STATUS_ACTIVE != 0) {
+        return Ok(None);
+    }
+    if entries.iter().any(|entry| entry.partition_type == MBR_TYPE_PROTECTIVE) {
+        log::warn!("Protective MBR without a valid GPT");
+        return Ok(None);
+    }
+    let fits = |starting_sector: u64, entry: &MbrEntry| {
+        let fits = starting_sector + entry.sector_count <= sector_storage.sector_count();
+        if !fits {
+            log::warn!("MBR partition at sector {} exceeds the disk", starting_sector);
+        }
+        fits
+    };
+    let mut partitions = Vec::new();
+    let mut logical_partitions = Vec::new();
+    for (entry_index, entry) in entries.iter().enumerate() {
+        if entry.partition_type == 0 || entry.sector_count == 0 || !fits(entry.starting_sector, entry) {
+            continue;
+        }
+        if !is_extended(entry.partition_type) {
+            partitions.push(mbr_partition(disk_signature, entry_index as u8 + 1, entry, entry.starting_sector));
+            continue;
+        }
+        // the EBRs form a chain, each describing a logical partition and linking to the next EBR
+        let mut ebr_sector_index = entry.starting_sector;
+        for _ in logical_partitions.len()..MAX_LOGICAL_PARTITION_COUNT {
+            let Some(([logical_entry, next_entry, ..], _)) = read_mbr_entries(sector_storage, ebr_sector_index)? else {
+                log::warn!("Invalid EBR at sector {}", ebr_sector_index);
+                break;
+            };
+            let logical_starting_sector = ebr_sector_index + logical_entry.starting_sector;
+            if logical_entry.partition_type != 0 && logical_entry.sector_count != 0 && fits(logical_starting_sector, &logical_entry) {
+                let logical_partition_number = (logical_partitions.len() + 5) as u8;
+                logical_partitions.push(mbr_partition(disk_signature, logical_partition_number, &logical_entry, logical_starting_sector));
+            }
+            if !is_extended(next_entry.partition_type) || next_entry.starting_sector == 0 {
+                break;
+            }
+            ebr_sector_index = entry.starting_sector + next_entry.starting_sector;
+        }
+    }
+    partitions.append(&mut logical_partitions);
+    Ok(Some(PartitionTable {
+        id: mbr_partition_id(disk_signature, 0),
+        kind: PartitionTableKind::Mbr,
+        partitions,
+    }))
+}
+
+// the sectors left for partitions by a GPT written with write_gpt
+pub fn gpt_usable_sectors<SS: SectorStorage>(sector_storage: &SS) -> Range<u64> {
+    let partition_entries_sector_count = (GPT_PARTITION_ENTRY_COUNT * GPT_PARTITION_ENTRY_SIZE).div_ceil(sector_storage.sector_size());
+    2 + partition_entries_sector_count..sector_storage.sector_count().saturating_sub(1 + partition_entries_sector_count)
+}
+
+impl PartitionTable {
+    pub fn new_gpt(id: Guid) -> Self {
+        PartitionTable {
+            id,
+            kind: PartitionTableKind::PrimaryGpt,
+            partitions: Vec::new(),
+        }
+    }
+
+    fn partition_mut(&mut self, id: &Guid) -> Result<&mut Partition, StorageError> {
+        self.partitions.iter_mut().find(|partition| partition.id == *id).ok_or(StorageError::OutOfRange)
+    }
+
+    // the free sectors after the given one, up to the next partition
+    fn free_sectors_from<SS: SectorStorage>(&self, sector_storage: &SS, sector_index: u64) -> Range<u64> {
+        let usable_sectors = gpt_usable_sectors(sector_storage);
+        let free_sectors_end = self
+            .partitions
+            .iter()
+            .map(|partition| partition.starting_sector)
+            .filter(|&starting_sector| starting_sector >= sector_index)
+            .fold(usable_sectors.end, u64::min);
+        sector_index.max(usable_sectors.start)..free_sectors_end
+    }
+
+    // placed in the first aligned gap large enough
+    pub fn create_partition<SS: SectorStorage>(
+        &mut self,
+        sector_storage: &SS,
+        type_id: Guid,
+        id: Guid,
+        sector_count: u64,
+        name: Option<&str>,
+    ) -> Result<&Partition, StorageError> {
+        if sector_count == 0
+            || name.is_some_and(|name| name.encode_utf16().count() > GPT_MAX_NAME_LEN)
+            || self.partitions.iter().any(|partition| partition.id == id)
+        {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.partitions.len() as u64 >= GPT_PARTITION_ENTRY_COUNT {
+            return Err(StorageError::NoSpace);
+        }
+        let alignment = (GPT_ALIGNMENT / sector_storage.sector_size()).max(1);
+        // the gaps start at the usable sectors and after every partition
+        let starting_sector = core::iter::once(0)
+            .chain(self.partitions.iter().map(|partition| partition.ending_sector + 1))
+            .map(|sector_index| self.free_sectors_from(sector_storage, sector_index))
+            .map(|free_sectors| (free_sectors.start.next_multiple_of(alignment), free_sectors.end))
+            .filter(|&(starting_sector, free_sectors_end)| starting_sector + sector_count <= free_sectors_end)
+            .map(|(starting_sector, _)| starting_sector)
+            .min()
+            .ok_or(StorageError::NoSpace)?;
+        self.partitions.push(Partition {
+            type_id,
+            id,
+            starting_sector,
+            ending_sector: starting_sector + sector_count - 1,
+            flags: 0,
+            name: name.map(String::from),
+        });
+        Ok(self.partitions.last().unwrap())
+    }
+
+    pub fn delete_partition(&mut self, id: &Guid) -> Result<Partition, StorageError> {
+        let partition_index = self
+            .partitions
+            .iter()
+            .position(|partition| partition.id == *id)
+            .ok_or(StorageError::OutOfRange)?;
+        Ok(self.partitions.remove(partition_index))
+    }
+
+    // moves the ending sector, keeping the starting one
+    pub fn resize_partition<SS: SectorStorage>(&mut self, sector_storage: &SS, id: &Guid, sector_count: u64) -> Result<(), StorageError> {
+        let starting_sector = self.partition_mut(id)?.starting_sector;
+        if sector_count == 0 {
+            return Err(StorageError::OutOfRange);
+        }
+        if starting_sector + sector_count > self.free_sectors_from(sector_storage, starting_sector + 1).end {
+            return Err(StorageError::NoSpace);
+        }
+        self.partition_mut(id)?.ending_sector = starting_sector + sector_count - 1;
+        Ok(())
+    }
+
+    pub fn rename_partition(&mut self, id: &Guid, name: Option<&str>) -> Result<(), StorageError> {
+        if name.is_some_and(|name| name.encode_utf16().count() > GPT_MAX_NAME_LEN) {
+            return Err(StorageError::OutOfRange);
+        }
+        self.partition_mut(id)?.name = name.map(String::from);
+        Ok(())
+    }
+
+    // the backup GPT goes first so that a torn write leaves one valid copy
+    pub fn write_gpt<SS: SectorStorage>(&self, sector_storage: &SS) -> Result<(), StorageError> {
+        let sector_size = sector_storage.sector_size();
+        let usable_sectors = gpt_usable_sectors(sector_storage);
+        if sector_size < 512 || usable_sectors.is_empty() || self.partitions.len() as u64 > GPT_PARTITION_ENTRY_COUNT {
+            return Err(StorageError::NoSpace);
+        }
+        let mut sorted_partitions: Vec<&Partition> = self.partitions.iter().collect();
+        sorted_partitions.sort_by_key(|partition| partition.starting_sector);
+        let valid = self.partitions.iter().all(|partition| {
+            partition.id != guid::ZERO
+                && partition.type_id != guid::ZERO
+                && usable_sectors.start <= partition.starting_sector
+                && partition.starting_sector <= partition.ending_sector
+                && partition.ending_sector < usable_sectors.end
+                && partition.name.as_ref().is_none_or(|name| name.encode_utf16().count() <= GPT_MAX_NAME_LEN)
+        }) && sorted_partitions
+            .windows(2)
+            .all(|partitions| partitions[0].ending_sector < partitions[1].starting_sector);
+        if !valid {
+            return Err(StorageError::OutOfRange);
+        }
+        let partition_entries_sector_count = usable_sectors.start - 2;
+        let mut partition_entries_data = vec![0; (partition_entries_sector_count * sector_size) as usize];
+        for (partition, partition_entry_data) in self.partitions.iter().zip(partition_entries_data.chunks_mut(GPT_PARTITION_ENTRY_SIZE as usize)) {
+            partition_entry_data[0..16].copy_from_slice(&partition.type_id.to_bytes());
+            partition_entry_data[16..32].copy_from_slice(&partition.id.to_bytes());
+            partition_entry_data[32..40].copy_from_slice(&partition.starting_sector.to_le_bytes());
+            partition_entry_data[40..48].copy_from_slice(&partition.ending_sector.to_le_bytes());
+            partition_entry_data[48..56].copy_from_slice(&partition.flags.to_le_bytes());
+            for (unit_index, unit) in partition.name.iter().flat_map(|name| name.encode_utf16()).enumerate() {
+                partition_entry_data[56 + unit_index * 2..58 + unit_index * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        let partition_entries_crc32 = crc32(&partition_entries_data[..(GPT_PARTITION_ENTRY_COUNT * GPT_PARTITION_ENTRY_SIZE) as usize]);
+        let last_sector_index = sector_storage.sector_count() - 1;
+        let header_data = |header_sector_index: u64, alternate_header_sector_index: u64, partition_entries_starting_sector: u64| {
+            let mut header_data = vec![0; sector_size as usize];
+            header_data[0..8].copy_from_slice(b"EFI PART");
+            header_data[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            header_data[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header_data[24..32].copy_from_slice(&header_sector_index.to_le_bytes());
+            header_data[32..40].copy_from_slice(&alternate_header_sector_index.to_le_bytes());
+            header_data[40..48].copy_from_slice(&usable_sectors.start.to_le_bytes());
+            header_data[48..56].copy_from_slice(&(usable_sectors.end - 1).to_le_bytes());
+            header_data[56..72].copy_from_slice(&self.id.to_bytes());
+            header_data[72..80].copy_from_slice(&partition_entries_starting_sector.to_le_bytes());
+            header_data[80..84].copy_from_slice(&(GPT_PARTITION_ENTRY_COUNT as u32).to_le_bytes());
+            header_data[84..88].copy_from_slice(&(GPT_PARTITION_ENTRY_SIZE as u32).to_le_bytes());
+            header_data[88..92].copy_from_slice(&partition_entries_crc32.to_le_bytes());
+            let header_crc32 = crc32(&header_data[0..92]);
+            header_data[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+            header_data
+        };
+        sector_storage.write_sectors(usable_sectors.end, &partition_entries_data)?;
+        sector_storage.write_sector(last_sector_index, &header_data(last_sector_index, 1, usable_sectors.end))?;
+        sector_storage.flush()?;
+        sector_storage.write_sectors(2, &partition_entries_data)?;
+        sector_storage.write_sector(1, &header_data(1, last_sector_index, 2))?;
+        // a protective MBR covering the whole disk, keeping the boot code
+        let mut mbr_data = sector_storage.read_sector(0)?;
+        mbr_data[440..].fill(0);
+        mbr_data[446..462].copy_from_slice(&[0, 0x00, 0x02, 0x00, MBR_TYPE_PROTECTIVE, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0]);
+        mbr_data[458..462].copy_from_slice(&(last_sector_index.min(u32::MAX as u64) as u32).to_le_bytes());
+        mbr_data[510..512].copy_from_slice(&[0x55, 0xAA]);
+        sector_storage.write_sector(0, &mbr_data)?;
+        sector_storage.flush()
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for (SS, Partition) {
+    fn sector_size(&self) -> u64 {
+        self.0.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.1.ending_sector - self.1.starting_sector + 1
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        let (sector_storage, partition) = self;
+        if sector_index >= self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        sector_storage.read_sector(partition.starting_sector + sector_index)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        let (sector_storage, partition) = self;
+        if sector_index >= self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        sector_storage.write_sector(partition.starting_sector + sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        let (sector_storage, partition) = self;
+        if sector_index + sector_count > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        sector_storage.read_sectors(partition.starting_sector + sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        let (sector_storage, partition) = self;
+        if sector_index + sectors_data.len() as u64 / self.sector_size() > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        sector_storage.write_sectors(partition.starting_sector + sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        let (sector_storage, partition) = self;
+        if sector_index + sectors_data.len() as u64 / self.sector_size() > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        sector_storage.write_sectors_fua(partition.starting_sector + sector_index, sectors_data)
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        let (sector_storage, partition) = self;
+        if sectors.start > sectors.end || sectors.end > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        sector_storage.discard(partition.starting_sector + sectors.start..partition.starting_sector + sectors.end)
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        let (sector_storage, partition) = self;
+        if sectors.start > sectors.end || sectors.end > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        sector_storage.write_zeroes(partition.starting_sector + sectors.start..partition.starting_sector + sectors.end)
+    }
+
+    fn read_only(&self) -> bool {
+        self.0.read_only() || self.1.flags & FLAG_READ_ONLY != 0
+    }
+}
@@ -0,0 +1,223 @@
+use alloc::{format, string::String, vec::Vec};
+
+use super::{
+    raid,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContentType {
+    Ext2,
+    Ext3,
+    Ext4,
+    Fat12,
+    Fat16,
+    Fat32,
+    Iso9660,
+    Swap,
+    Luks,
+    MdRaid,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Content {
+    pub content_type: ContentType,
+    pub label: Option<String>,
+    pub uuid: Option<String>, // formatted as blkid does for the type
+}
+
+type Probe = fn(&dyn SectorStorage) -> Result<Option<Content>, StorageError>;
+
+// the containers first, as they may carry leftovers of a previous filesystem, and FAT last for its weak signature
+const PROBES: [Probe; 6] = [probe_luks, probe_md_raid, probe_ext, probe_iso9660, probe_swap, probe_fat];
+
+pub fn probe<SS: SectorStorage>(sector_storage: &SS) -> Result<Option<Content>, StorageError> {
+    for probe in PROBES {
+        if let Some(content) = probe(sector_storage)? {
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+fn read_bytes_if_present(sector_storage: &dyn SectorStorage, start: u64, len: u64) -> Result<Option<Vec<u8>>, StorageError> {
+    if start + len > sector_storage.len() {
+        return Ok(None);
+    }
+    sector_storage.read_bytes(start, len).map(Some)
+}
+
+// trailing spaces and nulls are padding, an empty string is none
+fn padded_string(data: &[u8]) -> Option<String> {
+    let string = String::from_utf8_lossy(data);
+    let string = string.trim_end_matches([' ', '\0']);
+    (!string.is_empty()).then(|| String::from(string))
+}
+
+fn uuid(uuid_data: &[u8]) -> String {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex(&uuid_data[0..4]),
+        hex(&uuid_data[4..6]),
+        hex(&uuid_data[6..8]),
+        hex(&uuid_data[8..10]),
+        hex(&uuid_data[10..16])
+    )
+}
+
+// LUKS1 and LUKS2 share the binary header layout of the fields needed
+fn probe_luks(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    let Some(header_data) = read_bytes_if_present(sector_storage, 0, 512)? else {
+        return Ok(None);
+    };
+    if &header_data[0..6] != b"LUKS\xba\xbe" {
+        return Ok(None);
+    }
+    let version = u16::from_be_bytes(header_data[6..8].try_into().unwrap());
+    Ok(Some(Content {
+        content_type: ContentType::Luks,
+        label: if version == 2 { padded_string(&header_data[24..72]) } else { None },
+        uuid: padded_string(&header_data[168..208]),
+    }))
+}
+
+fn probe_md_raid(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    Ok(raid::read_superblock(&sector_storage)?.map(|superblock| Content {
+        content_type: ContentType::MdRaid,
+        label: padded_string(superblock.name.as_bytes()),
+        uuid: Some(uuid(&superblock.array_id)),
+    }))
+}
+
+fn probe_ext(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    const FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x4;
+    const FEATURE_INCOMPAT_EXT4: u32 = 0x40 | 0x80 | 0x100 | 0x200 | 0x400 | 0x2000 | 0x8000 | 0x10000; // extents, 64bit, mmp, flex_bg, ea_inode, csum_seed, large_dir, inline_data
+    const FEATURE_RO_COMPAT_EXT4: u32 = 0x8 | 0x10 | 0x20 | 0x40 | 0x400; // huge_file, gdt_csum, dir_nlink, extra_isize, metadata_csum
+    let Some(superblock_data) = read_bytes_if_present(sector_storage, 1024, 1024)? else {
+        return Ok(None);
+    };
+    if u16::from_le_bytes(superblock_data[56..58].try_into().unwrap()) != 0xEF53 {
+        return Ok(None);
+    }
+    let features_compat = u32::from_le_bytes(superblock_data[92..96].try_into().unwrap());
+    let features_incompat = u32::from_le_bytes(superblock_data[96..100].try_into().unwrap());
+    let features_ro_compat = u32::from_le_bytes(superblock_data[100..104].try_into().unwrap());
+    let content_type = if features_incompat & FEATURE_INCOMPAT_EXT4 != 0 || features_ro_compat & FEATURE_RO_COMPAT_EXT4 != 0 {
+        ContentType::Ext4
+    } else if features_compat & FEATURE_COMPAT_HAS_JOURNAL != 0 {
+        ContentType::Ext3
+    } else {
+        ContentType::Ext2
+    };
+    Ok(Some(Content {
+        content_type,
+        label: padded_string(&superblock_data[120..136]),
+        uuid: Some(uuid(&superblock_data[104..120])),
+    }))
+}
+
+// the primary volume descriptor, with the creation time standing in for a UUID
+fn probe_iso9660(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    for volume_descriptor_index in 0..16 {
+        let Some(volume_descriptor_data) = read_bytes_if_present(sector_storage, (16 + volume_descriptor_index) * 2048, 2048)? else {
+            return Ok(None);
+        };
+        if &volume_descriptor_data[1..6] != b"CD001" || volume_descriptor_data[0] == 255 {
+            return Ok(None);
+        }
+        if volume_descriptor_data[0] != 1 {
+            continue;
+        }
+        let creation_time = &volume_descriptor_data[813..829];
+        let uuid = creation_time.iter().all(u8::is_ascii_digit).then(|| {
+            let creation_time = core::str::from_utf8(creation_time).unwrap();
+            format!(
+                "{}-{}-{}-{}-{}-{}-{}",
+                &creation_time[0..4],
+                &creation_time[4..6],
+                &creation_time[6..8],
+                &creation_time[8..10],
+                &creation_time[10..12],
+                &creation_time[12..14],
+                &creation_time[14..16]
+            )
+        });
+        return Ok(Some(Content {
+            content_type: ContentType::Iso9660,
+            label: padded_string(&volume_descriptor_data[40..72]),
+            uuid,
+        }));
+    }
+    Ok(None)
+}
+
+// the signature ends the first page, whose size depends on the architecture that created the swap area
+fn probe_swap(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    for page_size in [4096, 8192, 16384, 65536] {
+        let Some(signature_data) = read_bytes_if_present(sector_storage, page_size - 10, 10)? else {
+            return Ok(None);
+        };
+        if &signature_data[..] == b"SWAPSPACE2" {
+            let header_data = sector_storage.read_bytes(1024, 44)?;
+            return Ok(Some(Content {
+                content_type: ContentType::Swap,
+                label: padded_string(&header_data[28..44]),
+                uuid: Some(uuid(&header_data[12..28])),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+// the BIOS parameter block has no magic, so its fields are checked for sane values
+fn probe_fat(sector_storage: &dyn SectorStorage) -> Result<Option<Content>, StorageError> {
+    let Some(boot_sector_data) = read_bytes_if_present(sector_storage, 0, 512)? else {
+        return Ok(None);
+    };
+    let u16_at = |offset: usize| u16::from_le_bytes(boot_sector_data[offset..offset + 2].try_into().unwrap()) as u64;
+    let u32_at = |offset: usize| u32::from_le_bytes(boot_sector_data[offset..offset + 4].try_into().unwrap()) as u64;
+    let bytes_per_sector = u16_at(11);
+    let sectors_per_cluster = boot_sector_data[13] as u64;
+    let reserved_sector_count = u16_at(14);
+    let fat_count = boot_sector_data[16] as u64;
+    if boot_sector_data[510..512] != [0x55, 0xAA]
+        || !matches!(boot_sector_data[0], 0xEB | 0xE9)
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sector_count == 0
+        || !(1..=2).contains(&fat_count)
+    {
+        return Ok(None);
+    }
+    let root_dir_sector_count = (u16_at(17) * 32).div_ceil(bytes_per_sector);
+    let sectors_per_fat = if u16_at(22) != 0 { u16_at(22) } else { u32_at(36) };
+    let total_sector_count = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+    let Some(data_sector_count) = total_sector_count.checked_sub(reserved_sector_count + fat_count * sectors_per_fat + root_dir_sector_count) else {
+        return Ok(None);
+    };
+    let cluster_count = data_sector_count / sectors_per_cluster;
+    let (content_type, extended_boot_record_offset) = if u16_at(22) == 0 {
+        (ContentType::Fat32, 64)
+    } else if cluster_count < 4085 {
+        (ContentType::Fat12, 36)
+    } else {
+        (ContentType::Fat16, 36)
+    };
+    // the extended boot signature tells whether the serial number and label are present
+    if !matches!(boot_sector_data[extended_boot_record_offset + 2], 0x28 | 0x29) {
+        return Ok(Some(Content {
+            content_type,
+            label: None,
+            uuid: None,
+        }));
+    }
+    let serial_number = u32_at(extended_boot_record_offset + 3);
+    let label = padded_string(&boot_sector_data[extended_boot_record_offset + 7..extended_boot_record_offset + 18]).filter(|label| label != "NO NAME");
+    Ok(Some(Content {
+        content_type,
+        label,
+        uuid: Some(format!("{:04X}-{:04X}", serial_number >> 16, serial_number & 0xFFFF)),
+    }))
+}
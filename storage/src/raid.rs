@@ -0,0 +1,523 @@
+use alloc::{string::String, vec::Vec};
+use core::{
+    cell::Cell,
+    fmt::{Debug, Formatter},
+    ops::Range,
+};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+const MAGIC: u32 = 0xa92b4efc;
+const MAJOR_VERSION: u32 = 1;
+const SUPERBLOCK_OFFSET: u64 = 4096; // superblock 1.2
+const SUPERBLOCK_SIZE: u64 = 4096;
+const MD_SECTOR_SIZE: u64 = 512;
+const FEATURE_RECOVERY_OFFSET: u32 = 1 << 1;
+const FEATURE_RESHAPE_ACTIVE: u32 = 1 << 2;
+const ROLE_FAULTY: u16 = 0xfffe;
+const ROLE_SPARE: u16 = 0xffff;
+const LEVEL_STRIPE: u32 = 0;
+const LEVEL_MIRROR: u32 = 1;
+const RESYNC_CHUNK_SIZE: u64 = 1 << 20;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    Stripe { chunk_size: u64 },
+    Mirror,
+}
+
+#[derive(Clone)]
+pub struct Superblock {
+    pub array_id: [u8; 16],
+    pub name: String,
+    pub level: u32,
+    pub chunk_size: u64,  // in bytes
+    pub member_size: u64, // in bytes, used by mirrors
+    pub raid_disk_count: u32,
+    pub data_offset: u64,             // in bytes
+    pub data_size: u64,               // in bytes
+    pub recovery_offset: Option<u64>, // in bytes
+    pub events: u64,
+    pub role: Option<u32>, // None for spares and faulty members
+    data: Vec<u8>,
+}
+
+impl Debug for Superblock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Superblock")
+            .field("name", &self.name)
+            .field("level", &self.level)
+            .field("raid_disk_count", &self.raid_disk_count)
+            .field("events", &self.events)
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    let size = 256 + u32_at(data, 220) as usize * 2;
+    let mut sum: u64 = data[..size]
+        .chunks(4)
+        .enumerate()
+        .map(|(index, word)| match index {
+            54 => 0, // the checksum itself
+            _ if word.len() == 2 => u16_at(word, 0) as u64,
+            _ => u32_at(word, 0) as u64,
+        })
+        .sum();
+    sum = (sum & 0xffffffff) + (sum >> 32);
+    sum as u32
+}
+
+pub fn read_superblock<SS: SectorStorage>(sector_storage: &SS) -> Result<Option<Superblock>, StorageError> {
+    if sector_storage.len() < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE {
+        return Ok(None);
+    }
+    let data = sector_storage.read_bytes(SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE)?;
+    if u32_at(&data, 0) != MAGIC || u32_at(&data, 4) != MAJOR_VERSION || u64_at(&data, 144) != SUPERBLOCK_OFFSET / MD_SECTOR_SIZE {
+        return Ok(None);
+    }
+    let max_dev = u32_at(&data, 220) as usize;
+    if 256 + max_dev * 2 > data.len() || checksum(&data) != u32_at(&data, 216) {
+        log::warn!("md superblock with an invalid checksum");
+        return Ok(None);
+    }
+    let feature_map = u32_at(&data, 8);
+    if feature_map & FEATURE_RESHAPE_ACTIVE != 0 {
+        log::warn!("md array in the middle of a reshape");
+        return Ok(None);
+    }
+    let dev_number = u32_at(&data, 160) as usize;
+    let role = match dev_number < max_dev {
+        true => u16_at(&data, 256 + dev_number * 2),
+        false => ROLE_SPARE,
+    };
+    Ok(Some(Superblock {
+        array_id: data[16..32].try_into().unwrap(),
+        name: String::from_utf8_lossy(data[32..64].split(|&byte| byte == 0).next().unwrap()).into_owned(),
+        level: u32_at(&data, 72),
+        chunk_size: u32_at(&data, 88) as u64 * MD_SECTOR_SIZE,
+        member_size: u64_at(&data, 80) * MD_SECTOR_SIZE,
+        raid_disk_count: u32_at(&data, 92),
+        data_offset: u64_at(&data, 128) * MD_SECTOR_SIZE,
+        data_size: u64_at(&data, 136) * MD_SECTOR_SIZE,
+        recovery_offset: (feature_map & FEATURE_RECOVERY_OFFSET != 0).then(|| u64_at(&data, 152) * MD_SECTOR_SIZE),
+        events: u64_at(&data, 200),
+        role: (role != ROLE_FAULTY && role != ROLE_SPARE).then_some(role as u32),
+        data,
+    }))
+}
+
+struct Member<SS: SectorStorage> {
+    sector_storage: SS,
+    superblock: Superblock,
+    stale: Cell<bool>,
+    failed: Cell<bool>,
+}
+
+pub struct RaidSectorStorage<SS: SectorStorage> {
+    array_id: [u8; 16],
+    name: String,
+    layout: Layout,
+    members: Vec<Option<Member<SS>>>, // indexed by role
+    sector_size: u64,
+    sector_count: u64,
+    events: Cell<u64>, // of the in-sync members
+    next_read_role: Cell<usize>,
+}
+
+impl<SS: SectorStorage + Debug> Debug for RaidSectorStorage<SS> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RaidSectorStorage")
+            .field("name", &self.name)
+            .field("layout", &self.layout)
+            .field(
+                "members",
+                &self
+                    .members
+                    .iter()
+                    .map(|member| member.as_ref().map(|member| &member.sector_storage))
+                    .collect::<Vec<_>>(),
+            )
+            .field("sector_size", &self.sector_size)
+            .field("sector_count", &self.sector_count)
+            .finish()
+    }
+}
+
+impl<SS: SectorStorage> RaidSectorStorage<SS> {
+    // all members have to belong to the same array
+    pub fn new(members: Vec<(SS, Superblock)>) -> Option<Self> {
+        let reference = members
+            .iter()
+            .map(|(_, superblock)| superblock)
+            .max_by_key(|superblock| superblock.events)?
+            .clone();
+        let sector_size = members[0].0.sector_size();
+        if members
+            .iter()
+            .any(|(sector_storage, superblock)| superblock.array_id != reference.array_id || sector_storage.sector_size() != sector_size)
+        {
+            return None;
+        }
+        let layout = match reference.level {
+            LEVEL_STRIPE if reference.chunk_size != 0 && reference.chunk_size % sector_size == 0 => Layout::Stripe {
+                chunk_size: reference.chunk_size,
+            },
+            LEVEL_MIRROR => Layout::Mirror,
+            level => {
+                log::warn!("Unsupported md array {} level {}", reference.name, level);
+                return None;
+            }
+        };
+        let mut slots: Vec<Option<Member<SS>>> = (0..reference.raid_disk_count).map(|_| None).collect();
+        for (sector_storage, superblock) in members {
+            let Some(role) = superblock.role.filter(|&role| role < reference.raid_disk_count) else {
+                continue;
+            };
+            if superblock.data_offset % sector_size != 0 || superblock.data_offset + superblock.data_size > sector_storage.len() {
+                log::warn!("md array {} member with an invalid data area", reference.name);
+                continue;
+            }
+            let slot = &mut slots[role as usize];
+            if matches!(slot, Some(member) if member.superblock.events >= superblock.events) {
+                continue;
+            }
+            *slot = Some(Member {
+                stale: Cell::new(superblock.events < reference.events || superblock.recovery_offset.is_some()),
+                failed: Cell::new(false),
+                sector_storage,
+                superblock,
+            });
+        }
+        let len = match layout {
+            Layout::Stripe { chunk_size } => {
+                if slots.iter().any(Option::is_none) {
+                    log::warn!("md array {} is missing stripe members", reference.name);
+                    return None;
+                }
+                let member_len = slots
+                    .iter()
+                    .flatten()
+                    .map(|member| member.superblock.data_size / chunk_size * chunk_size)
+                    .min()?;
+                if slots
+                    .iter()
+                    .flatten()
+                    .any(|member| member.superblock.data_size / chunk_size * chunk_size != member_len)
+                {
+                    log::warn!("md array {} has members of different sizes, only the first zone is used", reference.name);
+                }
+                member_len * slots.len() as u64
+            }
+            Layout::Mirror => {
+                if !slots.iter().flatten().any(|member| !member.stale.get()) {
+                    log::warn!("md array {} has no in-sync mirror", reference.name);
+                    return None;
+                }
+                if slots.iter().flatten().any(|member| member.superblock.data_size < reference.member_size) {
+                    log::warn!("md array {} has members smaller than the array", reference.name);
+                    return None;
+                }
+                reference.member_size
+            }
+        };
+        Some(RaidSectorStorage {
+            array_id: reference.array_id,
+            name: reference.name,
+            layout,
+            members: slots,
+            sector_size,
+            sector_count: len / sector_size,
+            events: Cell::new(reference.events),
+            next_read_role: Cell::new(0),
+        })
+    }
+
+    pub fn array_id(&self) -> [u8; 16] {
+        self.array_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn stale_member_count(&self) -> usize {
+        self.members
+            .iter()
+            .flatten()
+            .filter(|member| member.stale.get() && !member.failed.get())
+            .count()
+    }
+
+    // copies the mirrored data to the stale members and marks them in sync
+    pub fn resync(&self) -> Result<(), StorageError> {
+        let Layout::Mirror = self.layout else {
+            return Ok(());
+        };
+        let events = self.events.get();
+        for member in self.members.iter().flatten().filter(|member| member.stale.get() && !member.failed.get()) {
+            let mut offset = match member.superblock.events {
+                member_events if member_events < events => 0,
+                _ => member.superblock.recovery_offset.unwrap_or(0).min(self.len()),
+            };
+            while offset < self.len() {
+                let len = RESYNC_CHUNK_SIZE.min(self.len() - offset);
+                let data = self.read_mirror(offset, len)?;
+                member.sector_storage.write_bytes(member.superblock.data_offset + offset, &data)?;
+                offset += len;
+            }
+            self.write_in_sync_superblock(member)?;
+            member.stale.set(false);
+            log::info!("md array {} member resynced", self.name);
+        }
+        Ok(())
+    }
+
+    // the superblock of a member holding all the data, with the current events and the failed members marked faulty
+    fn write_in_sync_superblock(&self, member: &Member<SS>) -> Result<(), StorageError> {
+        let mut superblock_data = member.superblock.data.clone();
+        let feature_map = u32_at(&superblock_data, 8) & !FEATURE_RECOVERY_OFFSET;
+        superblock_data[8..12].copy_from_slice(&feature_map.to_le_bytes());
+        superblock_data[152..160].copy_from_slice(&0u64.to_le_bytes());
+        superblock_data[200..208].copy_from_slice(&self.events.get().to_le_bytes());
+        let max_dev = u32_at(&superblock_data, 220) as usize;
+        for failed_member in self.members.iter().flatten().filter(|member| member.failed.get()) {
+            let dev_number = u32_at(&failed_member.superblock.data, 160) as usize;
+            if dev_number < max_dev {
+                superblock_data[256 + dev_number * 2..258 + dev_number * 2].copy_from_slice(&ROLE_FAULTY.to_le_bytes());
+            }
+        }
+        let checksum = checksum(&superblock_data);
+        superblock_data[216..220].copy_from_slice(&checksum.to_le_bytes());
+        member.sector_storage.write_bytes(SUPERBLOCK_OFFSET, &superblock_data)
+    }
+
+    // like md, a new event records the member as faulty in the superblocks of the in-sync members so that it is not assembled again
+    fn fail_member(&self, role: usize, error: StorageError) {
+        log::error!("md array {} member {} failed: {:?}", self.name, role, error);
+        self.members[role].as_ref().unwrap().failed.set(true);
+        self.events.set(self.events.get() + 1);
+        for (role, member) in self.members.iter().enumerate() {
+            let Some(member) = member.as_ref().filter(|member| !member.failed.get() && !member.stale.get()) else {
+                continue;
+            };
+            // left with the old events, the member is resynced on the next assembly
+            if let Err(error) = self.write_in_sync_superblock(member) {
+                log::error!("md array {} member {} superblock update failed: {:?}", self.name, role, error);
+            }
+        }
+    }
+
+    fn read_mirror(&self, start: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        // round robin over the in-sync members, falling back to the next one on errors
+        let first_role = self.next_read_role.get();
+        self.next_read_role.set((first_role + 1) % self.members.len());
+        let mut result = Err(StorageError::DeviceError);
+        for role in (first_role..self.members.len()).chain(0..first_role) {
+            let Some(member) = &self.members[role] else {
+                continue;
+            };
+            if member.stale.get() || member.failed.get() {
+                continue;
+            }
+            result = member.sector_storage.read_bytes(member.superblock.data_offset + start, len);
+            match result {
+                Ok(_) => break,
+                Err(error) => self.fail_member(role, error),
+            }
+        }
+        result
+    }
+
+    fn write_mirror(&self, start: u64, data: &[u8]) -> Result<(), StorageError> {
+        self.write_mirror_members(|member| member.sector_storage.write_bytes(member.superblock.data_offset + start, data))
+    }
+
+    // succeeds if the write succeeded on at least one in-sync member, failing members are dropped from the array
+    fn write_mirror_members(&self, write: impl Fn(&Member<SS>) -> Result<(), StorageError>) -> Result<(), StorageError> {
+        let mut result = Err(StorageError::DeviceError);
+        for (role, member) in self.members.iter().enumerate() {
+            let Some(member) = member.as_ref().filter(|member| !member.failed.get()) else {
+                continue;
+            };
+            match write(member) {
+                Ok(()) if !member.stale.get() => result = Ok(()),
+                Ok(()) => {}
+                Err(error) => self.fail_member(role, error),
+            }
+        }
+        result
+    }
+
+    // splits a byte range of the array into (member, member offset, range offset, len) pieces
+    fn stripe_pieces(&self, chunk_size: u64, start: u64, len: u64) -> impl Iterator<Item = (&Member<SS>, u64, usize, usize)> {
+        let member_count = self.members.len() as u64;
+        let mut offset = start;
+        core::iter::from_fn(move || {
+            if offset >= start + len {
+                return None;
+            }
+            let chunk_index = offset / chunk_size;
+            let chunk_offset = offset % chunk_size;
+            let piece_len = (chunk_size - chunk_offset).min(start + len - offset);
+            let member = self.members[(chunk_index % member_count) as usize].as_ref().unwrap();
+            let member_offset = member.superblock.data_offset + chunk_index / member_count * chunk_size + chunk_offset;
+            let piece = (member, member_offset, (offset - start) as usize, piece_len as usize);
+            offset += piece_len;
+            Some(piece)
+        })
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for RaidSectorStorage<SS> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        let (start, len) = (sector_index * self.sector_size, sector_count * self.sector_size);
+        match self.layout {
+            Layout::Stripe { chunk_size } => {
+                let mut sectors_data = Vec::with_capacity(len as usize);
+                for (member, member_offset, _, piece_len) in self.stripe_pieces(chunk_size, start, len) {
+                    sectors_data.extend_from_slice(&member.sector_storage.read_bytes(member_offset, piece_len as u64)?);
+                }
+                Ok(sectors_data)
+            }
+            Layout::Mirror => self.read_mirror(start, len),
+        }
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        let start = sector_index * self.sector_size;
+        match self.layout {
+            Layout::Stripe { chunk_size } => {
+                for (member, member_offset, offset, piece_len) in self.stripe_pieces(chunk_size, start, sectors_data.len() as u64) {
+                    member.sector_storage.write_bytes(member_offset, &sectors_data[offset..offset + piece_len])?;
+                }
+                Ok(())
+            }
+            Layout::Mirror => self.write_mirror(start, sectors_data),
+        }
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        match self.layout {
+            Layout::Stripe { .. } => self.members.iter().flatten().try_for_each(|member| member.sector_storage.flush()),
+            Layout::Mirror => self.write_mirror_members(|member| member.sector_storage.flush()),
+        }
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        let (start, len) = (sectors.start * self.sector_size, (sectors.end - sectors.start) * self.sector_size);
+        match self.layout {
+            Layout::Stripe { chunk_size } => self
+                .stripe_pieces(chunk_size, start, len)
+                .try_for_each(|(member, member_offset, _, piece_len)| discard_bytes(&member.sector_storage, member_offset, piece_len as u64)),
+            Layout::Mirror => self.write_mirror_members(|member| discard_bytes(&member.sector_storage, member.superblock.data_offset + start, len)),
+        }
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        let (start, len) = (sectors.start * self.sector_size, (sectors.end - sectors.start) * self.sector_size);
+        match self.layout {
+            Layout::Stripe { chunk_size } => self
+                .stripe_pieces(chunk_size, start, len)
+                .try_for_each(|(member, member_offset, _, piece_len)| zero_bytes(&member.sector_storage, member_offset, piece_len as u64)),
+            Layout::Mirror => self.write_mirror_members(|member| zero_bytes(&member.sector_storage, member.superblock.data_offset + start, len)),
+        }
+    }
+
+    // the members are written together, a single read-only one makes the array read-only
+    fn read_only(&self) -> bool {
+        self.members
+            .iter()
+            .flatten()
+            .any(|member| !member.failed.get() && member.sector_storage.read_only())
+    }
+}
+
+// discards the member sectors fully inside the byte range
+fn discard_bytes<SS: SectorStorage>(sector_storage: &SS, start: u64, len: u64) -> Result<(), StorageError> {
+    let sector_size = sector_storage.sector_size();
+    let sectors = start.div_ceil(sector_size)..(start + len) / sector_size;
+    if sectors.start < sectors.end {
+        sector_storage.discard(sectors)?;
+    }
+    Ok(())
+}
+
+fn zero_bytes<SS: SectorStorage>(sector_storage: &SS, start: u64, len: u64) -> Result<(), StorageError> {
+    let sector_size = sector_storage.sector_size();
+    if start % sector_size == 0 && len % sector_size == 0 {
+        sector_storage.write_zeroes(start / sector_size..(start + len) / sector_size)
+    } else {
+        sector_storage.write_bytes(start, &alloc::vec![0; len as usize])
+    }
+}
+
+// groups the members by array, arrays that fail to assemble are dropped
+pub fn assemble_arrays<SS: SectorStorage>(members: Vec<(SS, Superblock)>) -> Vec<RaidSectorStorage<SS>> {
+    let mut arrays_members: Vec<Vec<(SS, Superblock)>> = Vec::new();
+    for (sector_storage, superblock) in members {
+        match arrays_members
+            .iter_mut()
+            .find(|array_members| array_members[0].1.array_id == superblock.array_id)
+        {
+            Some(array_members) => array_members.push((sector_storage, superblock)),
+            None => arrays_members.push(alloc::vec![(sector_storage, superblock)]),
+        }
+    }
+    arrays_members.into_iter().filter_map(RaidSectorStorage::new).collect()
+}
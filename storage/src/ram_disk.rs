@@ -0,0 +1,72 @@
+use alloc::{vec, vec::Vec};
+use core::{cell::RefCell, ops::Range};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+pub const DEFAULT_SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug)]
+pub struct DiskSectorStorage {
+    sector_size: u64,
+    data: RefCell<Vec<u8>>,
+}
+
+impl DiskSectorStorage {
+    pub fn new(sector_size: u64, sector_count: u64) -> Self {
+        DiskSectorStorage {
+            sector_size,
+            data: RefCell::new(vec![0; (sector_count * sector_size) as usize]),
+        }
+    }
+
+    pub fn from_data(sector_size: u64, mut data: Vec<u8>) -> Self {
+        data.resize((data.len() as u64).next_multiple_of(sector_size) as usize, 0);
+        DiskSectorStorage {
+            sector_size,
+            data: RefCell::new(data),
+        }
+    }
+}
+
+impl SectorStorage for DiskSectorStorage {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.data.borrow().len() as u64 / self.sector_size
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_sectors(sector_index, 1)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        if sector_index + sector_count > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok(self.data.borrow()[(sector_index * self.sector_size) as usize..((sector_index + sector_count) * self.sector_size) as usize].to_vec())
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size == 0);
+        if sector_index + sectors_data.len() as u64 / self.sector_size > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        let start = (sector_index * self.sector_size) as usize;
+        self.data.borrow_mut()[start..start + sectors_data.len()].copy_from_slice(sectors_data);
+        Ok(())
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        self.data.borrow_mut()[(sectors.start * self.sector_size) as usize..(sectors.end * self.sector_size) as usize].fill(0);
+        Ok(())
+    }
+}
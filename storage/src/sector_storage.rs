@@ -0,0 +1,206 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::ops::Range;
+
+const WRITE_ZEROES_CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StorageError {
+    OutOfRange,
+    DeviceError,
+    Timeout,
+    MediaReadOnly,
+    NoSpace,
+}
+
+pub trait SectorStorage {
+    fn sector_size(&self) -> u64;
+
+    fn sector_count(&self) -> u64;
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError>;
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError>;
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        let mut sectors_data = Vec::with_capacity((sector_count * self.sector_size()) as usize);
+        for sector_index in sector_index..sector_index + sector_count {
+            sectors_data.extend_from_slice(&self.read_sector(sector_index)?);
+        }
+        Ok(sectors_data)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        assert!(sectors_data.len() as u64 % self.sector_size() == 0);
+        for (index, sector_data) in sectors_data.chunks(self.sector_size() as usize).enumerate() {
+            self.write_sector(sector_index + index as u64, sector_data)?;
+        }
+        Ok(())
+    }
+
+    // makes all completed writes durable
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    // forced unit access, the data is durable once this returns
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        self.write_sectors(sector_index, sectors_data)?;
+        self.flush()
+    }
+
+    // a hint that the contents are no longer needed, discarded sectors read back unspecified data
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        if self.read_only() {
+            return Err(StorageError::MediaReadOnly);
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        if sectors.start > sectors.end || sectors.end > self.sector_count() {
+            return Err(StorageError::OutOfRange);
+        }
+        let sectors_per_chunk = (WRITE_ZEROES_CHUNK_SIZE / self.sector_size()).max(1);
+        let zeroes = vec![0; (sectors_per_chunk.min(sectors.end - sectors.start) * self.sector_size()) as usize];
+        for chunk_sector_index in sectors.clone().step_by(sectors_per_chunk as usize) {
+            let chunk_sector_count = sectors_per_chunk.min(sectors.end - chunk_sector_index);
+            self.write_sectors(chunk_sector_index, &zeroes[..(chunk_sector_count * self.sector_size()) as usize])?;
+        }
+        Ok(())
+    }
+
+    // writes fail with MediaReadOnly
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn len(&self) -> u64 {
+        self.sector_count() * self.sector_size()
+    }
+
+    fn read_aligned(&self, start: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        assert!(start % self.sector_size() == 0 && len % self.sector_size() == 0);
+        self.read_sectors(start / self.sector_size(), len / self.sector_size())
+    }
+
+    fn write_aligned(&self, start: u64, data: &[u8]) -> Result<(), StorageError> {
+        assert!(start % self.sector_size() == 0 && data.len() as u64 % self.sector_size() == 0);
+        self.write_sectors(start / self.sector_size(), data)
+    }
+
+    fn read_bytes(&self, start: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let aligned_start = start / self.sector_size() * self.sector_size();
+        let aligned_end = (start + len).next_multiple_of(self.sector_size());
+        let mut data = self.read_aligned(aligned_start, aligned_end - aligned_start)?;
+        data.drain(..(start - aligned_start) as usize);
+        data.truncate(len as usize);
+        Ok(data)
+    }
+
+    fn write_bytes(&self, start: u64, data: &[u8]) -> Result<(), StorageError> {
+        if start % self.sector_size() == 0 && data.len() as u64 % self.sector_size() == 0 {
+            return self.write_aligned(start, data);
+        }
+        // read-modify-write of the partially covered sectors
+        let aligned_start = start / self.sector_size() * self.sector_size();
+        let aligned_end = (start + data.len() as u64).next_multiple_of(self.sector_size());
+        let mut aligned_data = self.read_aligned(aligned_start, aligned_end - aligned_start)?;
+        aligned_data[(start - aligned_start) as usize..(start - aligned_start) as usize + data.len()].copy_from_slice(data);
+        self.write_aligned(aligned_start, &aligned_data)
+    }
+}
+
+impl<SS: SectorStorage + ?Sized> SectorStorage for &SS {
+    fn sector_size(&self) -> u64 {
+        (*self).sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        (*self).sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        (*self).read_sector(sector_index)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        (*self).write_sector(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        (*self).read_sectors(sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        (*self).write_sectors(sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        (*self).flush()
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        (*self).write_sectors_fua(sector_index, sectors_data)
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        (*self).discard(sectors)
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        (*self).write_zeroes(sectors)
+    }
+
+    fn read_only(&self) -> bool {
+        (*self).read_only()
+    }
+}
+
+impl<SS: SectorStorage + ?Sized> SectorStorage for Box<SS> {
+    fn sector_size(&self) -> u64 {
+        (**self).sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        (**self).sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        (**self).read_sector(sector_index)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        (**self).write_sector(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        (**self).read_sectors(sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        (**self).write_sectors(sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        (**self).flush()
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        (**self).write_sectors_fua(sector_index, sectors_data)
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        (**self).discard(sectors)
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        (**self).write_zeroes(sectors)
+    }
+
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+}
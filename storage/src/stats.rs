@@ -0,0 +1,284 @@
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::{cell::RefCell, fmt::Debug, ops::Range};
+
+use super::sector_storage::{SectorStorage, StorageError};
+
+pub const LATENCY_BUCKET_COUNT: usize = 64; // bucket n counts the latencies in [2^n, 2^(n+1)) ticks
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpStats {
+    pub request_count: u64,
+    pub error_count: u64,
+    pub byte_count: u64,
+    pub latency_histogram: [u64; LATENCY_BUCKET_COUNT],
+}
+
+impl Default for OpStats {
+    fn default() -> Self {
+        OpStats {
+            request_count: 0,
+            error_count: 0,
+            byte_count: 0,
+            latency_histogram: [0; LATENCY_BUCKET_COUNT],
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    pub read: OpStats,
+    pub write: OpStats,
+    pub flush: OpStats,
+    pub discard: OpStats,
+    pub write_zeroes: OpStats,
+}
+
+impl Stats {
+    pub fn op_stats(&self, op: Op) -> &OpStats {
+        match op {
+            Op::Read => &self.read,
+            Op::Write => &self.write,
+            Op::Flush => &self.flush,
+            Op::Discard => &self.discard,
+            Op::WriteZeroes => &self.write_zeroes,
+        }
+    }
+
+    fn op_stats_mut(&mut self, op: Op) -> &mut OpStats {
+        match op {
+            Op::Read => &mut self.read,
+            Op::Write => &mut self.write,
+            Op::Flush => &mut self.flush,
+            Op::Discard => &mut self.discard,
+            Op::WriteZeroes => &mut self.write_zeroes,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub sequence_number: u64,
+    pub start_time: u64,
+    pub latency: u64,
+    pub op: Op,
+    pub sector_index: u64,
+    pub sector_count: u64,
+    pub result: Result<(), StorageError>,
+}
+
+struct Trace {
+    capacity: usize,
+    entries: VecDeque<TraceEntry>, // the oldest entries are dropped once full
+    next_sequence_number: u64,
+}
+
+// counts the requests going through to the wrapped storage, timed with the given clock (the TSC in the kernel)
+pub struct StatsSectorStorage<SS: SectorStorage> {
+    sector_storage: SS,
+    name: String,
+    clock: fn() -> u64,
+    stats: RefCell<Stats>,
+    trace: RefCell<Option<Trace>>,
+}
+
+impl<SS: SectorStorage + Debug> Debug for StatsSectorStorage<SS> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("StatsSectorStorage")
+            .field("sector_storage", &self.sector_storage)
+            .field("name", &self.name)
+            .field("trace_capacity", &self.trace.borrow().as_ref().map(|trace| trace.capacity))
+            .finish()
+    }
+}
+
+impl<SS: SectorStorage> StatsSectorStorage<SS> {
+    pub fn new(sector_storage: SS, name: &str, clock: fn() -> u64) -> Self {
+        StatsSectorStorage {
+            sector_storage,
+            name: String::from(name),
+            clock,
+            stats: RefCell::new(Stats::default()),
+            trace: RefCell::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.borrow().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.borrow_mut() = Stats::default();
+    }
+
+    // a capacity of 0 disables tracing
+    pub fn set_trace_capacity(&self, capacity: usize) {
+        let mut trace = self.trace.borrow_mut();
+        if capacity == 0 {
+            *trace = None;
+            return;
+        }
+        let trace = trace.get_or_insert_with(|| Trace {
+            capacity,
+            entries: VecDeque::new(),
+            next_sequence_number: 0,
+        });
+        trace.capacity = capacity;
+        while trace.entries.len() > capacity {
+            trace.entries.pop_front();
+        }
+    }
+
+    pub fn trace_entries(&self) -> Vec<TraceEntry> {
+        self.trace
+            .borrow()
+            .as_ref()
+            .map(|trace| trace.entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn log_stats(&self) {
+        let stats = self.stats.borrow();
+        for (op_name, op) in [
+            ("read", Op::Read),
+            ("write", Op::Write),
+            ("flush", Op::Flush),
+            ("discard", Op::Discard),
+            ("write zeroes", Op::WriteZeroes),
+        ] {
+            let op_stats = stats.op_stats(op);
+            if op_stats.request_count == 0 {
+                continue;
+            }
+            log::info!(
+                "{} {}: {} requests, {} bytes, {} errors",
+                self.name,
+                op_name,
+                op_stats.request_count,
+                op_stats.byte_count,
+                op_stats.error_count
+            );
+            for (bucket_index, &count) in op_stats.latency_histogram.iter().enumerate().filter(|(_, &count)| count != 0) {
+                log::info!("{} {}:   2^{:<2} ticks: {}", self.name, op_name, bucket_index, count);
+            }
+        }
+    }
+
+    pub fn log_trace(&self) {
+        for entry in self.trace_entries() {
+            log::info!(
+                "{} #{} @{} {:?} sectors {}+{} in {} ticks: {:?}",
+                self.name,
+                entry.sequence_number,
+                entry.start_time,
+                entry.op,
+                entry.sector_index,
+                entry.sector_count,
+                entry.latency,
+                entry.result
+            );
+        }
+    }
+
+    fn record<T>(&self, op: Op, sector_index: u64, sector_count: u64, request: impl FnOnce() -> Result<T, StorageError>) -> Result<T, StorageError> {
+        let start_time = (self.clock)();
+        let result = request();
+        let latency = (self.clock)().saturating_sub(start_time);
+        let mut stats = self.stats.borrow_mut();
+        let op_stats = stats.op_stats_mut(op);
+        op_stats.request_count += 1;
+        match &result {
+            Ok(_) if matches!(op, Op::Read | Op::Write) => op_stats.byte_count += sector_count * self.sector_storage.sector_size(),
+            Ok(_) => {}
+            Err(_) => op_stats.error_count += 1,
+        }
+        op_stats.latency_histogram[(u64::BITS - 1).saturating_sub(latency.leading_zeros()) as usize] += 1;
+        if let Some(trace) = self.trace.borrow_mut().as_mut() {
+            if trace.entries.len() == trace.capacity {
+                trace.entries.pop_front();
+            }
+            trace.entries.push_back(TraceEntry {
+                sequence_number: trace.next_sequence_number,
+                start_time,
+                latency,
+                op,
+                sector_index,
+                sector_count,
+                result: result.as_ref().map(|_| ()).map_err(|&error| error),
+            });
+            trace.next_sequence_number += 1;
+        }
+        result
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for StatsSectorStorage<SS> {
+    fn sector_size(&self) -> u64 {
+        self.sector_storage.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_storage.sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.record(Op::Read, sector_index, 1, || self.sector_storage.read_sector(sector_index))
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.record(Op::Write, sector_index, 1, || self.sector_storage.write_sector(sector_index, sector_data))
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        self.record(Op::Read, sector_index, sector_count, || {
+            self.sector_storage.read_sectors(sector_index, sector_count)
+        })
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        let sector_count = sectors_data.len() as u64 / self.sector_size();
+        self.record(Op::Write, sector_index, sector_count, || {
+            self.sector_storage.write_sectors(sector_index, sectors_data)
+        })
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.record(Op::Flush, 0, 0, || self.sector_storage.flush())
+    }
+
+    fn write_sectors_fua(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        let sector_count = sectors_data.len() as u64 / self.sector_size();
+        self.record(Op::Write, sector_index, sector_count, || {
+            self.sector_storage.write_sectors_fua(sector_index, sectors_data)
+        })
+    }
+
+    // discarded and zeroed sectors are traced but not counted as bytes transferred
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        self.record(Op::Discard, sectors.start, sectors.end.saturating_sub(sectors.start), || {
+            self.sector_storage.discard(sectors.clone())
+        })
+    }
+
+    fn write_zeroes(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        self.record(Op::WriteZeroes, sectors.start, sectors.end.saturating_sub(sectors.start), || {
+            self.sector_storage.write_zeroes(sectors.clone())
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        self.sector_storage.read_only()
+    }
+}
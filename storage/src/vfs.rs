@@ -0,0 +1,202 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{fmt::Debug, ops::Range};
+
+use super::{
+    fs::{FileStat, FileType, Session},
+    sector_storage::StorageError,
+};
+
+// an inode of one of the mounted filesystems
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Node {
+    pub mount_index: usize,
+    pub inode_index: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: Option<FileType>,
+    pub node: Node, // the root of the filesystem mounted there, if any
+}
+
+struct Mount<'s> {
+    path: String,
+    session: Box<dyn Session + 's>,
+    mount_point: Option<Node>, // the directory covered in the parent filesystem, none for the root
+}
+
+pub struct Vfs<'s> {
+    mounts: Vec<Option<Mount<'s>>>, // slots are never reused, so that nodes of an unmounted filesystem can't alias
+}
+
+impl Debug for Vfs<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Vfs").field("mount_paths", &self.mount_paths()).finish()
+    }
+}
+
+impl<'s> Vfs<'s> {
+    pub fn new(root_session: Box<dyn Session + 's>) -> Self {
+        Vfs {
+            mounts: Vec::from([Some(Mount {
+                path: String::from("/"),
+                session: root_session,
+                mount_point: None,
+            })]),
+        }
+    }
+
+    // the root of whatever is mounted on "/"
+    pub fn root(&self) -> Node {
+        self.covering_node(self.mount_root(0).unwrap())
+    }
+
+    pub fn mount_paths(&self) -> Vec<&str> {
+        self.mounts.iter().flatten().map(|mount| mount.path.as_str()).collect()
+    }
+
+    fn mount_root(&self, mount_index: usize) -> Option<Node> {
+        let mount = self.mounts.get(mount_index)?.as_ref()?;
+        Some(Node {
+            mount_index,
+            inode_index: mount.session.root(),
+        })
+    }
+
+    fn session(&self, node: Node) -> Result<&(dyn Session + 's), StorageError> {
+        match self.mounts.get(node.mount_index) {
+            Some(Some(mount)) => Ok(mount.session.as_ref()),
+            _ => Err(StorageError::OutOfRange),
+        }
+    }
+
+    fn session_mut(&mut self, node: Node) -> Result<&mut (dyn Session + 's), StorageError> {
+        match self.mounts.get_mut(node.mount_index) {
+            Some(Some(mount)) => Ok(mount.session.as_mut()),
+            _ => Err(StorageError::OutOfRange),
+        }
+    }
+
+    // the root of the topmost filesystem mounted on a directory, or the directory itself
+    fn covering_node(&self, node: Node) -> Node {
+        let mut node = node;
+        while let Some(mount_index) = self
+            .mounts
+            .iter()
+            .position(|mount| mount.as_ref().is_some_and(|mount| mount.mount_point == Some(node)))
+        {
+            node = self.mount_root(mount_index).unwrap();
+        }
+        node
+    }
+
+    // the mount point is an existing directory, mounting on a mount point stacks on top of the mounted filesystem
+    pub fn mount(&mut self, path: &str, session: Box<dyn Session + 's>) -> Result<Node, StorageError> {
+        let Some(mount_point) = self.lookup(path)? else {
+            return Err(StorageError::OutOfRange);
+        };
+        if self.file_stat(mount_point)?.mode.file_type() != FileType::Dir {
+            return Err(StorageError::OutOfRange);
+        }
+        self.mounts.push(Some(Mount {
+            path: String::from(path),
+            session,
+            mount_point: Some(mount_point),
+        }));
+        Ok(self.mount_root(self.mounts.len() - 1).unwrap())
+    }
+
+    // only the topmost filesystem of a path without mounts below it, the root filesystem stays
+    pub fn unmount(&mut self, path: &str) -> Result<Box<dyn Session + 's>, StorageError> {
+        let Some(node) = self.lookup(path)? else {
+            return Err(StorageError::OutOfRange);
+        };
+        if node.mount_index == 0
+            || self.mount_root(node.mount_index) != Some(node)
+            || self
+                .mounts
+                .iter()
+                .flatten()
+                .any(|mount| mount.mount_point.is_some_and(|mount_point| mount_point.mount_index == node.mount_index))
+        {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok(self.mounts[node.mount_index].take().unwrap().session)
+    }
+
+    // absolute paths only, ".." goes back along the path walked so far and so leaves a filesystem where it was entered
+    pub fn lookup(&self, path: &str) -> Result<Option<Node>, StorageError> {
+        let Some(path) = path.strip_prefix('/') else {
+            return Ok(None);
+        };
+        let mut nodes = Vec::from([self.root()]);
+        for name in path.split('/') {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    if nodes.len() > 1 {
+                        nodes.pop();
+                    }
+                }
+                name => {
+                    let node = *nodes.last().unwrap();
+                    if self.file_stat(node)?.mode.file_type() != FileType::Dir {
+                        return Ok(None);
+                    }
+                    let Some(dir_entry) = self.read_dir(node)?.into_iter().find(|dir_entry| dir_entry.name == name) else {
+                        return Ok(None);
+                    };
+                    nodes.push(dir_entry.node);
+                }
+            }
+        }
+        Ok(nodes.pop())
+    }
+
+    pub fn read_only(&self, node: Node) -> Result<bool, StorageError> {
+        Ok(self.session(node)?.read_only())
+    }
+
+    pub fn file_stat(&self, node: Node) -> Result<FileStat, StorageError> {
+        self.session(node)?.file_stat(node.inode_index)
+    }
+
+    // "." and ".." are left out, as the filesystem's own entries are wrong at mount roots
+    pub fn read_dir(&self, node: Node) -> Result<Vec<DirEntry>, StorageError> {
+        Ok(self
+            .session(node)?
+            .read_dir(node.inode_index)?
+            .into_iter()
+            .filter(|dir_entry| dir_entry.inode_index != 0 && dir_entry.name != "." && dir_entry.name != "..")
+            .map(|dir_entry| {
+                let entry_node = Node {
+                    mount_index: node.mount_index,
+                    inode_index: dir_entry.inode_index,
+                };
+                let covering_node = self.covering_node(entry_node);
+                DirEntry {
+                    name: dir_entry.name,
+                    file_type: if covering_node == entry_node {
+                        dir_entry.file_type
+                    } else {
+                        Some(FileType::Dir)
+                    },
+                    node: covering_node,
+                }
+            })
+            .collect())
+    }
+
+    pub fn read_regular_file_range(&self, node: Node, range: Range<u64>) -> Result<Vec<u8>, StorageError> {
+        self.session(node)?.read_regular_file_range(node.inode_index, range)
+    }
+
+    pub fn write_regular_file_range(&mut self, node: Node, range: Range<u64>, data: &[u8]) -> Result<(), StorageError> {
+        self.session_mut(node)?.write_regular_file_range(node.inode_index, range, data)
+    }
+
+    pub fn resize_regular_file(&mut self, node: Node, size: u64) -> Result<(), StorageError> {
+        self.session_mut(node)?.resize_regular_file(node.inode_index, size)
+    }
+}
@@ -0,0 +1,71 @@
+// helpers shared by the test crates, each of which uses only some of them
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use storage::fs::Session;
+
+pub fn run(command: &mut Command) {
+    let output = command.output().expect("failed to run command");
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// a fresh directory per test
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir_path);
+    fs::create_dir_all(&dir_path).unwrap();
+    dir_path
+}
+
+pub fn make_empty_image(name: &str, size: u64) -> PathBuf {
+    let image_path = test_dir(name).join("image");
+    fs::File::create(&image_path).unwrap().set_len(size).unwrap();
+    image_path
+}
+
+// files are given by their path from the root, a trailing slash makes a directory
+pub fn make_ext2_image(name: &str, block_size: u64, size: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    let dir_path = test_dir(name);
+    let root_path = dir_path.join("root");
+    fs::create_dir_all(&root_path).unwrap();
+    for &(file_path, file_data) in files {
+        match file_path.strip_suffix('/') {
+            Some(dir_path) => fs::create_dir_all(root_path.join(dir_path)).unwrap(),
+            None => {
+                fs::create_dir_all(root_path.join(file_path).parent().unwrap()).unwrap();
+                fs::write(root_path.join(file_path), file_data).unwrap();
+            }
+        }
+    }
+    let image_path = dir_path.join("image.ext2");
+    run(Command::new("mkfs.ext2")
+        .args(["-q", "-F", "-b", &block_size.to_string(), "-E", "root_owner=0:0", "-d"])
+        .arg(&root_path)
+        .arg(&image_path)
+        .arg(size));
+    image_path
+}
+
+pub fn check_ext2_image(image_path: &Path) {
+    run(Command::new("e2fsck").args(["-f", "-n"]).arg(image_path));
+}
+
+pub fn lookup(session: &impl Session, dir_inode_index: u64, name: &str) -> u64 {
+    session
+        .read_dir(dir_inode_index)
+        .unwrap()
+        .into_iter()
+        .find(|dir_entry| dir_entry.inode_index != 0 && dir_entry.name == name)
+        .unwrap_or_else(|| panic!("{} not found", name))
+        .inode_index
+}
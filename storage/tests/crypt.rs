@@ -0,0 +1,99 @@
+use std::{fs, path::PathBuf, process::Command};
+
+use storage::{
+    crypt::{CryptSectorStorage, Xts},
+    ext2, file_disk,
+    fs::Session,
+    luks2,
+    sector_storage::SectorStorage,
+};
+
+mod common;
+
+const PASSPHRASE: &[u8] = b"correct horse battery staple";
+const HELLO_DATA: &[u8] = b"Hello from an encrypted disk!\n";
+
+fn make_luks2_image(name: &str, pbkdf_args: &[&str], sector_size: u64) -> PathBuf {
+    let image_path = common::make_empty_image(name, 32 << 20);
+    let key_file_path = image_path.with_file_name("key");
+    fs::write(&key_file_path, PASSPHRASE).unwrap();
+    common::run(
+        Command::new("cryptsetup")
+            .args(["luksFormat", "--batch-mode", "--type", "luks2", "--sector-size", &sector_size.to_string()])
+            .args(pbkdf_args)
+            .arg("--key-file")
+            .arg(&key_file_path)
+            .arg(&image_path),
+    );
+    image_path
+}
+
+fn check_luks2_image(name: &str, pbkdf_args: &[&str], sector_size: u64) {
+    let image_path = make_luks2_image(name, pbkdf_args, sector_size);
+    let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+    let header = luks2::read_header(&sector_storage).unwrap().expect("no LUKS2 header");
+    assert_eq!(header.segment().encryption, "aes-xts-plain64");
+    assert_eq!(header.segment().sector_size, sector_size);
+    assert!(header.unlock(&sector_storage, b"wrong passphrase").unwrap().is_none());
+    let key = header.unlock(&sector_storage, PASSPHRASE).unwrap().expect("failed to unlock");
+    let crypt_sector_storage = CryptSectorStorage::from_luks2_segment(&sector_storage, &key, header.segment()).unwrap();
+    assert_eq!(crypt_sector_storage.sector_size(), sector_size);
+    assert_eq!(crypt_sector_storage.len(), sector_storage.len() - header.segment().offset);
+    let ext2_image_path = common::make_ext2_image(
+        &format!("{}_ext2", name),
+        4096,
+        &format!("{}k", crypt_sector_storage.len() / 1024),
+        &[("hello.txt", HELLO_DATA)],
+    );
+    let ext2_image_data = fs::read(&ext2_image_path).unwrap();
+    crypt_sector_storage.write_bytes(0, &ext2_image_data).unwrap();
+    assert_ne!(sector_storage.read_bytes(header.segment().offset, 4096).unwrap(), ext2_image_data[..4096]);
+    let session = ext2::Session::new(&crypt_sector_storage).unwrap().unwrap();
+    let hello_inode_index = common::lookup(&session, session.root(), "hello.txt");
+    assert_eq!(
+        session.read_regular_file_range(hello_inode_index, 0..HELLO_DATA.len() as u64).unwrap(),
+        HELLO_DATA
+    );
+}
+
+#[test]
+fn xts_known_answer() {
+    let key: Vec<u8> = (0..64).collect();
+    let xts = Xts::new(&key).unwrap();
+    let plain_sector_data: Vec<u8> = (0..512).map(|index: u32| index as u8).collect();
+    let mut sector_data = plain_sector_data.clone();
+    xts.encrypt_sector(5, &mut sector_data);
+    assert_eq!(&sector_data[..16], b"\xf8\x7c\xa2\xf2\x9b\x11\x7c\x1b\x02\x4a\x6e\xc8\xe8\xc5\x99\x4e");
+    assert_eq!(&sector_data[496..], b"\xe7\x90\xce\x59\x00\xc4\x72\x86\xca\xae\xf5\xe4\x57\xfe\xcc\x4b");
+    xts.decrypt_sector(5, &mut sector_data);
+    assert_eq!(sector_data, plain_sector_data);
+}
+
+#[test]
+fn unlock_pbkdf2_luks2_image() {
+    check_luks2_image("unlock_pbkdf2_luks2_image", &["--pbkdf", "pbkdf2", "--pbkdf-force-iterations", "1000"], 512);
+}
+
+#[test]
+fn unlock_argon2id_luks2_image() {
+    check_luks2_image(
+        "unlock_argon2id_luks2_image",
+        &[
+            "--pbkdf",
+            "argon2id",
+            "--pbkdf-force-iterations",
+            "4",
+            "--pbkdf-memory",
+            "32",
+            "--pbkdf-parallel",
+            "1",
+        ],
+        4096,
+    );
+}
+
+#[test]
+fn not_luks2() {
+    let sector_storage = storage::ram_disk::DiskSectorStorage::new(512, 1 << 13);
+    assert!(luks2::read_header(&sector_storage).unwrap().is_none());
+}
@@ -0,0 +1,225 @@
+use std::{fs, path::PathBuf};
+
+use storage::{
+    ext2, file_disk,
+    fs::{FileType, Session},
+    guid,
+    partitions::{self, Partition},
+    sector_storage::{SectorStorage, StorageError},
+};
+
+mod common;
+
+use common::{check_ext2_image, lookup};
+
+const HELLO_DATA: &[u8] = b"Hello from mkfs.ext2!\n";
+
+fn big_data() -> Vec<u8> {
+    // spans the singly and doubly indirect blocks with 1 KiB blocks
+    (0..300 * 1024).map(|index: u32| (index * 7 + index / 1024) as u8).collect()
+}
+
+fn make_image(name: &str, block_size: u64) -> PathBuf {
+    common::make_ext2_image(name, block_size, "8M", &[("hello.txt", HELLO_DATA), ("dir/big.bin", &big_data())])
+}
+
+#[test]
+fn read_root_dir() {
+    let image_path = make_image("read_root_dir", 1024);
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let mut names: Vec<_> = session
+        .read_dir(session.root())
+        .unwrap()
+        .into_iter()
+        .filter(|dir_entry| dir_entry.inode_index != 0)
+        .map(|dir_entry| (dir_entry.name, dir_entry.file_type))
+        .collect();
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        names,
+        [
+            (".".to_string(), Some(FileType::Dir)),
+            ("..".to_string(), Some(FileType::Dir)),
+            ("dir".to_string(), Some(FileType::Dir)),
+            ("hello.txt".to_string(), Some(FileType::RegularFile)),
+            ("lost+found".to_string(), Some(FileType::Dir)),
+        ]
+    );
+    // inode 0 and inodes past the inode count do not exist
+    assert_eq!(session.file_stat(0).err(), Some(StorageError::OutOfRange));
+    assert_eq!(session.file_stat(1 << 40).err(), Some(StorageError::OutOfRange));
+    assert_eq!(session.read_dir(0).err(), Some(StorageError::OutOfRange));
+}
+
+#[test]
+fn read_regular_files() {
+    for block_size in [1024, 4096] {
+        let image_path = make_image(&format!("read_regular_files_{}", block_size), block_size);
+        let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+        let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+        let hello_inode_index = lookup(&session, session.root(), "hello.txt");
+        let hello_file_stat = session.file_stat(hello_inode_index).unwrap();
+        assert_eq!(hello_file_stat.mode.file_type(), FileType::RegularFile);
+        assert_eq!(hello_file_stat.size, HELLO_DATA.len() as u64);
+        assert_eq!(session.read_regular_file_range(hello_inode_index, 0..hello_file_stat.size).unwrap(), HELLO_DATA);
+        assert_eq!(session.read_regular_file_range(hello_inode_index, 6..10).unwrap(), &HELLO_DATA[6..10]);
+        let big_inode_index = lookup(&session, lookup(&session, session.root(), "dir"), "big.bin");
+        let big_data = big_data();
+        assert_eq!(session.file_stat(big_inode_index).unwrap().size, big_data.len() as u64);
+        assert_eq!(session.read_regular_file_range(big_inode_index, 0..big_data.len() as u64).unwrap(), big_data);
+        assert_eq!(
+            session.read_regular_file_range(big_inode_index, 200_000..200_100).unwrap(),
+            &big_data[200_000..200_100]
+        );
+    }
+}
+
+#[test]
+fn read_with_4096_byte_sectors() {
+    let image_path = make_image("read_with_4096_byte_sectors", 4096);
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 4096).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let hello_inode_index = lookup(&session, session.root(), "hello.txt");
+    assert_eq!(
+        session.read_regular_file_range(hello_inode_index, 0..HELLO_DATA.len() as u64).unwrap(),
+        HELLO_DATA
+    );
+}
+
+#[test]
+fn overwrite_regular_file_range() {
+    let image_path = make_image("overwrite_regular_file_range", 1024);
+    let mut big_data = big_data();
+    let new_data: Vec<u8> = (0..150_000).map(|index: u32| (index % 251) as u8 | 1).collect();
+    big_data[1000..151_000].copy_from_slice(&new_data);
+    {
+        let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+        let mut session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+        let big_inode_index = lookup(&session, lookup(&session, session.root(), "dir"), "big.bin");
+        session.write_regular_file_range(big_inode_index, 1000..151_000, &new_data).unwrap();
+        session.write_regular_file_range(big_inode_index, 0..3, b"abc").unwrap();
+    }
+    big_data[0..3].copy_from_slice(b"abc");
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let big_inode_index = lookup(&session, lookup(&session, session.root(), "dir"), "big.bin");
+    assert_eq!(session.read_regular_file_range(big_inode_index, 0..big_data.len() as u64).unwrap(), big_data);
+    check_ext2_image(&image_path);
+}
+
+#[test]
+fn shrink_regular_file_past_indirect_blocks() {
+    let image_path = make_image("shrink_regular_file_past_indirect_blocks", 1024);
+    let big_data = big_data();
+    {
+        let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+        let mut session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+        let big_inode_index = lookup(&session, lookup(&session, session.root(), "dir"), "big.bin");
+        // from the doubly indirect blocks to the singly indirect ones, then to the direct ones
+        session.resize_regular_file(big_inode_index, 100 * 1024).unwrap();
+        session.resize_regular_file(big_inode_index, 5000).unwrap();
+    }
+    check_ext2_image(&image_path);
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let big_inode_index = lookup(&session, lookup(&session, session.root(), "dir"), "big.bin");
+    assert_eq!(session.file_stat(big_inode_index).unwrap().size, 5000);
+    assert_eq!(session.read_regular_file_range(big_inode_index, 0..5000).unwrap(), &big_data[..5000]);
+}
+
+#[test]
+fn fill_filesystem() {
+    let image_path = common::make_ext2_image("fill_filesystem", 1024, "2M", &[("file", b"")]);
+    let chunk_data = vec![0xA5; 64 * 1024];
+    {
+        let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+        let mut session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+        let file_inode_index = lookup(&session, session.root(), "file");
+        session.resize_regular_file(file_inode_index, 4 << 20).unwrap();
+        let mut offset = 0;
+        let error = loop {
+            if let Err(error) = session.write_regular_file_range(file_inode_index, offset..offset + chunk_data.len() as u64, &chunk_data) {
+                break error;
+            }
+            offset += chunk_data.len() as u64;
+        };
+        assert_eq!(error, StorageError::NoSpace);
+        assert!(offset > 1 << 20);
+        assert_eq!(
+            session.read_regular_file_range(file_inode_index, 0..offset).unwrap(),
+            vec![0xA5; offset as usize]
+        );
+        // shrinking the file makes room again
+        session.resize_regular_file(file_inode_index, chunk_data.len() as u64).unwrap();
+        session
+            .write_regular_file_range(file_inode_index, 0..chunk_data.len() as u64, &chunk_data)
+            .unwrap();
+    }
+    check_ext2_image(&image_path);
+}
+
+#[test]
+fn read_only_storage_rejects_writes() {
+    let image_path = make_image("read_only_storage_rejects_writes", 1024);
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+    let mut session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let hello_inode_index = lookup(&session, session.root(), "hello.txt");
+    assert_eq!(
+        session.write_regular_file_range(hello_inode_index, 0..5, b"HELLO"),
+        Err(StorageError::MediaReadOnly)
+    );
+}
+
+#[test]
+fn read_only_partition_rejects_writes() {
+    let image_path = make_image("read_only_partition_rejects_writes", 1024);
+    let image_data = fs::read(&image_path).unwrap();
+    let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+    assert!(!sector_storage.read_only());
+    let partition = Partition {
+        type_id: guid::TYPE_ID_LINUX,
+        id: guid::ZERO,
+        starting_sector: 0,
+        ending_sector: sector_storage.sector_count() - 1,
+        flags: partitions::FLAG_READ_ONLY,
+        name: None,
+    };
+    let partition_sector_storage = (&sector_storage, partition);
+    assert!(partition_sector_storage.read_only());
+    assert_eq!(partition_sector_storage.write_sector(0, &[0; 512]), Err(StorageError::MediaReadOnly));
+    let mut session = ext2::Session::new(&partition_sector_storage).unwrap().unwrap();
+    assert!(session.read_only());
+    let hello_inode_index = lookup(&session, session.root(), "hello.txt");
+    assert_eq!(session.create(FileType::RegularFile, 0o644), Err(StorageError::MediaReadOnly));
+    assert_eq!(session.set_links_count(hello_inode_index, 0), Err(StorageError::MediaReadOnly));
+    assert_eq!(session.resize_regular_file(hello_inode_index, 0), Err(StorageError::MediaReadOnly));
+    assert_eq!(session.remove(hello_inode_index), Err(StorageError::MediaReadOnly));
+    assert_eq!(
+        session.read_regular_file_range(hello_inode_index, 0..HELLO_DATA.len() as u64).unwrap(),
+        HELLO_DATA
+    );
+    assert_eq!(fs::read(&image_path).unwrap(), image_data);
+}
+
+#[test]
+fn journal_needing_recovery_rejects_writes() {
+    let image_path = make_image("journal_needing_recovery_rejects_writes", 1024);
+    let mut image_data = fs::read(&image_path).unwrap();
+    image_data[1024 + 96] |= 0x4; // the recover incompatible feature
+    fs::write(&image_path, &image_data).unwrap();
+    let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+    let mut session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    assert!(session.needs_recovery());
+    assert!(session.read_only());
+    let hello_inode_index = lookup(&session, session.root(), "hello.txt");
+    assert_eq!(
+        session.write_regular_file_range(hello_inode_index, 0..5, b"HELLO"),
+        Err(StorageError::MediaReadOnly)
+    );
+    assert_eq!(
+        session.read_regular_file_range(hello_inode_index, 0..HELLO_DATA.len() as u64).unwrap(),
+        HELLO_DATA
+    );
+    assert_eq!(fs::read(&image_path).unwrap(), image_data);
+}
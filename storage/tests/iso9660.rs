@@ -0,0 +1,138 @@
+use storage::{
+    fs::{FileType, Session},
+    iso9660, ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const HELLO_DATA: &[u8] = b"Hello from a disc!\n";
+
+fn dir_record(extent_block_index: u32, data_len: u32, flags: u8, file_identifier: &[u8]) -> Vec<u8> {
+    let mut dir_record_data = vec![0; (33 + file_identifier.len()).next_multiple_of(2)];
+    dir_record_data[0] = dir_record_data.len() as u8;
+    dir_record_data[2..6].copy_from_slice(&extent_block_index.to_le_bytes());
+    dir_record_data[10..14].copy_from_slice(&data_len.to_le_bytes());
+    dir_record_data[25] = flags;
+    dir_record_data[32] = file_identifier.len() as u8;
+    dir_record_data[33..33 + file_identifier.len()].copy_from_slice(file_identifier);
+    dir_record_data
+}
+
+// a root directory in block 18 holding HELLO.TXT in block 19
+fn make_disc() -> ram_disk::DiskSectorStorage {
+    let disk = ram_disk::DiskSectorStorage::new(2048, 32);
+    let mut volume_descriptor_data = vec![0; 2048];
+    volume_descriptor_data[0] = 1;
+    volume_descriptor_data[1..7].copy_from_slice(b"CD001\x01");
+    volume_descriptor_data[128..130].copy_from_slice(&2048u16.to_le_bytes());
+    volume_descriptor_data[156..190].copy_from_slice(&dir_record(18, 2048, 2, &[0]));
+    disk.write_sector(16, &volume_descriptor_data).unwrap();
+    let mut terminator_data = vec![0; 2048];
+    terminator_data[0] = 255;
+    terminator_data[1..7].copy_from_slice(b"CD001\x01");
+    disk.write_sector(17, &terminator_data).unwrap();
+    let mut root_dir_data = [
+        dir_record(18, 2048, 2, &[0]),
+        dir_record(18, 2048, 2, &[1]),
+        dir_record(19, HELLO_DATA.len() as u32, 0, b"HELLO.TXT;1"),
+    ]
+    .concat();
+    root_dir_data.resize(2048, 0);
+    disk.write_sector(18, &root_dir_data).unwrap();
+    let mut hello_data = HELLO_DATA.to_vec();
+    hello_data.resize(2048, 0);
+    disk.write_sector(19, &hello_data).unwrap();
+    disk
+}
+
+fn with_system_use(mut dir_record_data: Vec<u8>, system_use: &[u8]) -> Vec<u8> {
+    dir_record_data.extend_from_slice(system_use);
+    if dir_record_data.len() % 2 != 0 {
+        dir_record_data.push(0);
+    }
+    dir_record_data[0] = dir_record_data.len() as u8;
+    dir_record_data
+}
+
+// Rock Ridge with a PX entry giving HELLO.TXT the mode
+fn make_rock_ridge_disc(mode: u32) -> ram_disk::DiskSectorStorage {
+    let disk = make_disc();
+    let mut posix_attributes = [0; 36];
+    posix_attributes[0..4].copy_from_slice(b"PX\x24\x01");
+    posix_attributes[4..8].copy_from_slice(&mode.to_le_bytes());
+    posix_attributes[8..12].copy_from_slice(&mode.to_be_bytes());
+    posix_attributes[12..16].copy_from_slice(&1u32.to_le_bytes());
+    let mut root_dir_data = [
+        with_system_use(dir_record(18, 2048, 2, &[0]), b"SP\x07\x01\xBE\xEF\x00"),
+        dir_record(18, 2048, 2, &[1]),
+        with_system_use(dir_record(19, HELLO_DATA.len() as u32, 0, b"HELLO.TXT;1"), &posix_attributes),
+    ]
+    .concat();
+    root_dir_data.resize(2048, 0);
+    disk.write_sector(18, &root_dir_data).unwrap();
+    disk
+}
+
+fn patch(disk: &ram_disk::DiskSectorStorage, sector_index: u64, offset: usize, data: &[u8]) {
+    let mut sector_data = disk.read_sector(sector_index).unwrap();
+    sector_data[offset..offset + data.len()].copy_from_slice(data);
+    disk.write_sector(sector_index, &sector_data).unwrap();
+}
+
+#[test]
+fn read_files() {
+    let disk = make_disc();
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    let dir_entries = session.read_dir(session.root()).unwrap();
+    let names: Vec<_> = dir_entries.iter().map(|dir_entry| dir_entry.name.as_str()).collect();
+    assert_eq!(names, [".", "..", "HELLO.TXT"]);
+    assert_eq!(dir_entries[2].file_type, Some(FileType::RegularFile));
+    assert_eq!(session.read_regular_file_range(dir_entries[2].inode_index, 0..1024).unwrap(), HELLO_DATA);
+}
+
+#[test]
+fn reject_malformed_volume_descriptors() {
+    // no logical block size
+    let disk = make_disc();
+    patch(&disk, 16, 128, &0u16.to_le_bytes());
+    assert!(iso9660::Session::new(&disk).unwrap().is_none());
+    // a root dir record whose file identifier runs past its end
+    let disk = make_disc();
+    patch(&disk, 16, 156 + 32, &[200]);
+    assert!(iso9660::Session::new(&disk).unwrap().is_none());
+    // a root dir extent past the end of the disc
+    let disk = make_disc();
+    patch(&disk, 16, 156 + 2, &1000u32.to_le_bytes());
+    assert!(iso9660::Session::new(&disk).unwrap().is_none());
+}
+
+#[test]
+fn reject_malformed_dir_records() {
+    // a record shorter than the fixed part
+    let disk = make_disc();
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    patch(&disk, 18, 68, &[10]);
+    assert_eq!(session.read_dir(session.root()).err(), Some(StorageError::OutOfRange));
+    // a record running past the end of the extent
+    let disk = make_disc();
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    patch(&disk, 18, 10, &100u32.to_le_bytes());
+    assert_eq!(session.read_dir(session.root()).err(), Some(StorageError::OutOfRange));
+    // a file identifier running past the end of its record
+    let disk = make_disc();
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    patch(&disk, 18, 68 + 32, &[100]);
+    assert_eq!(session.read_dir(session.root()).err(), Some(StorageError::OutOfRange));
+    assert_eq!(session.file_stat(18 * 2048 + 68).err(), Some(StorageError::OutOfRange));
+    // a Rock Ridge mode with unknown file type bits falls back to the dir flag
+    let disk = make_rock_ridge_disc(0o644);
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    let dir_entries = session.read_dir(session.root()).unwrap();
+    assert_eq!(dir_entries[2].file_type, Some(FileType::RegularFile));
+    let file_stat = session.file_stat(dir_entries[2].inode_index).unwrap();
+    assert_eq!(file_stat.mode.file_type(), FileType::RegularFile);
+    assert_eq!(file_stat.mode.permissions(), 0o644);
+    let disk = make_rock_ridge_disc(0o170755);
+    let session = iso9660::Session::new(&disk).unwrap().unwrap();
+    let dir_entries = session.read_dir(session.root()).unwrap();
+    assert_eq!(session.file_stat(dir_entries[2].inode_index).unwrap().mode.file_type(), FileType::RegularFile);
+}
@@ -0,0 +1,80 @@
+use std::{fs, path::PathBuf, process::Command};
+
+use storage::{
+    ext2, file_disk,
+    fs::Session,
+    guid::{self, Guid},
+    loop_disk,
+    partitions::{self, PartitionTable},
+    probe::{self, ContentType},
+    sector_storage::{SectorStorage, StorageError},
+};
+
+mod common;
+
+const HELLO_DATA: &[u8] = b"Hello from a nested filesystem!\n";
+
+// an ext2 image whose root holds the given disk image as disk.img
+fn make_image(name: &str, disk_image_data: &[u8]) -> PathBuf {
+    common::make_ext2_image(name, 1024, "8M", &[("disk.img", disk_image_data)])
+}
+
+fn lookup(session: &impl Session, name: &str) -> u64 {
+    common::lookup(session, session.root(), name)
+}
+
+#[test]
+fn read_nested_filesystem() {
+    let inner_image_path = common::make_ext2_image("read_nested_filesystem_inner", 1024, "1M", &[("hello.txt", HELLO_DATA)]);
+    let image_path = make_image("read_nested_filesystem", &fs::read(inner_image_path).unwrap());
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(&image_path, 512).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let disk_inode_index = lookup(&session, "disk.img");
+    assert!(loop_disk::DiskSectorStorage::new(session, 2, 512).unwrap().is_none());
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    assert_eq!(loop_disk::DiskSectorStorage::new(session, 0, 512).err(), Some(StorageError::OutOfRange));
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    assert_eq!(loop_disk::DiskSectorStorage::new(session, 1 << 40, 512).err(), Some(StorageError::OutOfRange));
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let loop_sector_storage = loop_disk::DiskSectorStorage::new(session, disk_inode_index, 512).unwrap().unwrap();
+    assert_eq!(loop_sector_storage.len(), 1 << 20);
+    assert!(loop_sector_storage.read_only());
+    assert_eq!(loop_sector_storage.write_sector(0, &[0; 512]), Err(StorageError::MediaReadOnly));
+    assert_eq!(loop_sector_storage.read_sector(2048), Err(StorageError::OutOfRange));
+    assert_eq!(probe::probe(&loop_sector_storage).unwrap().unwrap().content_type, ContentType::Ext2);
+    let nested_session = ext2::Session::new(&loop_sector_storage).unwrap().unwrap();
+    let hello_inode_index = lookup(&nested_session, "hello.txt");
+    assert_eq!(
+        nested_session.read_regular_file_range(hello_inode_index, 0..HELLO_DATA.len() as u64).unwrap(),
+        HELLO_DATA
+    );
+}
+
+#[test]
+fn write_partition_table_to_image_file() {
+    let image_path = make_image("write_partition_table_to_image_file", &vec![0; 2 << 20]);
+    let sector_storage = file_disk::DiskSectorStorage::open(&image_path, 512).unwrap();
+    let session = ext2::Session::new(&sector_storage).unwrap().unwrap();
+    let disk_inode_index = lookup(&session, "disk.img");
+    let loop_sector_storage = loop_disk::DiskSectorStorage::new(session, disk_inode_index, 512).unwrap().unwrap();
+    let mut partition_table = PartitionTable::new_gpt(Guid::from_bytes([0x44; 16]));
+    partition_table
+        .create_partition(&loop_sector_storage, guid::TYPE_ID_LINUX, Guid::from_bytes([1; 16]), 1024, Some("nested"))
+        .unwrap();
+    partition_table.write_gpt(&loop_sector_storage).unwrap();
+    assert_eq!(partitions::read_partition_table(&loop_sector_storage).unwrap().as_ref(), Some(&partition_table));
+    let session = loop_sector_storage.into_session();
+    assert_eq!(session.file_stat(disk_inode_index).unwrap().size, 2 << 20);
+    drop(session);
+    drop(sector_storage);
+
+    common::check_ext2_image(&image_path);
+    let dir_path = image_path.parent().unwrap();
+    common::run(
+        Command::new("debugfs")
+            .args(["-R", &format!("dump disk.img {}", dir_path.join("disk.img").display())])
+            .arg(&image_path),
+    );
+    let sector_storage = file_disk::DiskSectorStorage::open_read_only(dir_path.join("disk.img"), 512).unwrap();
+    assert_eq!(partitions::read_partition_table(&sector_storage).unwrap(), Some(partition_table));
+}
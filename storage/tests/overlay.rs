@@ -0,0 +1,83 @@
+use storage::{
+    overlay::OverlaySectorStorage,
+    ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+fn sector(value: u8) -> Vec<u8> {
+    vec![value; SECTOR_SIZE as usize]
+}
+
+fn make_overlay(delta_sector_count: u64) -> OverlaySectorStorage<ram_disk::DiskSectorStorage, ram_disk::DiskSectorStorage> {
+    let base_data: Vec<u8> = (0..64).flat_map(|sector_index| sector(sector_index as u8)).collect();
+    OverlaySectorStorage::new(
+        ram_disk::DiskSectorStorage::from_data(SECTOR_SIZE, base_data),
+        ram_disk::DiskSectorStorage::new(SECTOR_SIZE, delta_sector_count),
+    )
+}
+
+#[test]
+fn writes_go_to_delta() {
+    let overlay = make_overlay(16);
+    overlay.write_sectors(10, &[sector(0xAA), sector(0xBB)].concat()).unwrap();
+    assert_eq!(
+        overlay.read_sectors(9, 4).unwrap(),
+        [sector(9), sector(0xAA), sector(0xBB), sector(12)].concat()
+    );
+    assert_eq!(overlay.base_sector_storage().read_sectors(10, 2).unwrap(), [sector(10), sector(11)].concat());
+    assert_eq!(overlay.changed_sector_count(), 2);
+    overlay.write_bytes(11 * SECTOR_SIZE + 3, b"xyz").unwrap();
+    let mut expected_sector = sector(0xBB);
+    expected_sector[3..6].copy_from_slice(b"xyz");
+    assert_eq!(overlay.read_sector(11).unwrap(), expected_sector);
+    assert_eq!(overlay.changed_sector_count(), 2);
+}
+
+#[test]
+fn discard_changes_and_commit() {
+    let overlay = make_overlay(16);
+    overlay.write_sector(3, &sector(0xCC)).unwrap();
+    overlay.discard_changes();
+    assert_eq!(overlay.read_sector(3).unwrap(), sector(3));
+    overlay.write_sectors(3, &[sector(0xCC), sector(0xDD)].concat()).unwrap();
+    overlay.write_sector(40, &sector(0xEE)).unwrap();
+    overlay.commit().unwrap();
+    assert_eq!(overlay.changed_sector_count(), 0);
+    assert_eq!(overlay.base_sector_storage().read_sectors(3, 2).unwrap(), [sector(0xCC), sector(0xDD)].concat());
+    assert_eq!(overlay.base_sector_storage().read_sector(40).unwrap(), sector(0xEE));
+}
+
+#[test]
+fn snapshots() {
+    let overlay = make_overlay(16);
+    overlay.write_sector(5, &sector(1)).unwrap();
+    overlay.take_snapshot("first");
+    overlay.write_sector(5, &sector(2)).unwrap();
+    overlay.write_sector(6, &sector(2)).unwrap();
+    overlay.take_snapshot("second");
+    overlay.write_sector(5, &sector(3)).unwrap();
+    assert_eq!(overlay.snapshot_names(), ["first", "second"]);
+    assert!(overlay.restore_snapshot("first"));
+    assert_eq!(overlay.read_sectors(5, 2).unwrap(), [sector(1), sector(6)].concat());
+    overlay.write_sector(5, &sector(4)).unwrap();
+    assert!(overlay.restore_snapshot("second"));
+    assert_eq!(overlay.read_sectors(5, 2).unwrap(), [sector(2), sector(2)].concat());
+    assert!(overlay.restore_snapshot("first"));
+    assert_eq!(overlay.read_sector(5).unwrap(), sector(1));
+    assert!(overlay.delete_snapshot("second"));
+    assert!(!overlay.restore_snapshot("second"));
+    assert_eq!(overlay.snapshot_names(), ["first"]);
+}
+
+#[test]
+fn delta_full() {
+    let overlay = make_overlay(2);
+    overlay.write_sectors(0, &[sector(0xAA), sector(0xBB)].concat()).unwrap();
+    assert_eq!(overlay.write_sector(2, &sector(0xCC)), Err(StorageError::NoSpace));
+    overlay.write_sector(1, &sector(0xCC)).unwrap();
+    overlay.take_snapshot("full");
+    assert_eq!(overlay.write_sector(1, &sector(0xDD)), Err(StorageError::NoSpace));
+    assert_eq!(overlay.read_sectors(0, 3).unwrap(), [sector(0xAA), sector(0xCC), sector(2)].concat());
+}
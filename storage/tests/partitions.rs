@@ -0,0 +1,229 @@
+use storage::{
+    guid::{self, Guid},
+    partitions::{self, PartitionTable, PartitionTableKind, FLAG_LEGACY_BIOS_BOOTABLE},
+    ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const SECTOR_SIZE: u64 = 512;
+const DISK_SIGNATURE: u32 = 0x1234_ABCD;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// entries are (status, type, starting sector, sector count)
+fn write_mbr(disk: &impl SectorStorage, sector_index: u64, disk_signature: u32, entries: &[(u8, u8, u32, u32)]) {
+    let mut sector_data = vec![0; SECTOR_SIZE as usize];
+    sector_data[440..444].copy_from_slice(&disk_signature.to_le_bytes());
+    for (entry_index, &(status, partition_type, starting_sector, sector_count)) in entries.iter().enumerate() {
+        let entry_data = &mut sector_data[446 + entry_index * 16..446 + (entry_index + 1) * 16];
+        entry_data[0] = status;
+        entry_data[4] = partition_type;
+        entry_data[8..12].copy_from_slice(&starting_sector.to_le_bytes());
+        entry_data[12..16].copy_from_slice(&sector_count.to_le_bytes());
+    }
+    sector_data[510..512].copy_from_slice(&[0x55, 0xAA]);
+    disk.write_sector(sector_index, &sector_data).unwrap();
+}
+
+fn mbr_partition_id(partition_number: u8) -> Guid {
+    let mut id_bytes = [0; 16];
+    id_bytes[0..4].copy_from_slice(&DISK_SIGNATURE.to_le_bytes());
+    id_bytes[4] = partition_number;
+    Guid::from_bytes(id_bytes)
+}
+
+#[test]
+fn mbr_primary_and_logical_partitions() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 4096);
+    write_mbr(
+        &disk,
+        0,
+        DISK_SIGNATURE,
+        &[(0x80, 0x83, 2048, 1024), (0, 0, 0, 0), (0, 0x0F, 3072, 1024), (0, 0x07, 100, 100)],
+    );
+    // EBRs at 3072 and 3584, the second one linked relative to the extended partition
+    write_mbr(&disk, 3072, 0, &[(0, 0xFD, 64, 256), (0, 0x05, 512, 512)]);
+    write_mbr(&disk, 3584, 0, &[(0, 0x42, 1, 511)]);
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    let partitions: Vec<_> = partition_table
+        .partitions
+        .iter()
+        .map(|partition| (&partition.type_id, &partition.id, partition.starting_sector, partition.ending_sector))
+        .collect();
+    let mut unknown_type_id_bytes = [0; 16];
+    unknown_type_id_bytes[0] = 0x42;
+    assert_eq!(
+        partitions,
+        [
+            (&guid::TYPE_ID_LINUX, &mbr_partition_id(1), 2048, 3071),
+            (&guid::TYPE_ID_MICROSOFT_BASIC_DATA, &mbr_partition_id(4), 100, 199),
+            (&guid::TYPE_ID_LINUX_RAID, &mbr_partition_id(5), 3136, 3391),
+            (&Guid::from_bytes(unknown_type_id_bytes), &mbr_partition_id(6), 3585, 4095),
+        ]
+    );
+    assert_eq!(partition_table.partitions[0].flags, FLAG_LEGACY_BIOS_BOOTABLE);
+    assert_eq!(partition_table.partitions[1].flags, 0);
+    assert_eq!(partition_table.id, mbr_partition_id(0));
+    assert_eq!(partition_table.kind, PartitionTableKind::Mbr);
+}
+
+#[test]
+fn mbr_rejects_non_partition_tables() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 64);
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+    // a boot sector whose code overlaps the entries
+    write_mbr(&disk, 0, 0, &[(0xEB, 0x3C, 0x90, 0)]);
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+    // a protective MBR whose GPT is missing
+    write_mbr(&disk, 0, 0, &[(0, 0xEE, 1, 63)]);
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+}
+
+// a GPT of 128 entries with a single Linux partition at sectors 64..=127 of a 256-sector disk
+fn write_gpt(disk: &impl SectorStorage, header_sector_index: u64, partition_entries_starting_sector: u64) {
+    let mut partition_entries_data = vec![0; 128 * 128];
+    partition_entries_data[0..16].copy_from_slice(&[0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+    partition_entries_data[16..32].fill(0x11);
+    partition_entries_data[32..40].copy_from_slice(&64u64.to_le_bytes());
+    partition_entries_data[40..48].copy_from_slice(&127u64.to_le_bytes());
+    let mut header_data = vec![0; SECTOR_SIZE as usize];
+    header_data[0..8].copy_from_slice(b"EFI PART");
+    header_data[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header_data[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header_data[24..32].copy_from_slice(&header_sector_index.to_le_bytes());
+    header_data[32..40].copy_from_slice(&(256 - header_sector_index).to_le_bytes());
+    header_data[40..48].copy_from_slice(&34u64.to_le_bytes());
+    header_data[48..56].copy_from_slice(&222u64.to_le_bytes());
+    header_data[56..72].fill(0x22);
+    header_data[72..80].copy_from_slice(&partition_entries_starting_sector.to_le_bytes());
+    header_data[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header_data[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header_data[88..92].copy_from_slice(&crc32(&partition_entries_data).to_le_bytes());
+    let header_crc32 = crc32(&header_data[0..92]);
+    header_data[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+    disk.write_sector(header_sector_index, &header_data).unwrap();
+    disk.write_sectors(partition_entries_starting_sector, &partition_entries_data).unwrap();
+}
+
+fn assert_gpt_partitions(partition_table: &PartitionTable) {
+    assert_eq!(partition_table.id, Guid::from_bytes([0x22; 16]));
+    assert_eq!(partition_table.partitions.len(), 1);
+    assert_eq!(partition_table.partitions[0].type_id, guid::TYPE_ID_LINUX);
+    assert_eq!(partition_table.partitions[0].id, Guid::from_bytes([0x11; 16]));
+    assert_eq!(
+        (partition_table.partitions[0].starting_sector, partition_table.partitions[0].ending_sector),
+        (64, 127)
+    );
+}
+
+#[test]
+fn gpt_takes_precedence_over_hybrid_mbr() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 256);
+    write_mbr(&disk, 0, DISK_SIGNATURE, &[(0, 0xEE, 1, 33), (0, 0x0C, 34, 30)]);
+    write_gpt(&disk, 1, 2);
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(partition_table.kind, PartitionTableKind::PrimaryGpt);
+    assert_gpt_partitions(&partition_table);
+    // a GPT left behind by an MBR without a protective entry is stale
+    write_mbr(&disk, 0, DISK_SIGNATURE, &[(0, 0x0C, 34, 30)]);
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(partition_table.kind, PartitionTableKind::Mbr);
+    assert_eq!(partition_table.partitions.len(), 1);
+}
+
+#[test]
+fn gpt_falls_back_to_backup() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 256);
+    write_mbr(&disk, 0, 0, &[(0, 0xEE, 1, 255)]);
+    write_gpt(&disk, 1, 2);
+    write_gpt(&disk, 255, 223);
+    // a corrupt partition entry array
+    disk.write_sector(2, &[0xFF; SECTOR_SIZE as usize]).unwrap();
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(partition_table.kind, PartitionTableKind::BackupGpt);
+    assert_gpt_partitions(&partition_table);
+    // a corrupt backup header as well
+    let mut header_data = disk.read_sector(255).unwrap();
+    header_data[48] ^= 1;
+    disk.write_sector(255, &header_data).unwrap();
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+}
+
+#[test]
+fn backup_gpt_needs_protective_mbr() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 256);
+    write_gpt(&disk, 255, 223);
+    // a stale backup GPT behind a valid MBR
+    write_mbr(&disk, 0, DISK_SIGNATURE, &[(0x80, 0x83, 64, 128)]);
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(partition_table.kind, PartitionTableKind::Mbr);
+    assert_eq!(partition_table.partitions.len(), 1);
+    assert_eq!(partition_table.partitions[0].type_id, guid::TYPE_ID_LINUX);
+    assert_eq!(partition_table.partitions[0].id, mbr_partition_id(1));
+    // and without any MBR
+    disk.write_sector(0, &[0; SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+    // a protective MBR makes it count
+    write_mbr(&disk, 0, 0, &[(0, 0xEE, 1, 255)]);
+    let partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(partition_table.kind, PartitionTableKind::BackupGpt);
+    assert_gpt_partitions(&partition_table);
+}
+
+#[test]
+fn gpt_editing_round_trip() {
+    let disk = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 8192);
+    let mut partition_table = PartitionTable::new_gpt(Guid::from_bytes([0x33; 16]));
+    let partition = partition_table
+        .create_partition(&disk, guid::TYPE_ID_LINUX, Guid::from_bytes([1; 16]), 2048, Some("kernel_root"))
+        .unwrap();
+    assert_eq!((partition.starting_sector, partition.ending_sector), (2048, 4095));
+    let partition = partition_table
+        .create_partition(&disk, guid::TYPE_ID_LINUX_RAID, Guid::from_bytes([2; 16]), 1024, None)
+        .unwrap();
+    assert_eq!((partition.starting_sector, partition.ending_sector), (4096, 5119));
+    assert_eq!(
+        partition_table
+            .create_partition(&disk, guid::TYPE_ID_LINUX, Guid::from_bytes([3; 16]), 4096, None)
+            .err(),
+        Some(StorageError::NoSpace)
+    );
+    partition_table.write_gpt(&disk).unwrap();
+    assert_eq!(partitions::read_partition_table(&disk).unwrap().as_ref(), Some(&partition_table));
+
+    partition_table.delete_partition(&Guid::from_bytes([1; 16])).unwrap();
+    partition_table
+        .rename_partition(&Guid::from_bytes([2; 16]), Some("a name of exactly thirty-six chars!!"))
+        .unwrap();
+    assert_eq!(
+        partition_table.rename_partition(&Guid::from_bytes([2; 16]), Some("a name of thirty-seven chars is long!")),
+        Err(StorageError::OutOfRange)
+    );
+    assert_eq!(
+        partition_table.resize_partition(&disk, &Guid::from_bytes([2; 16]), 8192),
+        Err(StorageError::NoSpace)
+    );
+    partition_table.resize_partition(&disk, &Guid::from_bytes([2; 16]), 2048).unwrap();
+    let partition = partition_table
+        .create_partition(&disk, guid::TYPE_ID_LINUX, Guid::from_bytes([3; 16]), 1024, None)
+        .unwrap();
+    assert_eq!(partition.starting_sector, 2048);
+    partition_table.write_gpt(&disk).unwrap();
+    disk.write_sector(1, &[0; SECTOR_SIZE as usize]).unwrap();
+    let read_partition_table = partitions::read_partition_table(&disk).unwrap().unwrap();
+    assert_eq!(read_partition_table.kind, PartitionTableKind::BackupGpt);
+    assert_eq!(read_partition_table.partitions, partition_table.partitions);
+    assert_eq!(read_partition_table.partitions[0].name.as_deref(), Some("a name of exactly thirty-six chars!!"));
+    // the protective MBR is left once both GPTs are gone
+    disk.write_sector(8191, &[0; SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(partitions::read_partition_table(&disk).unwrap(), None);
+}
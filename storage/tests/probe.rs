@@ -0,0 +1,128 @@
+use std::{fs, path::Path, process::Command};
+
+use storage::{
+    ext2, file_disk,
+    probe::{self, Content, ContentType},
+    ram_disk,
+    sector_storage::SectorStorage,
+};
+
+mod common;
+
+const UUID: &str = "0123abcd-4567-89ef-0123-456789abcdef";
+
+fn probe_image(image_path: &Path) -> Option<Content> {
+    probe::probe(&file_disk::DiskSectorStorage::open_read_only(image_path, 512).unwrap()).unwrap()
+}
+
+fn content(content_type: ContentType, label: Option<&str>, uuid: Option<&str>) -> Option<Content> {
+    Some(Content {
+        content_type,
+        label: label.map(String::from),
+        uuid: uuid.map(String::from),
+    })
+}
+
+#[test]
+fn probe_ext() {
+    for (mkfs, content_type) in [
+        ("mkfs.ext2", ContentType::Ext2),
+        ("mkfs.ext3", ContentType::Ext3),
+        ("mkfs.ext4", ContentType::Ext4),
+    ] {
+        let image_path = common::make_empty_image(mkfs, 8 << 20);
+        common::run(Command::new(mkfs).args(["-q", "-F", "-L", "kernel_root", "-U", UUID]).arg(&image_path));
+        assert_eq!(probe_image(&image_path), content(content_type, Some("kernel_root"), Some(UUID)));
+    }
+}
+
+#[test]
+fn probe_swap() {
+    let image_path = common::make_empty_image("mkswap", 1 << 20);
+    common::run(Command::new("mkswap").args(["-L", "swap", "-U", UUID]).arg(&image_path));
+    assert_eq!(probe_image(&image_path), content(ContentType::Swap, Some("swap"), Some(UUID)));
+}
+
+#[test]
+fn probe_luks() {
+    let image_path = common::make_empty_image("luksFormat", 32 << 20);
+    let key_file_path = image_path.with_file_name("key");
+    fs::write(&key_file_path, b"passphrase").unwrap();
+    common::run(
+        Command::new("cryptsetup")
+            .args([
+                "luksFormat",
+                "--batch-mode",
+                "--type",
+                "luks2",
+                "--pbkdf",
+                "pbkdf2",
+                "--pbkdf-force-iterations",
+                "1000",
+            ])
+            .args(["--label", "secret", "--uuid", UUID, "--key-file"])
+            .arg(&key_file_path)
+            .arg(&image_path),
+    );
+    assert_eq!(probe_image(&image_path), content(ContentType::Luks, Some("secret"), Some(UUID)));
+}
+
+#[test]
+fn probe_fat() {
+    // a 16 MiB FAT16 volume with 4 sectors per cluster
+    let disk = ram_disk::DiskSectorStorage::new(512, 32768);
+    let mut boot_sector_data = vec![0; 512];
+    boot_sector_data[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot_sector_data[3..11].copy_from_slice(b"mkfs.fat");
+    boot_sector_data[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot_sector_data[13] = 4;
+    boot_sector_data[14..16].copy_from_slice(&4u16.to_le_bytes());
+    boot_sector_data[16] = 2;
+    boot_sector_data[17..19].copy_from_slice(&512u16.to_le_bytes());
+    boot_sector_data[19..21].copy_from_slice(&32768u16.to_le_bytes());
+    boot_sector_data[21] = 0xF8;
+    boot_sector_data[22..24].copy_from_slice(&32u16.to_le_bytes());
+    boot_sector_data[38] = 0x29;
+    boot_sector_data[39..43].copy_from_slice(&0x1234_ABCDu32.to_le_bytes());
+    boot_sector_data[43..54].copy_from_slice(b"EFI        ");
+    boot_sector_data[54..62].copy_from_slice(b"FAT16   ");
+    boot_sector_data[510..512].copy_from_slice(&[0x55, 0xAA]);
+    disk.write_sector(0, &boot_sector_data).unwrap();
+    assert_eq!(probe::probe(&disk).unwrap(), content(ContentType::Fat16, Some("EFI"), Some("1234-ABCD")));
+    boot_sector_data[43..54].copy_from_slice(b"NO NAME    ");
+    boot_sector_data[13] = 32;
+    disk.write_sector(0, &boot_sector_data).unwrap();
+    assert_eq!(probe::probe(&disk).unwrap(), content(ContentType::Fat12, None, Some("1234-ABCD")));
+    // not a power of two sectors per cluster
+    boot_sector_data[13] = 3;
+    disk.write_sector(0, &boot_sector_data).unwrap();
+    assert_eq!(probe::probe(&disk).unwrap(), None);
+}
+
+#[test]
+fn probe_iso9660() {
+    let disk = ram_disk::DiskSectorStorage::new(2048, 64);
+    let mut volume_descriptor_data = vec![0; 2048];
+    volume_descriptor_data[0] = 1;
+    volume_descriptor_data[1..7].copy_from_slice(b"CD001\x01");
+    volume_descriptor_data[40..72].copy_from_slice(b"INSTALL_DISC                    ");
+    volume_descriptor_data[813..830].copy_from_slice(b"2024031512304599\x00");
+    disk.write_sector(16, &volume_descriptor_data).unwrap();
+    let mut terminator_data = vec![0; 2048];
+    terminator_data[0] = 255;
+    terminator_data[1..7].copy_from_slice(b"CD001\x01");
+    disk.write_sector(17, &terminator_data).unwrap();
+    assert_eq!(
+        probe::probe(&disk).unwrap(),
+        content(ContentType::Iso9660, Some("INSTALL_DISC"), Some("2024-03-15-12-30-45-99"))
+    );
+}
+
+#[test]
+fn probe_unknown_contents() {
+    let disk = ram_disk::DiskSectorStorage::new(512, 8192);
+    assert_eq!(probe::probe(&disk).unwrap(), None);
+    assert!(ext2::Session::new(&disk).unwrap().is_none());
+    // too small for most superblocks
+    assert_eq!(probe::probe(&ram_disk::DiskSectorStorage::new(512, 1)).unwrap(), None);
+}
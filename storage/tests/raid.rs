@@ -0,0 +1,225 @@
+use std::cell::Cell;
+
+use storage::{
+    raid::{self, Layout, RaidSectorStorage},
+    ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+const SECTOR_SIZE: u64 = 512;
+const MEMBER_SIZE: u64 = 1 << 20;
+const DATA_OFFSET: u64 = 64 << 10;
+const ARRAY_ID: [u8; 16] = *b"0123456789abcdef";
+
+struct SuperblockFields {
+    level: u32,
+    chunk_sectors: u32,
+    raid_disks: u32,
+    dev_number: u32,
+    events: u64,
+    recovery_offset: Option<u64>,
+}
+
+// md superblock 1.2 as written by mdadm, with all members in their dev_number role
+fn write_superblock(member: &ram_disk::DiskSectorStorage, fields: SuperblockFields) {
+    let mut data = vec![0u8; 4096];
+    let max_dev = 128u32;
+    data[0..4].copy_from_slice(&0xa92b4efcu32.to_le_bytes());
+    data[4..8].copy_from_slice(&1u32.to_le_bytes());
+    data[8..12].copy_from_slice(&(if fields.recovery_offset.is_some() { 2u32 } else { 0 }).to_le_bytes());
+    data[16..32].copy_from_slice(&ARRAY_ID);
+    data[32..42].copy_from_slice(b"host:array");
+    data[72..76].copy_from_slice(&fields.level.to_le_bytes());
+    let data_sectors = (MEMBER_SIZE - DATA_OFFSET) / 512;
+    data[80..88].copy_from_slice(&(if fields.level == 1 { data_sectors } else { 0 }).to_le_bytes());
+    data[88..92].copy_from_slice(&fields.chunk_sectors.to_le_bytes());
+    data[92..96].copy_from_slice(&fields.raid_disks.to_le_bytes());
+    data[128..136].copy_from_slice(&(DATA_OFFSET / 512).to_le_bytes());
+    data[136..144].copy_from_slice(&data_sectors.to_le_bytes());
+    data[144..152].copy_from_slice(&8u64.to_le_bytes());
+    data[152..160].copy_from_slice(&fields.recovery_offset.unwrap_or(0).to_le_bytes());
+    data[160..164].copy_from_slice(&fields.dev_number.to_le_bytes());
+    data[200..208].copy_from_slice(&fields.events.to_le_bytes());
+    data[220..224].copy_from_slice(&max_dev.to_le_bytes());
+    for dev_number in 0..max_dev as usize {
+        let role = if (dev_number as u32) < fields.raid_disks { dev_number as u16 } else { 0xffff };
+        data[256 + dev_number * 2..258 + dev_number * 2].copy_from_slice(&role.to_le_bytes());
+    }
+    let mut sum: u64 = data[..256 + max_dev as usize * 2]
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as u64)
+        .sum();
+    sum = (sum & 0xffffffff) + (sum >> 32);
+    data[216..220].copy_from_slice(&(sum as u32).to_le_bytes());
+    member.write_bytes(4096, &data).unwrap();
+}
+
+fn make_members(level: u32, chunk_sectors: u32, events: &[u64]) -> Vec<(ram_disk::DiskSectorStorage, raid::Superblock)> {
+    events
+        .iter()
+        .enumerate()
+        .map(|(dev_number, &member_events)| {
+            let member = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, MEMBER_SIZE / SECTOR_SIZE);
+            write_superblock(
+                &member,
+                SuperblockFields {
+                    level,
+                    chunk_sectors,
+                    raid_disks: events.len() as u32,
+                    dev_number: dev_number as u32,
+                    events: member_events,
+                    recovery_offset: None,
+                },
+            );
+            let superblock = raid::read_superblock(&member).unwrap().expect("no md superblock");
+            (member, superblock)
+        })
+        .collect()
+}
+
+fn pattern(len: u64) -> Vec<u8> {
+    (0..len).map(|index| (index % 251) as u8).collect()
+}
+
+#[test]
+fn stripe() {
+    let members = make_members(0, 8, &[5, 5, 5]);
+    assert_eq!(members[1].1.role, Some(1));
+    let array = RaidSectorStorage::new(members).unwrap();
+    assert_eq!(array.layout(), Layout::Stripe { chunk_size: 4096 });
+    assert_eq!(array.name(), "host:array");
+    assert_eq!(array.array_id(), ARRAY_ID);
+    assert_eq!(array.len(), 3 * (MEMBER_SIZE - DATA_OFFSET));
+    let data = pattern(5 * 4096 + 1024);
+    array.write_bytes(4096 + 512, &data).unwrap();
+    assert_eq!(array.read_bytes(4096 + 512, data.len() as u64).unwrap(), data);
+}
+
+#[test]
+fn stripe_member_layout() {
+    let members = make_members(0, 8, &[1, 1]);
+    let array = RaidSectorStorage::new(members.iter().map(|(member, superblock)| (member, superblock.clone())).collect()).unwrap();
+    let data = pattern(4 * 4096);
+    array.write_bytes(0, &data).unwrap();
+    assert_eq!(members[0].0.read_bytes(DATA_OFFSET, 4096).unwrap(), data[0..4096]);
+    assert_eq!(members[1].0.read_bytes(DATA_OFFSET, 4096).unwrap(), data[4096..8192]);
+    assert_eq!(members[0].0.read_bytes(DATA_OFFSET + 4096, 4096).unwrap(), data[8192..12288]);
+    assert_eq!(members[1].0.read_bytes(DATA_OFFSET + 4096, 4096).unwrap(), data[12288..16384]);
+}
+
+#[test]
+fn mirror_resync() {
+    let members = make_members(1, 0, &[7, 7, 3]);
+    for (member, _) in &members[..2] {
+        member.write_bytes(DATA_OFFSET, &pattern(8192)).unwrap();
+    }
+    let array = RaidSectorStorage::new(members.iter().map(|(member, superblock)| (member, superblock.clone())).collect()).unwrap();
+    assert_eq!(array.layout(), Layout::Mirror);
+    assert_eq!(array.len(), MEMBER_SIZE - DATA_OFFSET);
+    assert_eq!(array.stale_member_count(), 1);
+    // reads are balanced over the in-sync members only
+    for _ in 0..4 {
+        assert_eq!(array.read_bytes(0, 8192).unwrap(), pattern(8192));
+    }
+    array.write_bytes(8192, b"mirrored").unwrap();
+    array.resync().unwrap();
+    assert_eq!(array.stale_member_count(), 0);
+    for (member, _) in &members {
+        assert_eq!(member.read_bytes(DATA_OFFSET, 8192).unwrap(), pattern(8192));
+        assert_eq!(member.read_bytes(DATA_OFFSET + 8192, 8).unwrap(), b"mirrored");
+        let superblock = raid::read_superblock(member).unwrap().expect("resync broke the superblock");
+        assert_eq!(superblock.events, 7);
+    }
+}
+
+// a member whose writes fail once it is pulled
+struct PulledDisk {
+    disk: ram_disk::DiskSectorStorage,
+    pulled: Cell<bool>,
+}
+
+impl SectorStorage for PulledDisk {
+    fn sector_size(&self) -> u64 {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.disk.sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.disk.read_sector(sector_index)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        match self.pulled.get() {
+            true => Err(StorageError::DeviceError),
+            false => self.disk.write_sector(sector_index, sector_data),
+        }
+    }
+}
+
+#[test]
+fn mirror_member_failure() {
+    let members: Vec<_> = make_members(1, 0, &[4, 4, 4])
+        .into_iter()
+        .map(|(disk, superblock)| {
+            (
+                PulledDisk {
+                    disk,
+                    pulled: Cell::new(false),
+                },
+                superblock,
+            )
+        })
+        .collect();
+    let array = RaidSectorStorage::new(members.iter().map(|(member, superblock)| (member, superblock.clone())).collect()).unwrap();
+    members[2].0.pulled.set(true);
+    array.write_bytes(0, b"degraded").unwrap();
+    // the survivors record a new event with the failed member as faulty
+    for (member, _) in &members[..2] {
+        let superblock = raid::read_superblock(member).unwrap().expect("the failure broke the superblock");
+        assert_eq!(superblock.events, 5);
+        assert_eq!(member.read_bytes(4096 + 256, 6).unwrap(), [0, 0, 1, 0, 0xfe, 0xff]);
+        assert_eq!(member.read_bytes(DATA_OFFSET, 8).unwrap(), b"degraded");
+    }
+    assert_eq!(raid::read_superblock(&members[2].0).unwrap().unwrap().events, 4);
+    // a second failure is another event
+    members[1].0.pulled.set(true);
+    array.write_bytes(0, b"single").unwrap();
+    assert_eq!(raid::read_superblock(&members[0].0).unwrap().unwrap().events, 6);
+    assert_eq!(members[0].0.read_bytes(4096 + 256, 6).unwrap(), [0, 0, 0xfe, 0xff, 0xfe, 0xff]);
+    // the failed members come back stale
+    members[1].0.pulled.set(false);
+    members[2].0.pulled.set(false);
+    let superblocks = members.iter().map(|(member, _)| raid::read_superblock(member).unwrap().unwrap());
+    let array = RaidSectorStorage::new(members.iter().map(|(member, _)| member).zip(superblocks).collect()).unwrap();
+    assert_eq!(array.stale_member_count(), 2);
+    assert_eq!(array.read_bytes(0, 6).unwrap(), b"single");
+}
+
+#[test]
+fn assemble_arrays() {
+    let mut members = make_members(1, 0, &[2, 2]);
+    members.extend(make_members(0, 8, &[4, 4]).into_iter().map(|(member, mut superblock)| {
+        superblock.array_id = *b"fedcba9876543210";
+        (member, superblock)
+    }));
+    let arrays = raid::assemble_arrays(members);
+    assert_eq!(arrays.len(), 2);
+    assert_eq!(arrays[0].layout(), Layout::Mirror);
+    assert_eq!(arrays[1].layout(), Layout::Stripe { chunk_size: 4096 });
+}
+
+#[test]
+fn invalid_superblocks() {
+    let member = ram_disk::DiskSectorStorage::new(SECTOR_SIZE, MEMBER_SIZE / SECTOR_SIZE);
+    assert!(raid::read_superblock(&member).unwrap().is_none());
+    let (member, _) = make_members(1, 0, &[2]).pop().unwrap();
+    member.write_bytes(4096 + 200, &[0xff]).unwrap();
+    assert!(raid::read_superblock(&member).unwrap().is_none());
+    assert!(RaidSectorStorage::<ram_disk::DiskSectorStorage>::new(Vec::new()).is_none());
+    let mut members = make_members(0, 8, &[3, 3]);
+    members.pop();
+    assert!(RaidSectorStorage::new(members).is_none());
+}
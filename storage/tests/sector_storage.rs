@@ -0,0 +1,131 @@
+use std::{cell::RefCell, ops::Range};
+
+use storage::{
+    cache::CachedSectorStorage,
+    ext2, file_disk,
+    fs::Session,
+    guid,
+    partitions::Partition,
+    ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+};
+
+mod common;
+
+const SECTOR_SIZE: u64 = 512;
+
+// records the flushes and discards reaching the underlying storage
+#[derive(Default)]
+struct Log {
+    flush_count: usize,
+    discards: Vec<Range<u64>>,
+}
+
+struct LoggingSectorStorage<SS: SectorStorage> {
+    sector_storage: SS,
+    log: RefCell<Log>,
+}
+
+impl<SS: SectorStorage> LoggingSectorStorage<SS> {
+    fn new(sector_storage: SS) -> Self {
+        LoggingSectorStorage {
+            sector_storage,
+            log: RefCell::new(Log::default()),
+        }
+    }
+}
+
+impl<SS: SectorStorage> SectorStorage for LoggingSectorStorage<SS> {
+    fn sector_size(&self) -> u64 {
+        self.sector_storage.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_storage.sector_count()
+    }
+
+    fn read_sector(&self, sector_index: u64) -> Result<Vec<u8>, StorageError> {
+        self.sector_storage.read_sector(sector_index)
+    }
+
+    fn write_sector(&self, sector_index: u64, sector_data: &[u8]) -> Result<(), StorageError> {
+        self.sector_storage.write_sector(sector_index, sector_data)
+    }
+
+    fn read_sectors(&self, sector_index: u64, sector_count: u64) -> Result<Vec<u8>, StorageError> {
+        self.sector_storage.read_sectors(sector_index, sector_count)
+    }
+
+    fn write_sectors(&self, sector_index: u64, sectors_data: &[u8]) -> Result<(), StorageError> {
+        self.sector_storage.write_sectors(sector_index, sectors_data)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.log.borrow_mut().flush_count += 1;
+        self.sector_storage.flush()
+    }
+
+    fn discard(&self, sectors: Range<u64>) -> Result<(), StorageError> {
+        self.log.borrow_mut().discards.push(sectors.clone());
+        self.sector_storage.discard(sectors)
+    }
+}
+
+fn partition(starting_sector: u64, ending_sector: u64) -> Partition {
+    Partition {
+        type_id: guid::ZERO,
+        id: guid::ZERO,
+        starting_sector,
+        ending_sector,
+        flags: 0,
+        name: None,
+    }
+}
+
+#[test]
+fn cache_write_back_on_flush() {
+    let disk = LoggingSectorStorage::new(ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 64));
+    let cache = CachedSectorStorage::new(&disk, 16);
+    cache.write_sector(3, &[0xAA; SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(disk.read_sector(3).unwrap(), [0; SECTOR_SIZE as usize]);
+    cache.flush().unwrap();
+    assert_eq!(disk.read_sector(3).unwrap(), [0xAA; SECTOR_SIZE as usize]);
+    assert_eq!(disk.log.borrow().flush_count, 1);
+    cache.write_sectors_fua(4, &[0xBB; SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(disk.read_sector(4).unwrap(), [0xBB; SECTOR_SIZE as usize]);
+    assert_eq!(disk.log.borrow().flush_count, 2);
+}
+
+#[test]
+fn write_zeroes_and_discard_through_partition() {
+    let disk = LoggingSectorStorage::new(ram_disk::DiskSectorStorage::from_data(SECTOR_SIZE, vec![0xFF; 64 * SECTOR_SIZE as usize]));
+    let cache = CachedSectorStorage::new(&disk, 16);
+    let partition = (&cache, partition(8, 39));
+    assert_eq!(partition.read_sector(2).unwrap(), [0xFF; SECTOR_SIZE as usize]);
+    partition.write_zeroes(1..4).unwrap();
+    assert_eq!(
+        partition.read_sectors(0, 5).unwrap(),
+        [[0xFF; 512], [0; 512], [0; 512], [0; 512], [0xFF; 512]].concat()
+    );
+    assert_eq!(disk.read_sectors(9, 3).unwrap(), vec![0; 3 * SECTOR_SIZE as usize]);
+    partition.discard(30..32).unwrap();
+    assert_eq!(disk.log.borrow().discards.len(), 1);
+    assert_eq!(disk.log.borrow().discards[0], 38..40);
+    assert_eq!(partition.discard(30..33), Err(StorageError::OutOfRange));
+    assert_eq!(partition.write_zeroes(0..33), Err(StorageError::OutOfRange));
+}
+
+#[test]
+fn ext2_discards_freed_blocks() {
+    let image_path = common::make_ext2_image("ext2_discards_freed_blocks", 4096, "8M", &[("data.bin", &[0x5A; 64 * 1024])]);
+    let disk = LoggingSectorStorage::new(file_disk::DiskSectorStorage::open(&image_path, SECTOR_SIZE).unwrap());
+    let mut session = ext2::Session::new(&disk).unwrap().unwrap();
+    let data_inode_index = common::lookup(&session, session.root(), "data.bin");
+    // an all-zero block is freed
+    session.write_regular_file_range(data_inode_index, 4096..8192, &[0; 4096]).unwrap();
+    let log = disk.log.borrow();
+    assert_eq!(log.discards.len(), 1);
+    assert_eq!(log.discards[0].end - log.discards[0].start, 8);
+    assert_eq!(log.discards[0].start % 8, 0);
+    assert_eq!(log.flush_count, 1);
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use storage::{
+    ram_disk,
+    sector_storage::{SectorStorage, StorageError},
+    stats::{Op, StatsSectorStorage},
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+// every reading advances the time, so no request takes 0 ticks
+fn clock() -> u64 {
+    static TIME: AtomicU64 = AtomicU64::new(0);
+    TIME.fetch_add(500, Ordering::Relaxed)
+}
+
+#[test]
+fn counts_requests() {
+    let disk = StatsSectorStorage::new(ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 64), "disk", clock);
+    disk.read_sectors(0, 4).unwrap();
+    disk.read_bytes(100, 1000).unwrap();
+    disk.write_sectors(8, &[0xAA; 2 * SECTOR_SIZE as usize]).unwrap();
+    assert_eq!(disk.read_sectors(63, 2), Err(StorageError::OutOfRange));
+    disk.discard(0..8).unwrap();
+    let stats = disk.stats();
+    assert_eq!(stats.read.request_count, 3);
+    assert_eq!(stats.read.error_count, 1);
+    assert_eq!(stats.read.byte_count, 4 * SECTOR_SIZE + 3 * SECTOR_SIZE);
+    assert_eq!(stats.write.request_count, 1);
+    assert_eq!(stats.write.byte_count, 2 * SECTOR_SIZE);
+    assert_eq!(stats.op_stats(Op::Discard).request_count, 1);
+    assert_eq!(stats.op_stats(Op::Discard).byte_count, 0);
+    assert_eq!(stats.flush.request_count, 0);
+    assert_eq!(stats.read.latency_histogram.iter().sum::<u64>(), 3);
+    assert_eq!(stats.read.latency_histogram[0], 0);
+    disk.reset_stats();
+    assert_eq!(disk.stats().read.request_count, 0);
+}
+
+#[test]
+fn trace_keeps_the_latest_requests() {
+    let disk = StatsSectorStorage::new(ram_disk::DiskSectorStorage::new(SECTOR_SIZE, 64), "disk", clock);
+    disk.read_sector(1).unwrap();
+    assert!(disk.trace_entries().is_empty());
+    disk.set_trace_capacity(2);
+    disk.read_sector(2).unwrap();
+    disk.write_sector(3, &[0; SECTOR_SIZE as usize]).unwrap();
+    disk.write_zeroes(4..12).unwrap();
+    let trace_entries = disk.trace_entries();
+    assert_eq!(trace_entries.len(), 2);
+    assert_eq!(
+        (trace_entries[0].sequence_number, trace_entries[0].op, trace_entries[0].sector_index),
+        (1, Op::Write, 3)
+    );
+    assert_eq!(
+        (trace_entries[1].sequence_number, trace_entries[1].op, trace_entries[1].sector_count),
+        (2, Op::WriteZeroes, 8)
+    );
+    assert!(trace_entries.iter().all(|entry| entry.result.is_ok()));
+    disk.set_trace_capacity(0);
+    assert!(disk.trace_entries().is_empty());
+}
@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+
+use storage::{
+    ext2, file_disk,
+    fs::FileType,
+    sector_storage::StorageError,
+    vfs::{Node, Vfs},
+};
+
+mod common;
+
+// an ext2 image of the given files, a trailing slash makes a directory
+fn make_image(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    common::make_ext2_image(name, 1024, "1M", files)
+}
+
+fn read_file(vfs: &Vfs, path: &str) -> Vec<u8> {
+    let node = vfs.lookup(path).unwrap().unwrap_or_else(|| panic!("{} not found", path));
+    vfs.read_regular_file_range(node, 0..vfs.file_stat(node).unwrap().size).unwrap()
+}
+
+fn dir_names(vfs: &Vfs, path: &str) -> Vec<String> {
+    let mut names: Vec<_> = vfs
+        .read_dir(vfs.lookup(path).unwrap().unwrap())
+        .unwrap()
+        .into_iter()
+        .map(|dir_entry| dir_entry.name)
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn lookup_across_mounts() {
+    let root_image_path = make_image("vfs_root", &[("a/b/hello.txt", b"root"), ("mnt/", b"")]);
+    let data_image_path = make_image("vfs_data", &[("c/d", b"data"), ("nested/", b"")]);
+    let root_sector_storage = file_disk::DiskSectorStorage::open_read_only(&root_image_path, 512).unwrap();
+    let data_sector_storage = file_disk::DiskSectorStorage::open_read_only(&data_image_path, 512).unwrap();
+    let nested_sector_storage = file_disk::DiskSectorStorage::open_read_only(&root_image_path, 512).unwrap();
+    let mut vfs = Vfs::new(Box::new(ext2::Session::new(&root_sector_storage).unwrap().unwrap()));
+    let mnt_node = vfs.lookup("/mnt").unwrap().unwrap();
+    assert_eq!(mnt_node.mount_index, 0);
+    assert_eq!(vfs.lookup("/").unwrap(), Some(vfs.root()));
+    assert!(dir_names(&vfs, "/mnt").is_empty());
+
+    let data_root_node = vfs.mount("/mnt", Box::new(ext2::Session::new(&data_sector_storage).unwrap().unwrap())).unwrap();
+    assert_eq!(
+        data_root_node,
+        Node {
+            mount_index: 1,
+            inode_index: 2
+        }
+    );
+    assert_eq!(vfs.lookup("/mnt").unwrap(), Some(data_root_node));
+    assert_eq!(dir_names(&vfs, "/mnt"), ["c", "lost+found", "nested"]);
+    let mnt_dir_entry = vfs.read_dir(vfs.root()).unwrap().into_iter().find(|dir_entry| dir_entry.name == "mnt").unwrap();
+    assert_eq!((mnt_dir_entry.node, mnt_dir_entry.file_type), (data_root_node, Some(FileType::Dir)));
+    assert_eq!(read_file(&vfs, "/mnt/c/d"), b"data");
+    assert_eq!(read_file(&vfs, "//a/./b/../b/hello.txt"), b"root");
+    assert_eq!(read_file(&vfs, "/mnt/c/../../a/b/hello.txt"), b"root");
+    assert_eq!(vfs.lookup("/mnt/..").unwrap(), Some(vfs.root()));
+    assert_eq!(vfs.lookup("/..").unwrap(), Some(vfs.root()));
+    assert_eq!(vfs.lookup("/mnt/missing").unwrap(), None);
+    assert_eq!(vfs.lookup("/a/b/hello.txt/x").unwrap(), None);
+    assert_eq!(vfs.lookup("a/b").unwrap(), None);
+
+    assert_eq!(
+        vfs.mount("/a/b/hello.txt", Box::new(ext2::Session::new(&nested_sector_storage).unwrap().unwrap()))
+            .err(),
+        Some(StorageError::OutOfRange)
+    );
+    vfs.mount("/mnt/nested", Box::new(ext2::Session::new(&nested_sector_storage).unwrap().unwrap()))
+        .unwrap();
+    assert_eq!(vfs.mount_paths(), ["/", "/mnt", "/mnt/nested"]);
+    assert_eq!(read_file(&vfs, "/mnt/nested/a/b/hello.txt"), b"root");
+    assert_eq!(read_file(&vfs, "/mnt/nested/../c/d"), b"data");
+    assert_eq!(vfs.unmount("/mnt").err(), Some(StorageError::OutOfRange));
+    assert_eq!(vfs.unmount("/mnt/c").err(), Some(StorageError::OutOfRange));
+    assert_eq!(vfs.unmount("/").err(), Some(StorageError::OutOfRange));
+    vfs.unmount("/mnt/nested").unwrap();
+    vfs.unmount("/mnt").unwrap();
+    assert_eq!(vfs.mount_paths(), ["/"]);
+    assert_eq!(vfs.lookup("/mnt").unwrap(), Some(mnt_node));
+    assert_eq!(vfs.file_stat(data_root_node).err(), Some(StorageError::OutOfRange));
+}
+
+#[test]
+fn write_through_mount() {
+    let root_image_path = make_image("vfs_write_root", &[("mnt/", b"")]);
+    let data_image_path = make_image("vfs_write_data", &[("file", b"0123456789")]);
+    let root_sector_storage = file_disk::DiskSectorStorage::open_read_only(&root_image_path, 512).unwrap();
+    let data_sector_storage = file_disk::DiskSectorStorage::open(&data_image_path, 512).unwrap();
+    let mut vfs = Vfs::new(Box::new(ext2::Session::new(&root_sector_storage).unwrap().unwrap()));
+    vfs.mount("/mnt", Box::new(ext2::Session::new(&data_sector_storage).unwrap().unwrap())).unwrap();
+    assert!(vfs.read_only(vfs.root()).unwrap());
+    let file_node = vfs.lookup("/mnt/file").unwrap().unwrap();
+    assert!(!vfs.read_only(file_node).unwrap());
+    vfs.write_regular_file_range(file_node, 2..4, b"ab").unwrap();
+    vfs.resize_regular_file(file_node, 6).unwrap();
+    assert_eq!(read_file(&vfs, "/mnt/file"), b"01ab45");
+}
+
+#[test]
+fn mount_over_root() {
+    let root_image_path = make_image("vfs_over_root_root", &[("a/hello.txt", b"root")]);
+    let data_image_path = make_image("vfs_over_root_data", &[("c/d", b"data")]);
+    let root_sector_storage = file_disk::DiskSectorStorage::open_read_only(&root_image_path, 512).unwrap();
+    let data_sector_storage = file_disk::DiskSectorStorage::open_read_only(&data_image_path, 512).unwrap();
+    let mut vfs = Vfs::new(Box::new(ext2::Session::new(&root_sector_storage).unwrap().unwrap()));
+    let root_node = vfs.root();
+    let data_root_node = vfs.mount("/", Box::new(ext2::Session::new(&data_sector_storage).unwrap().unwrap())).unwrap();
+    assert_eq!(vfs.root(), data_root_node);
+    assert_eq!(vfs.lookup("/").unwrap(), Some(data_root_node));
+    assert_eq!(dir_names(&vfs, "/"), ["c", "lost+found"]);
+    assert_eq!(read_file(&vfs, "/c/d"), b"data");
+    assert_eq!(read_file(&vfs, "/c/../../c/d"), b"data");
+    assert_eq!(vfs.lookup("/a").unwrap(), None);
+    vfs.unmount("/").unwrap();
+    assert_eq!(vfs.root(), root_node);
+    assert_eq!(read_file(&vfs, "/a/hello.txt"), b"root");
+    assert_eq!(vfs.unmount("/").err(), Some(StorageError::OutOfRange));
+}
@@ -0,0 +1,3 @@
+#/usr/bin/bash
+
+(cd storage && cargo test)